@@ -0,0 +1,7 @@
+DELETE FROM config WHERE key IN (
+    'feed_subcategory',
+    'feed_language',
+    'feed_itunes_type',
+    'feed_copyright',
+    'feed_block',
+    'feed_complete');
@@ -0,0 +1,8 @@
+INSERT OR IGNORE INTO config (key, value) VALUES
+    ('feed_summary', ''),
+    ('feed_subcategory', ''),
+    ('feed_language', 'es'),
+    ('feed_itunes_type', 'episodic'),
+    ('feed_copyright', ''),
+    ('feed_block', 'FALSE'),
+    ('feed_complete', 'FALSE');
@@ -0,0 +1,4 @@
+DROP TABLE IF EXISTS hub_subscribers;
+DELETE FROM config WHERE key IN (
+    'feed_public_url',
+    'feed_hub_url');
@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS hub_subscribers(
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    topic TEXT NOT NULL,
+    callback TEXT NOT NULL,
+    secret TEXT NOT NULL DEFAULT '',
+    expires_at DATETIME NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE(topic, callback)
+);
+INSERT OR IGNORE INTO config (key, value) VALUES
+    ('feed_public_url', ''),
+    ('feed_hub_url', '');
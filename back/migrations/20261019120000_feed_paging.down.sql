@@ -0,0 +1 @@
+DELETE FROM config WHERE key = 'feed_page_size';
@@ -0,0 +1,2 @@
+INSERT OR IGNORE INTO config (key, value) VALUES
+    ('feed_page_size', '100');
@@ -0,0 +1,10 @@
+DELETE FROM config WHERE key IN (
+    'mastodon_active',
+    'mastodon_url',
+    'mastodon_token',
+    'mastodon_visibility',
+    'mastodon_language',
+    'mastodon_spoiler_text',
+    'mastodon_max_characters',
+    'mastodon_upload_audio',
+    'mastodon_template');
@@ -0,0 +1,12 @@
+INSERT OR IGNORE INTO config (key, value) VALUES
+    ('mastodon_active', 'FALSE'),
+    ('mastodon_url', ''),
+    ('mastodon_token', ''),
+    ('mastodon_visibility', 'public'),
+    ('mastodon_language', ''),
+    ('mastodon_spoiler_text', ''),
+    ('mastodon_max_characters', '500'),
+    ('mastodon_upload_audio', 'FALSE'),
+    ('mastodon_template', '{{title}}
+{{description|truncate(300)}}...
+{{link}}');
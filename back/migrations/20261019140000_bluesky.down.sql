@@ -0,0 +1,9 @@
+DELETE FROM config WHERE key IN (
+    'bluesky_active',
+    'bluesky_url',
+    'bluesky_handle',
+    'bluesky_password',
+    'bluesky_did',
+    'bluesky_access_jwt',
+    'bluesky_refresh_jwt',
+    'bluesky_template');
@@ -0,0 +1,10 @@
+INSERT OR IGNORE INTO config (key, value) VALUES
+    ('bluesky_active', 'FALSE'),
+    ('bluesky_url', 'https://bsky.social'),
+    ('bluesky_handle', ''),
+    ('bluesky_password', ''),
+    ('bluesky_did', ''),
+    ('bluesky_access_jwt', ''),
+    ('bluesky_refresh_jwt', ''),
+    ('bluesky_template', '{{title}}
+{{description|truncate(200)}}...');
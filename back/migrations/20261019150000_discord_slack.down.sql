@@ -0,0 +1,8 @@
+DELETE FROM config WHERE key IN (
+    'discord_active',
+    'discord_webhooks',
+    'discord_username',
+    'discord_template',
+    'slack_active',
+    'slack_webhooks',
+    'slack_template');
@@ -0,0 +1,8 @@
+INSERT OR IGNORE INTO config (key, value) VALUES
+    ('discord_active', 'FALSE'),
+    ('discord_webhooks', ''),
+    ('discord_username', ''),
+    ('discord_template', 'New episode: {{title}}'),
+    ('slack_active', 'FALSE'),
+    ('slack_webhooks', ''),
+    ('slack_template', 'New episode: {{title}}');
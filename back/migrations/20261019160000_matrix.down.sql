@@ -0,0 +1,7 @@
+DELETE FROM config WHERE key IN (
+    'matrix_active',
+    'matrix_url',
+    'matrix_token',
+    'matrix_rooms',
+    'matrix_upload_audio',
+    'matrix_template');
@@ -0,0 +1,9 @@
+INSERT OR IGNORE INTO config (key, value) VALUES
+    ('matrix_active', 'FALSE'),
+    ('matrix_url', ''),
+    ('matrix_token', ''),
+    ('matrix_rooms', ''),
+    ('matrix_upload_audio', 'FALSE'),
+    ('matrix_template', '<b>{{title}}</b><br>
+{{description|truncate(300)}}...<br>
+<a href="{{link}}">{{link}}</a>');
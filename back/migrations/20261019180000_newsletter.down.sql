@@ -0,0 +1,3 @@
+DELETE FROM config WHERE key LIKE 'newsletter_%';
+DROP TABLE IF EXISTS newsletter_queue;
+DROP TABLE IF EXISTS newsletter_subscribers;
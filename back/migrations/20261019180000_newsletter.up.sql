@@ -0,0 +1,38 @@
+CREATE TABLE IF NOT EXISTS newsletter_subscribers(
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    email TEXT NOT NULL UNIQUE,
+    token TEXT NOT NULL UNIQUE,
+    confirmed BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE TABLE IF NOT EXISTS newsletter_queue(
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    guid TEXT NOT NULL UNIQUE,
+    episode TEXT NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+INSERT OR IGNORE INTO config (key, value) VALUES
+    ('newsletter_active', 'FALSE'),
+    ('newsletter_smtp_host', ''),
+    ('newsletter_smtp_port', '587'),
+    ('newsletter_smtp_username', ''),
+    ('newsletter_smtp_password', ''),
+    ('newsletter_smtp_security', 'starttls'),
+    ('newsletter_from', ''),
+    ('newsletter_frequency', 'weekly'),
+    ('newsletter_subject', 'New episodes of {{mix.title}}'),
+    ('newsletter_html_template', '<h1>{{mix.title}}</h1>
+{% for episode in episodes %}
+<h2><a href="{{episode.link}}">{{episode.title}}</a></h2>
+<p><i>{{episode.podcast}}</i></p>
+<p>{{episode.description|truncate(300)}}...</p>
+{% endfor %}
+<p><a href="{{unsubscribe_url}}">Unsubscribe</a></p>'),
+    ('newsletter_text_template', '{{mix.title}}
+{% for episode in episodes %}
+* {{episode.title}} ({{episode.podcast}})
+  {{episode.link}}
+{% endfor %}
+Unsubscribe: {{unsubscribe_url}}'),
+    ('newsletter_last_sent_at', '');
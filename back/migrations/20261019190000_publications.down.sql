@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS publications_status;
+DROP TABLE IF EXISTS publications;
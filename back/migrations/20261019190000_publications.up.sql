@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS publications(
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    guid TEXT NOT NULL,
+    destination TEXT NOT NULL,
+    episode TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    next_attempt_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE(guid, destination)
+);
+CREATE INDEX IF NOT EXISTS publications_status ON publications(status, next_attempt_at);
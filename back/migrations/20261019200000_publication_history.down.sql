@@ -0,0 +1,4 @@
+DROP INDEX IF EXISTS publications_destination;
+ALTER TABLE publications DROP COLUMN message;
+ALTER TABLE publications DROP COLUMN remote_url;
+ALTER TABLE publications DROP COLUMN remote_id;
@@ -0,0 +1,4 @@
+ALTER TABLE publications ADD COLUMN remote_id TEXT;
+ALTER TABLE publications ADD COLUMN remote_url TEXT;
+ALTER TABLE publications ADD COLUMN message TEXT;
+CREATE INDEX IF NOT EXISTS publications_destination ON publications(destination, id);
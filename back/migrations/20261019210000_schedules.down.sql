@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS publications_published;
+ALTER TABLE publications DROP COLUMN published_at;
+DROP TABLE IF EXISTS schedules;
@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS schedules(
+    destination TEXT PRIMARY KEY NOT NULL,
+    timezone TEXT NOT NULL DEFAULT 'UTC',
+    window_start TEXT,
+    window_end TEXT,
+    min_interval INTEGER NOT NULL DEFAULT 0,
+    max_per_day INTEGER NOT NULL DEFAULT 0,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+ALTER TABLE publications ADD COLUMN published_at DATETIME;
+UPDATE publications SET published_at = updated_at WHERE status IN ('published', 'deleted');
+CREATE INDEX IF NOT EXISTS publications_published ON publications(destination, published_at);
@@ -0,0 +1,3 @@
+DELETE FROM config WHERE key IN (
+    'telegram_url',
+    'twitter_url');
@@ -0,0 +1,3 @@
+INSERT OR IGNORE INTO config (key, value) VALUES
+    ('telegram_url', 'https://api.telegram.org'),
+    ('twitter_url', 'https://api.twitter.com');
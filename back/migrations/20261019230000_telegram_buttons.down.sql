@@ -0,0 +1,3 @@
+DELETE FROM config WHERE key IN (
+    'telegram_listen_button',
+    'telegram_subscribe_button');
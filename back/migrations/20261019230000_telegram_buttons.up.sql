@@ -0,0 +1,3 @@
+INSERT OR IGNORE INTO config (key, value) VALUES
+    ('telegram_listen_button', ''),
+    ('telegram_subscribe_button', '');
@@ -0,0 +1,4 @@
+INSERT OR IGNORE INTO config (key, value) VALUES
+    ('telegram_chat_id', COALESCE((SELECT chat_id FROM telegram_targets ORDER BY id LIMIT 1), '')),
+    ('telegram_thread_id', COALESCE((SELECT thread_id FROM telegram_targets ORDER BY id LIMIT 1), ''));
+DROP TABLE IF EXISTS telegram_targets;
@@ -0,0 +1,19 @@
+CREATE TABLE IF NOT EXISTS telegram_targets(
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    name TEXT NOT NULL DEFAULT '',
+    chat_id TEXT NOT NULL,
+    thread_id TEXT NOT NULL DEFAULT '',
+    template TEXT NOT NULL DEFAULT '',
+    podcasts TEXT NOT NULL DEFAULT '[]',
+    active BOOLEAN NOT NULL DEFAULT TRUE,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+INSERT INTO telegram_targets (name, chat_id, thread_id)
+    SELECT 'Default', chat.value, COALESCE(thread.value, '')
+    FROM config chat
+    LEFT JOIN config thread ON thread.key = 'telegram_thread_id'
+    WHERE chat.key = 'telegram_chat_id' AND chat.value != '';
+DELETE FROM config WHERE key IN (
+    'telegram_chat_id',
+    'telegram_thread_id');
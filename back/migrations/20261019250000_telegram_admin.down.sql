@@ -0,0 +1 @@
+DELETE FROM config WHERE key = 'telegram_admin_chats';
@@ -0,0 +1,2 @@
+INSERT OR IGNORE INTO config (key, value) VALUES
+    ('telegram_admin_chats', '');
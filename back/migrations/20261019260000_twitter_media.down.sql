@@ -0,0 +1 @@
+DELETE FROM config WHERE key = 'twitter_attach_image';
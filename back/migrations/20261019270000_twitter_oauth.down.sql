@@ -0,0 +1,4 @@
+DELETE FROM config WHERE key IN (
+    'twitter_expires_at',
+    'twitter_oauth_state',
+    'twitter_oauth_verifier');
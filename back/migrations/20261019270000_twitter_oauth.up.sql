@@ -0,0 +1,4 @@
+INSERT OR IGNORE INTO config (key, value) VALUES
+    ('twitter_expires_at', ''),
+    ('twitter_oauth_state', ''),
+    ('twitter_oauth_verifier', '');
@@ -0,0 +1,3 @@
+ALTER TABLE podcasts DROP COLUMN templates;
+ALTER TABLE podcasts DROP COLUMN destinations;
+ALTER TABLE podcasts DROP COLUMN announce;
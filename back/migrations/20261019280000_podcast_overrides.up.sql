@@ -0,0 +1,3 @@
+ALTER TABLE podcasts ADD COLUMN announce BOOLEAN NOT NULL DEFAULT TRUE;
+ALTER TABLE podcasts ADD COLUMN destinations TEXT NOT NULL DEFAULT '[]';
+ALTER TABLE podcasts ADD COLUMN templates TEXT NOT NULL DEFAULT '{}';
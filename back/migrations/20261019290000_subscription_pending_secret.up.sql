@@ -0,0 +1 @@
+ALTER TABLE subscriptions ADD COLUMN pending_secret TEXT;
@@ -0,0 +1 @@
+ALTER TABLE publications ADD COLUMN resends INTEGER NOT NULL DEFAULT 0;
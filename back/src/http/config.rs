@@ -1,13 +1,14 @@
 use std::sync::Arc;
 
 use axum::{
-    extract::State,
+    extract::{Path, Query, State},
     http::StatusCode,
     response::IntoResponse,
     routing, Json, Router,
 };
+use serde::Deserialize;
 use tracing::{debug, error};
-use crate::models::{ApiResponse, AppState, Data, Feed, Twitter, Telegram};
+use crate::models::{ApiResponse, AppState, Data, Id, Feed, Preview, Twitter, Telegram, Mastodon, Bluesky, Discord, Slack, Matrix, Newsletter, Registry, Schedule, telegram::{NewTarget, Target}};
 
 pub fn config_router() -> Router<Arc<AppState>> {
     Router::new()
@@ -17,6 +18,36 @@ pub fn config_router() -> Router<Arc<AppState>> {
         .route("/twitter", routing::post(save_twitter))
         .route("/telegram", routing::get(read_telegram))
         .route("/telegram", routing::post(save_telegram))
+        .route("/telegram/targets", routing::get(read_telegram_targets))
+        .route("/telegram/targets", routing::post(create_telegram_target))
+        .route("/telegram/targets", routing::delete(delete_telegram_target))
+        .route("/telegram/targets/{id}", routing::patch(update_telegram_target))
+        .route("/mastodon", routing::get(read_mastodon))
+        .route("/mastodon", routing::post(save_mastodon))
+        .route("/bluesky", routing::get(read_bluesky))
+        .route("/bluesky", routing::post(save_bluesky))
+        .route("/discord", routing::get(read_discord))
+        .route("/discord", routing::post(save_discord))
+        .route("/slack", routing::get(read_slack))
+        .route("/slack", routing::post(save_slack))
+        .route("/matrix", routing::get(read_matrix))
+        .route("/matrix", routing::post(save_matrix))
+        .route("/newsletter", routing::get(read_newsletter))
+        .route("/newsletter", routing::post(save_newsletter))
+        .route("/publishers", routing::get(read_publishers))
+        .route("/publishers/{name}/test", routing::post(test_publisher))
+        .route("/schedules/{name}", routing::get(read_schedule))
+        .route("/schedules/{name}", routing::post(save_schedule))
+        .route("/preview", routing::post(preview))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PreviewRequest{
+    destination: String,
+    /// The saved template when there is none
+    template: Option<String>,
+    /// An episode already announced, a sample one when there is none
+    guid: Option<String>,
 }
 
 pub async fn read_feed(
@@ -45,8 +76,8 @@ pub async fn save_feed(
             ApiResponse::new(StatusCode::OK, "Feed saved", Data::One(serde_json::to_value(feed).unwrap()))
         },
         Err(e) => {
-            error!("Error reading feed: {:?}", e);
-            ApiResponse::new(StatusCode::BAD_REQUEST, "Error saving feed", Data::None)
+            error!("Error saving feed: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, &format!("Error saving feed: {e}"), Data::None)
         }
     }
 }
@@ -76,8 +107,8 @@ pub async fn save_twitter(
             ApiResponse::new(StatusCode::OK, "Twitter saved", Data::One(serde_json::to_value(twitter).unwrap()))
         },
         Err(e) => {
-            error!("Error reading twitter: {:?}", e);
-            ApiResponse::new(StatusCode::BAD_REQUEST, "Error saving twitter", Data::None)
+            error!("Error saving twitter: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, &format!("Error saving twitter: {e}"), Data::None)
         }
     }
 }
@@ -108,8 +139,360 @@ pub async fn save_telegram(
             ApiResponse::new(StatusCode::OK, "Telegram saved", Data::One(serde_json::to_value(telegram).unwrap()))
         },
         Err(e) => {
-            error!("Error reading twitter: {:?}", e);
-            ApiResponse::new(StatusCode::BAD_REQUEST, "Error saving telegram", Data::None)
+            error!("Error saving telegram: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, &format!("Error saving telegram: {e}"), Data::None)
+        }
+    }
+}
+
+pub async fn read_telegram_targets(
+    State(app_state): State<Arc<AppState>>,
+) -> impl IntoResponse{
+    match Target::get(&app_state.pool).await {
+        Ok(targets) => ApiResponse::new(StatusCode::OK, "Telegram chats", Data::One(serde_json::to_value(targets).unwrap())),
+        Err(e) => {
+            error!("Error reading telegram chats: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, "Error reading telegram chats", Data::None)
+        }
+    }
+}
+
+pub async fn create_telegram_target(
+    State(app_state): State<Arc<AppState>>,
+    Json(target): Json<NewTarget>,
+) -> impl IntoResponse{
+    debug!("{:?}", target);
+    match Target::create(&app_state.pool, &target).await {
+        Ok(target) => ApiResponse::new(StatusCode::CREATED, "Telegram chat created", Data::One(serde_json::to_value(target).unwrap())),
+        Err(e) => {
+            error!("Error creating telegram chat: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, &format!("Error creating telegram chat: {e}"), Data::None)
+        }
+    }
+}
+
+pub async fn update_telegram_target(
+    State(app_state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    Json(target): Json<NewTarget>,
+) -> impl IntoResponse{
+    debug!("{:?}", target);
+    match Target::update(&app_state.pool, id, &target).await {
+        Ok(target) => ApiResponse::new(StatusCode::OK, "Telegram chat updated", Data::One(serde_json::to_value(target).unwrap())),
+        Err(e) => {
+            error!("Error updating telegram chat: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, &format!("Error updating telegram chat: {e}"), Data::None)
+        }
+    }
+}
+
+pub async fn delete_telegram_target(
+    State(app_state): State<Arc<AppState>>,
+    id: Query<Id>,
+) -> impl IntoResponse{
+    match Target::delete(&app_state.pool, id.id).await {
+        Ok(target) => ApiResponse::new(StatusCode::OK, "Telegram chat deleted", Data::One(serde_json::to_value(target).unwrap())),
+        Err(e) => {
+            error!("Error deleting telegram chat: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, "Error deleting telegram chat", Data::None)
+        }
+    }
+}
+
+pub async fn read_mastodon(
+    State(app_state): State<Arc<AppState>>,
+) -> impl IntoResponse{
+    match Mastodon::get(&app_state.pool).await {
+        Ok(mastodon) => {
+            debug!("{:?}", mastodon);
+            ApiResponse::new(StatusCode::OK, "Mastodon read", Data::One(serde_json::to_value(mastodon).unwrap()))
+        },
+        Err(e) => {
+            error!("Error reading mastodon: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, "Error reading mastodon", Data::None)
+        }
+    }
+}
+
+pub async fn save_mastodon(
+    State(app_state): State<Arc<AppState>>,
+    Json(mastodon): Json<Mastodon>
+) -> impl IntoResponse{
+    debug!("{:?}", mastodon);
+    match Mastodon::set(&app_state.pool, &mastodon).await {
+        Ok(mastodon) => {
+            debug!("{:?}", mastodon);
+            ApiResponse::new(StatusCode::OK, "Mastodon saved", Data::One(serde_json::to_value(mastodon).unwrap()))
+        },
+        Err(e) => {
+            error!("Error saving mastodon: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, &format!("Error saving mastodon: {e}"), Data::None)
+        }
+    }
+}
+
+pub async fn read_bluesky(
+    State(app_state): State<Arc<AppState>>,
+) -> impl IntoResponse{
+    match Bluesky::get(&app_state.pool).await {
+        Ok(bluesky) => {
+            debug!("{:?}", bluesky);
+            ApiResponse::new(StatusCode::OK, "Bluesky read", Data::One(serde_json::to_value(bluesky).unwrap()))
+        },
+        Err(e) => {
+            error!("Error reading bluesky: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, "Error reading bluesky", Data::None)
+        }
+    }
+}
+
+pub async fn save_bluesky(
+    State(app_state): State<Arc<AppState>>,
+    Json(bluesky): Json<Bluesky>
+) -> impl IntoResponse{
+    debug!("{:?}", bluesky);
+    match Bluesky::set(&app_state.pool, &bluesky).await {
+        Ok(bluesky) => {
+            debug!("{:?}", bluesky);
+            ApiResponse::new(StatusCode::OK, "Bluesky saved", Data::One(serde_json::to_value(bluesky).unwrap()))
+        },
+        Err(e) => {
+            error!("Error saving bluesky: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, &format!("Error saving bluesky: {e}"), Data::None)
+        }
+    }
+}
+
+pub async fn read_discord(
+    State(app_state): State<Arc<AppState>>,
+) -> impl IntoResponse{
+    match Discord::get(&app_state.pool).await {
+        Ok(discord) => {
+            debug!("{:?}", discord);
+            ApiResponse::new(StatusCode::OK, "Discord read", Data::One(serde_json::to_value(discord).unwrap()))
+        },
+        Err(e) => {
+            error!("Error reading discord: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, "Error reading discord", Data::None)
+        }
+    }
+}
+
+pub async fn save_discord(
+    State(app_state): State<Arc<AppState>>,
+    Json(discord): Json<Discord>
+) -> impl IntoResponse{
+    debug!("{:?}", discord);
+    match Discord::set(&app_state.pool, &discord).await {
+        Ok(discord) => {
+            debug!("{:?}", discord);
+            ApiResponse::new(StatusCode::OK, "Discord saved", Data::One(serde_json::to_value(discord).unwrap()))
+        },
+        Err(e) => {
+            error!("Error saving discord: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, &format!("Error saving discord: {e}"), Data::None)
+        }
+    }
+}
+
+pub async fn read_slack(
+    State(app_state): State<Arc<AppState>>,
+) -> impl IntoResponse{
+    match Slack::get(&app_state.pool).await {
+        Ok(slack) => {
+            debug!("{:?}", slack);
+            ApiResponse::new(StatusCode::OK, "Slack read", Data::One(serde_json::to_value(slack).unwrap()))
+        },
+        Err(e) => {
+            error!("Error reading slack: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, "Error reading slack", Data::None)
+        }
+    }
+}
+
+pub async fn save_slack(
+    State(app_state): State<Arc<AppState>>,
+    Json(slack): Json<Slack>
+) -> impl IntoResponse{
+    debug!("{:?}", slack);
+    match Slack::set(&app_state.pool, &slack).await {
+        Ok(slack) => {
+            debug!("{:?}", slack);
+            ApiResponse::new(StatusCode::OK, "Slack saved", Data::One(serde_json::to_value(slack).unwrap()))
+        },
+        Err(e) => {
+            error!("Error saving slack: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, &format!("Error saving slack: {e}"), Data::None)
+        }
+    }
+}
+
+pub async fn read_matrix(
+    State(app_state): State<Arc<AppState>>,
+) -> impl IntoResponse{
+    match Matrix::get(&app_state.pool).await {
+        Ok(matrix) => {
+            debug!("{:?}", matrix);
+            ApiResponse::new(StatusCode::OK, "Matrix read", Data::One(serde_json::to_value(matrix).unwrap()))
+        },
+        Err(e) => {
+            error!("Error reading matrix: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, "Error reading matrix", Data::None)
+        }
+    }
+}
+
+pub async fn save_matrix(
+    State(app_state): State<Arc<AppState>>,
+    Json(matrix): Json<Matrix>
+) -> impl IntoResponse{
+    debug!("{:?}", matrix);
+    match Matrix::set(&app_state.pool, &matrix).await {
+        Ok(matrix) => {
+            debug!("{:?}", matrix);
+            ApiResponse::new(StatusCode::OK, "Matrix saved", Data::One(serde_json::to_value(matrix).unwrap()))
+        },
+        Err(e) => {
+            error!("Error saving matrix: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, &format!("Error saving matrix: {e}"), Data::None)
+        }
+    }
+}
+
+pub async fn read_newsletter(
+    State(app_state): State<Arc<AppState>>,
+) -> impl IntoResponse{
+    match Newsletter::get(&app_state.pool).await {
+        Ok(newsletter) => {
+            debug!("{:?}", newsletter);
+            ApiResponse::new(StatusCode::OK, "Newsletter read", Data::One(serde_json::to_value(newsletter).unwrap()))
+        },
+        Err(e) => {
+            error!("Error reading newsletter: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, "Error reading newsletter", Data::None)
+        }
+    }
+}
+
+pub async fn save_newsletter(
+    State(app_state): State<Arc<AppState>>,
+    Json(newsletter): Json<Newsletter>
+) -> impl IntoResponse{
+    debug!("{:?}", newsletter);
+    match Newsletter::set(&app_state.pool, &newsletter).await {
+        Ok(newsletter) => {
+            debug!("{:?}", newsletter);
+            ApiResponse::new(StatusCode::OK, "Newsletter saved", Data::One(serde_json::to_value(newsletter).unwrap()))
+        },
+        Err(e) => {
+            error!("Error saving newsletter: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, &format!("Error saving newsletter: {e}"), Data::None)
+        }
+    }
+}
+
+pub async fn read_publishers(
+    State(app_state): State<Arc<AppState>>,
+) -> impl IntoResponse{
+    match Registry::load(&app_state.pool).await {
+        Ok(registry) => {
+            let publishers: Vec<serde_json::Value> = registry.all()
+                .map(|publisher| serde_json::json!({
+                    "name": publisher.name(),
+                    "active": publisher.is_active(),
+                    "error": publisher.validate().err().map(|e| e.to_string()),
+                }))
+                .collect();
+            ApiResponse::new(StatusCode::OK, "Publishers read", Data::Some(publishers))
+        },
+        Err(e) => {
+            error!("Error reading publishers: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, "Error reading publishers", Data::None)
+        }
+    }
+}
+
+pub async fn test_publisher(
+    State(app_state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> impl IntoResponse{
+    let registry = match Registry::load(&app_state.pool).await {
+        Ok(registry) => registry,
+        Err(e) => {
+            error!("Error reading publishers: {:?}", e);
+            return ApiResponse::new(StatusCode::BAD_REQUEST, "Error reading publishers", Data::None);
+        }
+    };
+    let Some(publisher) = registry.get(&name) else {
+        return ApiResponse::new(StatusCode::NOT_FOUND, "Publisher not found", Data::None);
+    };
+    let result = match publisher.validate() {
+        Ok(()) => publisher.test_connection().await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(()) => ApiResponse::new(StatusCode::OK, "Connection ok", Data::None),
+        Err(e) => {
+            error!("Error testing {name}: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, &format!("Connection failed: {e}"), Data::None)
+        }
+    }
+}
+
+pub async fn read_schedule(
+    State(app_state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> impl IntoResponse{
+    match Schedule::get(&app_state.pool, &name).await {
+        Ok(schedule) => {
+            debug!("{:?}", schedule);
+            ApiResponse::new(StatusCode::OK, "Schedule read", Data::One(serde_json::to_value(schedule).unwrap()))
+        },
+        Err(e) => {
+            error!("Error reading schedule: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, "Error reading schedule", Data::None)
+        }
+    }
+}
+
+pub async fn save_schedule(
+    State(app_state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Json(mut schedule): Json<Schedule>,
+) -> impl IntoResponse{
+    debug!("{:?}", schedule);
+    let result = async {
+        if Registry::load(&app_state.pool).await?.get(&name).is_none() {
+            return Err(format!("Unknown destination {name}").into());
+        }
+        schedule.destination = name;
+        Schedule::set(&app_state.pool, &schedule).await
+    }.await;
+    match result {
+        Ok(schedule) => {
+            debug!("{:?}", schedule);
+            ApiResponse::new(StatusCode::OK, "Schedule saved", Data::One(serde_json::to_value(schedule).unwrap()))
+        },
+        Err(e) => {
+            error!("Error saving schedule: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, &format!("Error saving schedule: {e}"), Data::None)
+        }
+    }
+}
+
+pub async fn preview(
+    State(app_state): State<Arc<AppState>>,
+    Json(request): Json<PreviewRequest>,
+) -> impl IntoResponse{
+    debug!("{:?}", request);
+    match Preview::new(&app_state.pool, &request.destination, request.template.as_deref(), request.guid.as_deref()).await {
+        Ok(preview) => {
+            debug!("{:?}", preview);
+            ApiResponse::new(StatusCode::OK, "Template preview", Data::One(serde_json::to_value(preview).unwrap()))
+        },
+        Err(e) => {
+            error!("Error previewing template: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, &format!("Error previewing template: {e}"), Data::None)
         }
     }
 }
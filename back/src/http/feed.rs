@@ -0,0 +1,99 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::Query,
+    http::{header, StatusCode},
+    response::IntoResponse,
+    routing, Router,
+};
+use serde::Deserialize;
+use tracing::{debug, error};
+use rss::Channel;
+
+use crate::models::{ApiResponse, AppState, Data, validator, feed::page_path};
+
+#[derive(Debug, Deserialize)]
+pub struct FeedName{
+    #[serde(default = "default_name")]
+    pub name: String,
+}
+
+fn default_name() -> String{
+    "short".to_string()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Page{
+    #[serde(default = "default_page")]
+    pub page: usize,
+}
+
+fn default_page() -> usize{
+    1
+}
+
+/// Serves the pages of the long feed, `/rss/long.xml?page=n`.
+pub fn rss_router() -> Router {
+    Router::new()
+        .route("/rss/long.xml", routing::get(read_long))
+}
+
+pub async fn read_long(
+    Query(page): Query<Page>,
+) -> impl IntoResponse {
+    debug!("Long feed page: {}", page.page);
+    match tokio::fs::read(page_path("long.xml", page.page)).await {
+        Ok(content) => ([(header::CONTENT_TYPE, "text/xml")], content).into_response(),
+        Err(e) => {
+            debug!("Error reading page {}: {:?}", page.page, e);
+            StatusCode::NOT_FOUND.into_response()
+        }
+    }
+}
+
+pub fn feed_router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/validate", routing::get(validate))
+}
+
+pub async fn validate(
+    Query(feed): Query<FeedName>,
+) -> impl IntoResponse {
+    debug!("Validate feed: {}", feed.name);
+    if feed.name != "short" && feed.name != "long" {
+        return ApiResponse::new(StatusCode::BAD_REQUEST, "Unknown feed", Data::None);
+    }
+    let content = match tokio::fs::read(format!("rss/{}.xml", feed.name)).await {
+        Ok(content) => content,
+        Err(e) => {
+            error!("Error reading feed: {:?}", e);
+            return ApiResponse::new(StatusCode::NOT_FOUND, "Feed not generated yet", Data::None);
+        }
+    };
+    match Channel::read_from(&content[..]) {
+        Ok(mut channel) => {
+            if feed.name == "long" {
+                // The rest of the pages only add episodes to the first one
+                let mut items = channel.items().to_vec();
+                let mut page = 2;
+                while let Ok(content) = tokio::fs::read(page_path("long.xml", page)).await {
+                    match Channel::read_from(&content[..]) {
+                        Ok(other) => items.extend_from_slice(other.items()),
+                        Err(e) => return ApiResponse::new(StatusCode::UNPROCESSABLE_ENTITY,
+                            &format!("Error parsing page {page} of the feed: {e}"), Data::None),
+                    }
+                    page += 1;
+                }
+                channel.set_items(items);
+            }
+            let report = validator::validate(&channel).await;
+            debug!("Report: {:?}", report);
+            let message = if report.valid { "Feed valid" } else { "Feed not valid" };
+            ApiResponse::new(StatusCode::OK, message, Data::One(serde_json::to_value(report).unwrap()))
+        },
+        Err(e) => {
+            error!("Error parsing feed: {:?}", e);
+            ApiResponse::new(StatusCode::UNPROCESSABLE_ENTITY, &format!("Error parsing feed: {e}"), Data::None)
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::IntoResponse,
+    routing, Form, Router,
+};
+use reqwest::Url;
+use serde::Deserialize;
+use tracing::{debug, error, info};
+
+use crate::models::{
+    ApiResponse,
+    AppState,
+    Data,
+    Feed,
+    hub::{Subscriber, DEFAULT_LEASE_SECONDS, MAX_LEASE_SECONDS},
+};
+
+#[derive(Debug, Deserialize)]
+pub struct HubRequest{
+    #[serde(rename = "hub.mode")]
+    pub mode: String,
+    #[serde(rename = "hub.topic")]
+    pub topic: String,
+    #[serde(rename = "hub.callback")]
+    pub callback: String,
+    #[serde(rename = "hub.lease_seconds")]
+    pub lease_seconds: Option<i64>,
+    #[serde(rename = "hub.secret", default)]
+    pub secret: String,
+}
+
+pub fn hub_router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/", routing::post(subscribe))
+}
+
+pub async fn subscribe(
+    State(app_state): State<Arc<AppState>>,
+    Form(request): Form<HubRequest>,
+) -> impl IntoResponse {
+    debug!("Hub request: {:?}", request);
+    if request.mode != "subscribe" && request.mode != "unsubscribe" {
+        return ApiResponse::new(StatusCode::BAD_REQUEST, "Unsupported hub.mode", Data::None);
+    }
+    if !matches!(Url::parse(&request.callback), Ok(url) if url.scheme() == "http" || url.scheme() == "https") {
+        return ApiResponse::new(StatusCode::BAD_REQUEST, "Invalid hub.callback", Data::None);
+    }
+    if request.secret.len() >= 200 {
+        return ApiResponse::new(StatusCode::BAD_REQUEST, "hub.secret too long", Data::None);
+    }
+    let feed = match Feed::get(&app_state.pool).await {
+        Ok(feed) => feed,
+        Err(e) => {
+            error!("Error reading feed: {:?}", e);
+            return ApiResponse::new(StatusCode::INTERNAL_SERVER_ERROR, "Error reading feed", Data::None);
+        }
+    };
+    let topics = [feed.self_url("short.xml"), feed.self_url("long.xml")];
+    if !topics.iter().flatten().any(|topic| *topic == request.topic) {
+        return ApiResponse::new(StatusCode::BAD_REQUEST, "Unknown hub.topic", Data::None);
+    }
+    let lease_seconds = request.lease_seconds
+        .unwrap_or(DEFAULT_LEASE_SECONDS)
+        .clamp(1, MAX_LEASE_SECONDS);
+    let pool = app_state.pool.clone();
+    tokio::spawn(async move {
+        match Subscriber::verify_intent(&request.callback, &request.mode, &request.topic, lease_seconds).await {
+            Ok(true) => {
+                info!("Verified {} of {} for {}", request.mode, request.topic, request.callback);
+                let response = if request.mode == "subscribe" {
+                    Subscriber::create_or_update(&pool, &request.topic, &request.callback, &request.secret, lease_seconds)
+                        .await
+                        .map(|_| ())
+                } else {
+                    Subscriber::delete(&pool, &request.topic, &request.callback)
+                        .await
+                        .map(|_| ())
+                };
+                if let Err(e) = response {
+                    error!("Error saving subscriber: {:?}", e);
+                }
+            },
+            Ok(false) => info!("Subscriber {} did not confirm {}", request.callback, request.mode),
+            Err(e) => error!("Error verifying subscriber {}: {e}", request.callback),
+        }
+    });
+    ApiResponse::new(StatusCode::ACCEPTED, "Verifying intent", Data::None)
+}
@@ -2,9 +2,23 @@ mod user;
 mod health;
 mod podcast;
 mod config;
+mod feed;
+mod hub;
+mod websub;
+mod webhook;
+mod newsletter;
+mod publication;
+mod oauth;
 
 pub use health::health_router;
 pub use user::user_router;
 pub use podcast::podcast_router;
 pub use config::config_router;
+pub use feed::{feed_router, rss_router};
+pub use hub::hub_router;
+pub use websub::websub_router;
+pub use webhook::webhook_router;
+pub use newsletter::newsletter_router;
+pub use publication::publication_router;
+pub use oauth::oauth_router;
 
@@ -0,0 +1,139 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{Html, IntoResponse},
+    routing, Json, Router,
+};
+use serde::Deserialize;
+use tracing::{debug, error};
+
+use crate::models::{
+    ApiResponse,
+    AppState,
+    Data,
+    Feed,
+    Id,
+    Newsletter,
+    newsletter::Subscriber,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct Subscription{
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Token{
+    pub token: String,
+}
+
+pub fn newsletter_router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/subscribe", routing::post(subscribe))
+        .route("/confirm", routing::get(confirm))
+        .route("/unsubscribe", routing::get(unsubscribe))
+        .route("/subscribers", routing::get(read_subscribers))
+        .route("/subscribers", routing::delete(delete_subscriber))
+        .route("/send", routing::post(send))
+}
+
+/// First step of the double opt-in, mails the confirmation link.
+pub async fn subscribe(
+    State(app_state): State<Arc<AppState>>,
+    Json(subscription): Json<Subscription>,
+) -> impl IntoResponse {
+    debug!("Subscribe: {:?}", subscription);
+    let result = async {
+        let newsletter = Newsletter::get(&app_state.pool).await?;
+        if !newsletter.active {
+            return Err("The newsletter is not active".into());
+        }
+        let feed = Feed::get(&app_state.pool).await?;
+        if feed.public_url.is_empty() {
+            return Err("No public url for the confirmation link".into());
+        }
+        let subscriber = Subscriber::create(&app_state.pool, &subscription.email).await?;
+        newsletter.send_confirmation(&subscriber, &feed).await
+    }.await;
+    match result {
+        Ok(()) => ApiResponse::new(StatusCode::OK, "Check your email to confirm the subscription", Data::None),
+        Err(e) => {
+            error!("Error subscribing {}: {:?}", subscription.email, e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, &format!("Error subscribing: {e}"), Data::None)
+        }
+    }
+}
+
+pub async fn confirm(
+    State(app_state): State<Arc<AppState>>,
+    Query(token): Query<Token>,
+) -> impl IntoResponse {
+    match Subscriber::confirm(&app_state.pool, &token.token).await {
+        Ok(Some(subscriber)) => {
+            debug!("Subscriber confirmed: {:?}", subscriber);
+            (StatusCode::OK, Html("<p>Your subscription is confirmed. Thank you!</p>"))
+        },
+        Ok(None) => (StatusCode::NOT_FOUND, Html("<p>Unknown or expired link.</p>")),
+        Err(e) => {
+            error!("Error confirming subscriber: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Html("<p>Something went wrong, try again later.</p>"))
+        }
+    }
+}
+
+pub async fn unsubscribe(
+    State(app_state): State<Arc<AppState>>,
+    Query(token): Query<Token>,
+) -> impl IntoResponse {
+    match Subscriber::unsubscribe(&app_state.pool, &token.token).await {
+        Ok(Some(subscriber)) => {
+            debug!("Subscriber removed: {:?}", subscriber);
+            (StatusCode::OK, Html("<p>You will not receive more emails.</p>"))
+        },
+        Ok(None) => (StatusCode::NOT_FOUND, Html("<p>Unknown or expired link.</p>")),
+        Err(e) => {
+            error!("Error removing subscriber: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Html("<p>Something went wrong, try again later.</p>"))
+        }
+    }
+}
+
+pub async fn read_subscribers(
+    State(app_state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    match Subscriber::get(&app_state.pool).await {
+        Ok(subscribers) => ApiResponse::new(StatusCode::OK, "Subscribers", Data::One(serde_json::to_value(subscribers).unwrap())),
+        Err(e) => {
+            error!("Error reading subscribers: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, "Error reading subscribers", Data::None)
+        }
+    }
+}
+
+pub async fn delete_subscriber(
+    State(app_state): State<Arc<AppState>>,
+    id: Query<Id>,
+) -> impl IntoResponse {
+    match Subscriber::delete(&app_state.pool, id.id).await {
+        Ok(subscriber) => ApiResponse::new(StatusCode::OK, "Subscriber deleted", Data::One(serde_json::to_value(subscriber).unwrap())),
+        Err(e) => {
+            error!("Error deleting subscriber: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, "Error deleting subscriber", Data::None)
+        }
+    }
+}
+
+/// Sends the queued episodes now, without waiting for the next digest.
+pub async fn send(
+    State(app_state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    match Newsletter::send_digest(&app_state.pool, true).await {
+        Ok(sent) => ApiResponse::new(StatusCode::OK, &format!("Newsletter sent to {sent} subscribers"), Data::None),
+        Err(e) => {
+            error!("Error sending newsletter: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, &format!("Error sending newsletter: {e}"), Data::None)
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Redirect},
+    routing, Router,
+};
+use serde::Deserialize;
+use tracing::{debug, error, info};
+
+use crate::models::{ApiResponse, AppState, Data, Twitter};
+
+pub fn oauth_router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/twitter/start", routing::get(twitter_start))
+        .route("/twitter/callback", routing::get(twitter_callback))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Callback {
+    state: Option<String>,
+    code: Option<String>,
+    error: Option<String>,
+}
+
+pub async fn twitter_start(
+    State(app_state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    match Twitter::authorize(&app_state.pool).await {
+        Ok(url) => Redirect::to(&url).into_response(),
+        Err(e) => {
+            error!("Error starting X authorization: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, &e.to_string(), Data::None).into_response()
+        }
+    }
+}
+
+pub async fn twitter_callback(
+    State(app_state): State<Arc<AppState>>,
+    Query(callback): Query<Callback>,
+) -> impl IntoResponse {
+    debug!("X callback: {:?}", callback);
+    let (Some(state), Some(code)) = (callback.state, callback.code) else {
+        let message = format!("X authorization denied: {}", callback.error.unwrap_or_default());
+        return ApiResponse::new(StatusCode::BAD_REQUEST, &message, Data::None).into_response();
+    };
+    match Twitter::callback(&app_state.pool, &state, &code).await {
+        Ok(_) => {
+            info!("X authorized");
+            Redirect::to("/").into_response()
+        },
+        Err(e) => {
+            error!("Error finishing X authorization: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, &e.to_string(), Data::None).into_response()
+        }
+    }
+}
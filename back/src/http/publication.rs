@@ -0,0 +1,92 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing, Json, Router,
+};
+use serde::Deserialize;
+use tracing::{debug, error};
+
+use crate::models::{
+    ApiResponse,
+    AppState,
+    Data,
+    publication::{self, Publication},
+};
+
+#[derive(Debug, Deserialize)]
+pub struct Filter{
+    pub guid: Option<String>,
+    pub destination: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Message{
+    pub message: String,
+}
+
+pub fn publication_router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/", routing::get(read))
+        .route("/{id}", routing::patch(edit))
+        .route("/{id}", routing::delete(delete))
+        .route("/{id}/resend", routing::post(resend))
+}
+
+/// History of the publications, by episode (`guid`) or `destination`.
+pub async fn read(
+    State(app_state): State<Arc<AppState>>,
+    Query(filter): Query<Filter>,
+) -> impl IntoResponse {
+    debug!("Publications: {:?}", filter);
+    match Publication::get(&app_state.pool, filter.guid.as_deref(), filter.destination.as_deref()).await {
+        Ok(publications) => ApiResponse::new(StatusCode::OK, "Publications", Data::One(serde_json::to_value(publications).unwrap())),
+        Err(e) => {
+            error!("Error reading publications: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, "Error reading publications", Data::None)
+        }
+    }
+}
+
+pub async fn resend(
+    State(app_state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+) -> impl IntoResponse {
+    match Publication::resend(&app_state.pool, id).await {
+        Ok(publication) => ApiResponse::new(StatusCode::OK, "Publication queued", Data::One(serde_json::to_value(publication).unwrap())),
+        Err(e) => {
+            error!("Error queuing publication {id}: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, &format!("Error queuing publication: {e}"), Data::None)
+        }
+    }
+}
+
+pub async fn edit(
+    State(app_state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    Json(message): Json<Message>,
+) -> impl IntoResponse {
+    debug!("Edit publication {id}: {:?}", message);
+    match publication::edit(&app_state.pool, id, &message.message).await {
+        Ok(publication) => ApiResponse::new(StatusCode::OK, "Publication edited", Data::One(serde_json::to_value(publication).unwrap())),
+        Err(e) => {
+            error!("Error editing publication {id}: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, &format!("Error editing publication: {e}"), Data::None)
+        }
+    }
+}
+
+pub async fn delete(
+    State(app_state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+) -> impl IntoResponse {
+    match publication::delete(&app_state.pool, id).await {
+        Ok(publication) => ApiResponse::new(StatusCode::OK, "Publication deleted", Data::One(serde_json::to_value(publication).unwrap())),
+        Err(e) => {
+            error!("Error deleting publication {id}: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, &format!("Error deleting publication: {e}"), Data::None)
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing, Json, Router,
+};
+use serde_json::json;
+use tracing::{debug, error};
+
+use crate::models::{
+    ApiResponse,
+    AppState,
+    Data,
+    Id,
+    webhook::{self, NewWebhook, Webhook, Delivery},
+};
+
+pub fn webhook_router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/", routing::post(create))
+        .route("/", routing::get(read))
+        .route("/", routing::delete(delete))
+        .route("/{id}", routing::patch(update))
+        .route("/{id}/deliveries", routing::get(read_deliveries))
+        .route("/{id}/test", routing::post(test))
+}
+
+pub async fn create(
+    State(app_state): State<Arc<AppState>>,
+    Json(webhook): Json<NewWebhook>,
+) -> impl IntoResponse {
+    debug!("Webhook: {:?}", webhook);
+    match Webhook::create(&app_state.pool, &webhook).await {
+        Ok(webhook) => {
+            debug!("Webhook created: {:?}", webhook);
+            ApiResponse::new(StatusCode::CREATED, "Webhook created", Data::One(serde_json::to_value(webhook).unwrap()))
+        },
+        Err(e) => {
+            error!("Error creating webhook: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, &format!("Error creating webhook: {e}"), Data::None)
+        }
+    }
+}
+
+pub async fn update(
+    State(app_state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    Json(webhook): Json<NewWebhook>,
+) -> impl IntoResponse {
+    debug!("Update webhook {id}: {:?}", webhook);
+    match Webhook::update(&app_state.pool, id, &webhook).await {
+        Ok(webhook) => {
+            debug!("Webhook updated: {:?}", webhook);
+            ApiResponse::new(StatusCode::OK, "Webhook updated", Data::One(serde_json::to_value(webhook).unwrap()))
+        },
+        Err(e) => {
+            error!("Error updating webhook: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, &format!("Error updating webhook: {e}"), Data::None)
+        }
+    }
+}
+
+pub async fn read(
+    State(app_state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    match Webhook::get(&app_state.pool).await {
+        Ok(webhooks) => {
+            debug!("Webhooks: {:?}", webhooks);
+            ApiResponse::new(StatusCode::OK, "Webhooks", Data::One(serde_json::to_value(webhooks).unwrap()))
+        },
+        Err(e) => {
+            error!("Error reading webhooks: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, "Error reading webhooks", Data::None)
+        }
+    }
+}
+
+pub async fn delete(
+    State(app_state): State<Arc<AppState>>,
+    id: Query<Id>,
+) -> impl IntoResponse {
+    debug!("Webhook: {:?}", id);
+    match Webhook::delete(&app_state.pool, id.id).await {
+        Ok(webhook) => {
+            debug!("Webhook deleted: {:?}", webhook);
+            ApiResponse::new(StatusCode::OK, "Webhook deleted", Data::One(serde_json::to_value(webhook).unwrap()))
+        },
+        Err(e) => {
+            error!("Error deleting webhook: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, "Error deleting webhook", Data::None)
+        }
+    }
+}
+
+pub async fn read_deliveries(
+    State(app_state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+) -> impl IntoResponse {
+    match Delivery::get_by_webhook(&app_state.pool, id).await {
+        Ok(deliveries) => ApiResponse::new(StatusCode::OK, "Deliveries", Data::One(serde_json::to_value(deliveries).unwrap())),
+        Err(e) => {
+            error!("Error reading deliveries: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, "Error reading deliveries", Data::None)
+        }
+    }
+}
+
+/// Sends a `ping` event, whatever the filters of the webhook are.
+pub async fn test(
+    State(app_state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+) -> impl IntoResponse {
+    let webhook = match Webhook::get_by_id(&app_state.pool, id).await {
+        Ok(Some(webhook)) => webhook,
+        Ok(None) => return ApiResponse::new(StatusCode::NOT_FOUND, "Webhook not found", Data::None),
+        Err(e) => {
+            error!("Error reading webhook: {:?}", e);
+            return ApiResponse::new(StatusCode::BAD_REQUEST, "Error reading webhook", Data::None);
+        }
+    };
+    let payload = json!({"event": webhook::PING, "webhook": webhook.name}).to_string();
+    match webhook::Delivery::create_attempt(&app_state.pool, &webhook, webhook::PING, &payload).await {
+        Ok(delivery) => ApiResponse::new(StatusCode::OK, "Ping sent", Data::One(serde_json::to_value(delivery).unwrap())),
+        Err(e) => {
+            error!("Error sending ping: {:?}", e);
+            ApiResponse::new(StatusCode::BAD_REQUEST, &format!("Error sending ping: {e}"), Data::None)
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing, Router,
+};
+use rss::Channel;
+use tracing::{debug, error, info};
+
+use crate::models::{ApiResponse, AppState, Data, Subscription, subscription};
+
+pub fn websub_router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/{id}", routing::get(verify))
+        .route("/{id}", routing::post(receive))
+}
+
+pub async fn verify(
+    State(app_state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    debug!("Verify subscription for podcast {id}: {:?}", params);
+    let mode = params.get("hub.mode").map(String::as_str).unwrap_or("");
+    let topic = params.get("hub.topic").map(String::as_str).unwrap_or("");
+    let subscription = match Subscription::get_by_podcast(&app_state.pool, id).await {
+        Ok(Some(subscription)) if subscription.topic == topic => subscription,
+        Ok(_) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            error!("Error reading subscription: {:?}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    match mode {
+        "subscribe" => {
+            let Some(challenge) = params.get("hub.challenge") else {
+                return StatusCode::BAD_REQUEST.into_response();
+            };
+            // Without a lease it would count as expired right away
+            let lease_seconds = params.get("hub.lease_seconds")
+                .and_then(|lease| lease.parse().ok())
+                .filter(|lease: &i64| *lease > 0)
+                .unwrap_or(subscription::LEASE_SECONDS);
+            match Subscription::activate(&app_state.pool, subscription.id, lease_seconds).await {
+                Ok(_) => {
+                    info!("Subscribed to {topic} for {lease_seconds} seconds");
+                    (StatusCode::OK, challenge.to_string()).into_response()
+                },
+                Err(e) => {
+                    error!("Error activating subscription: {:?}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                }
+            }
+        },
+        "denied" => {
+            info!("Subscription to {topic} denied: {:?}", params.get("hub.reason"));
+            if let Err(e) = Subscription::delete(&app_state.pool, subscription.id).await {
+                error!("Error deleting subscription: {:?}", e);
+            }
+            StatusCode::OK.into_response()
+        },
+        // Subscriptions are never cancelled on purpose, they just expire
+        _ => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+pub async fn receive(
+    State(app_state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoResponse {
+    debug!("Content received for podcast {id}");
+    let subscription = match Subscription::get_by_podcast(&app_state.pool, id).await {
+        Ok(Some(subscription)) if subscription.active => subscription,
+        Ok(_) => return ApiResponse::new(StatusCode::GONE, "Not subscribed", Data::None),
+        Err(e) => {
+            error!("Error reading subscription: {:?}", e);
+            return ApiResponse::new(StatusCode::INTERNAL_SERVER_ERROR, "Error reading subscription", Data::None);
+        }
+    };
+    let signature = headers.get("X-Hub-Signature")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+    // The hub must get a 2xx even when the signature does not match
+    if !subscription.verify_signature(signature, &body) {
+        error!("Invalid signature for podcast {id}, content ignored");
+        return ApiResponse::new(StatusCode::ACCEPTED, "Content ignored", Data::None);
+    }
+    // Fat pings bring the whole feed, thin ones only tell it changed
+    let channel = Channel::read_from(&body[..]).ok();
+    if let Err(e) = app_state.worker.send((id, channel)) {
+        error!("Error waking up the worker: {:?}", e);
+    }
+    ApiResponse::new(StatusCode::ACCEPTED, "Content received", Data::None)
+}
@@ -44,27 +44,41 @@ use tracing_subscriber::{
 use tower_http::services::{ServeDir, ServeFile};
 use tracing::{info, error, debug};
 use chrono::DateTime;
-use rss::Item;
-use minijinja::{Environment, context, Value};
-use html2text::from_read;
+use rss::{Channel, Item};
 use http::{
     health_router,
     user_router,
     podcast_router,
     config_router,
+    feed_router,
+    rss_router,
+    hub_router,
+    websub_router,
+    webhook_router,
+    newsletter_router,
+    publication_router,
+    oauth_router,
 };
 use models::{
+    bot,
+    feed,
     util,
+    webhook,
+    publication,
     AppState,
     Error,
-    Param,
-    Telegram,
-    Twitter,
     Feed,
+    Episode,
+    Registry,
+    Newsletter,
     Podcast,
     CompletePodcast,
+    Subscription,
 };
 
+/// Seconds between checks of the publication queue
+const DISPATCH_INTERVAL: u64 = 30;
+
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     let log_level = var("RUST_LOG").unwrap_or("debug".to_string());
@@ -110,14 +124,30 @@ async fn main() -> Result<(), Error> {
         .await
         .unwrap();
 
+    match publication::Publication::fail_interrupted(&pool).await {
+        Ok(0) => {},
+        Ok(interrupted) => error!("{interrupted} publications interrupted while sending"),
+        Err(e) => error!("Error checking interrupted publications: {e}"),
+    }
+
+    let (worker, mut receiver) = tokio::sync::mpsc::unbounded_channel::<(i64, Option<Channel>)>();
+
     let api_routes = Router::new()
         .nest("/health", health_router())
         .nest("/auth", user_router())
         .nest("/podcasts", podcast_router())
         .nest("/config", config_router())
+        .nest("/feed", feed_router())
+        .nest("/hub", hub_router())
+        .nest("/websub", websub_router())
+        .nest("/webhooks", webhook_router())
+        .nest("/newsletter", newsletter_router())
+        .nest("/publications", publication_router())
+        .nest("/oauth", oauth_router())
         .with_state(Arc::new(AppState {
             pool: pool.clone(),
             secret,
+            worker,
     }));
 
     let cors = CorsLayer::new()
@@ -129,6 +159,7 @@ async fn main() -> Result<(), Error> {
         .allow_headers([AUTHORIZATION, ACCEPT, CONTENT_TYPE]);
 
     let app = Router::new()
+        .merge(rss_router())
         .nest_service("/rss", ServeDir::new("./rss"))
         .nest("/api/v1", api_routes)
         .fallback_service(ServeDir::new("static").fallback(ServeFile::new("static/index.html")))
@@ -137,21 +168,46 @@ async fn main() -> Result<(), Error> {
 
     let pool2 = pool.clone();
     tokio::spawn(async move {
+        let mut next_round = tokio::time::Instant::now();
         loop {
-            match do_the_work(&pool2, older_than).await{
-                Ok(_) => {},
-                Err(error) => {
-                    error!("do_the_work error: {error}");
-                    let mut next_err = error.source();
-                    while next_err.is_some(){
-                        error!("caused by: {:#}", next_err.unwrap());
-                        next_err = next_err.unwrap().source();
+            if tokio::time::Instant::now() >= next_round {
+                match do_the_work(&pool2, older_than).await{
+                    Ok(_) => {},
+                    Err(error) => util::log_error("do_the_work error", &error),
+                }
+                if let Err(error) = webhook::retry(&pool2).await {
+                    util::log_error("Webhook retry error", &error);
+                }
+                if let Err(error) = Newsletter::send_digest(&pool2, false).await {
+                    util::log_error("Newsletter error", &error);
+                }
+                next_round = tokio::time::Instant::now() + Duration::from_secs(sleep_time);
+            }
+            // Pushed content does not delay the next round
+            tokio::select! {
+                _ = tokio::time::sleep_until(next_round) => {},
+                Some((podcast_id, channel)) = receiver.recv() => {
+                    info!("New content pushed for podcast {podcast_id}");
+                    if let Err(error) = ingest(&pool2, older_than, podcast_id, channel).await {
+                        util::log_error("Ingest error", &error);
                     }
                 },
             }
-            tokio::time::sleep(Duration::from_secs(sleep_time)).await;
         }
     });
+    let pool3 = pool.clone();
+    tokio::spawn(async move {
+        loop {
+            if let Err(error) = publication::dispatch(&pool3).await {
+                util::log_error("Dispatch error", &error);
+            }
+            tokio::time::sleep(Duration::from_secs(DISPATCH_INTERVAL)).await;
+        }
+    });
+    let pool4 = pool.clone();
+    tokio::spawn(async move {
+        bot::run(&pool4, older_than).await;
+    });
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await?;
     tracing::info!("🚀 Server started successfully");
     axum::serve(listener, app).await?;
@@ -162,7 +218,7 @@ async fn main() -> Result<(), Error> {
 async fn do_the_work(pool: &SqlitePool, older_than: i32) -> Result<(), Error>{
     debug!("Init feed");
     let feed = Feed::get(pool).await?;
-    let mut new_episodes: Vec<Item> = Vec::new();
+    let mut episodes: Vec<Episode> = Vec::new();
     let mut older_than_episodes: Vec<Item> = Vec::new();
     let mut all_episodes: Vec<Item> = Vec::new();
     let mut podcasts = Podcast::get(pool).await?;
@@ -170,264 +226,100 @@ async fn do_the_work(pool: &SqlitePool, older_than: i32) -> Result<(), Error>{
     for podcast in podcasts.as_mut_slice(){
         match CompletePodcast::new(podcast).await{
             Ok(complete) => {
-                match complete.get_new(){
-                    Ok(news) => {
-                        info!("Get episodes for: {}. News: {}", &podcast.name, news.len());
-                        new_episodes.extend_from_slice(news.as_slice());
-                        if !news.is_empty(){
-                            generate = true;
-                            let first = news.first().unwrap();
-                            info!("{}", first.pub_date().unwrap());
-                            if let Ok(pub_date) = DateTime::parse_from_rfc2822(first.pub_date().unwrap()){
-                                podcast.last_pub_date = pub_date.to_utc();
-                            }else if let Ok(pub_date) = DateTime::parse_from_str(first.pub_date().unwrap(), "%a, %d %b %Y %H:%M:%S") {
-                                podcast.last_pub_date = pub_date.to_utc();
-                            }
-                            match futures::executor::block_on(Podcast::update(pool, podcast)){
-                                Ok(response) => debug!("{:?}", response),
-                                Err(e) => error!("{:?}", e),
-                            };
-                        }
-                    },
-                    Err(e) => error!("Error doing the work: {}", e),
-                };
+                let news = new_episodes(pool, &feed, podcast, &complete).await;
+                generate = generate || !news.is_empty();
+                episodes.extend(news);
                 match complete.get_older_than_days(older_than){
                     Ok(older) => older_than_episodes.extend_from_slice(older.as_slice()),
                     Err(e) => error!("Error doing the work: {}", e),
                 };
                 let all = complete.get_all();
                 all_episodes.extend_from_slice(all.as_slice());
+                if let Err(e) = Subscription::ensure(pool, &feed, &complete).await {
+                    error!("Error subscribing to {}: {e}", &podcast.name);
+                }
             },
             Err(e) => error!("Error doing the work: {}", e),
         }
     }
     if generate {
-        info!("Init telegram");
-        let telegram = Telegram::get(pool).await?;
-        info!("Init twitter");
-        let mut twitter = Twitter::get(pool).await?;
-        if twitter.is_active() {
-            debug!("What before access_token: {}", twitter.get_access_token());
-            debug!("What before refresh_token: {}", twitter.get_refresh_token());
-            debug!("Update twitter");
-            if twitter.update_access_token().await.is_ok(){
-                let twitter_access_token = twitter.get_access_token();
-                debug!("Access token: {twitter_access_token}");
-                match Param::set(pool, "twitter_access_token", twitter_access_token).await{
-                    Ok(response) => debug!("{:?}", response),
-                    Err(e) => error!("{:?}", e),
-                };
-                let twitter_refresh_token = twitter.get_refresh_token();
-                debug!("Refresh token: {twitter_refresh_token}");
-                match Param::set(pool, "twitter_refresh_token", twitter_refresh_token).await{
-                    Ok(response) => debug!("{:?}", response),
-                    Err(e) => error!("{:?}", e),
-                };
-            }else{
-                error!("Someting goes wrong");
-            }
-            debug!("What after access_token: {}", twitter.get_access_token());
-            debug!("What after refresh_token: {}", twitter.get_refresh_token());
-        }
-        new_episodes.sort_by(|a, b| a.pub_date.cmp(&b.pub_date));
-        for episode in new_episodes.as_slice(){
-            let ctx = context!(
-                title => episode.title().unwrap_or(""),
-                description => from_read(
-                    episode.description().unwrap_or("").as_bytes(),
-                    5000).unwrap_or("".to_string()),
-                link => episode.link().unwrap(),
-            );
-            if telegram.is_active() {
-                info!("Trying to populate in Telegram: {}", episode.title().unwrap());
-                let template = Param::get(pool, "telegram_template")
-                    .await
-                    .unwrap();
-                match populate_in_telegram(&ctx, &template, &telegram, episode).await{
-                    Ok(_) => {
-                        info!("Populated in Telegram: {}", episode.title().unwrap());
-                    },
-                    Err(error) => {
-                        error!("Could NOT populate in Telegram: {error}");
-                        let mut next_error = error.source();
-                        // render causes as well
-                        while next_error.is_some(){
-                            error!("caused by: {:#}", next_error.unwrap());
-                            next_error = next_error.unwrap().source();
-                        }
-                    },
-                }
-            }
-            if twitter.is_active() {
-                info!("Trying to populate in Twitter: {}", episode.title().unwrap());
-                let template = Param::get(pool, "twitter_template")
-                    .await
-                    .unwrap();
-                match populate_in_twitter(&ctx, &template, &twitter).await{
-                    Ok(_) => info!("Populated in Twitter: {}", episode.title().unwrap()),
-                    Err(error) => {
-                        error!("Could NOT populate in Twitter: {error}");
-                        let mut next_error = error.source();
-                        // render causes as well
-                        while next_error.is_some(){
-                            error!("caused by: {:#}", next_error.unwrap());
-                            next_error = next_error.unwrap().source();
-                        }
-                    },
-                }
-            }
-            tokio::time::sleep(Duration::from_secs(1)).await;
-        }
-        // Sort episodes
-        all_episodes.sort_by(item_comparator);
-        older_than_episodes.sort_by(item_comparator);
-        //Make short feed
-        debug!("Make short feed");
-        match feed.rss(older_than_episodes){
-            Ok(short_feed) => {
-                //debug!("{}", &short_feed);
-                match std::fs::write("rss/short.xml", short_feed.as_bytes()){
-                    Ok(response) => debug!("{:?}", response),
-                    Err(e) => error!("{:?}", e),
-                };
-            },
-            Err(e) => error!("{:?}", e),
-        };
-        //Make long feed
-        debug!("Make long feed");
-        match feed.rss(all_episodes){
-            Ok(long_feed) => {
-                //debug!("{}", &long_feed);
-                match std::fs::write("rss/long.xml", long_feed.as_bytes()){
-                    Ok(response) => debug!("{:?}", response),
-                    Err(e) => error!("{:?}", e),
-                };
-            },
-            Err(e) => error!("{:?}", e),
-        };
+        announce(pool, &feed, episodes).await?;
+        feed::write(pool, &feed, all_episodes, older_than_episodes).await?;
     }
     Ok(())
 }
 
-fn truncate(value: String, length: usize) -> String {
-    debug!("truncate");
-    match value.char_indices().nth(length) {
-        Some((idx, _)) => value[..idx].to_string(),
-        None => value,
+/// The new episodes of a source podcast, moving its last publication date
+/// to the newest one.
+async fn new_episodes(pool: &SqlitePool, feed: &Feed, podcast: &mut Podcast, complete: &CompletePodcast) -> Vec<Episode>{
+    let news = match complete.get_new(){
+        Ok(news) => news,
+        Err(e) => {
+            error!("Error doing the work: {}", e);
+            return Vec::new();
+        },
+    };
+    info!("Get episodes for: {}. News: {}", &podcast.name, news.len());
+    let Some(first) = news.first() else {
+        return Vec::new();
+    };
+    if let Some(pub_date) = first.pub_date(){
+        info!("{}", pub_date);
+        if let Ok(pub_date) = DateTime::parse_from_rfc2822(pub_date){
+            podcast.last_pub_date = pub_date.to_utc();
+        }else if let Ok(pub_date) = DateTime::parse_from_str(pub_date, "%a, %d %b %Y %H:%M:%S") {
+            podcast.last_pub_date = pub_date.to_utc();
+        }
     }
+    match Podcast::update(pool, podcast).await{
+        Ok(response) => debug!("{:?}", response),
+        Err(e) => error!("{:?}", e),
+    };
+    news.iter().map(|item| Episode::new(feed, complete, item)).collect()
 }
 
-#[allow(unused)]
-fn truncate2(value: String, length: usize) -> String {
-    debug!("truncate");
-    let mut cloned = value.clone();
-    cloned.truncate(length);
-    cloned
-}
-
-async fn populate_in_telegram(ctx: &Value, template: &str, telegram: &Telegram, episode: &Item) -> Result<(), Error>{
-    let mut env = Environment::new();
-    env.add_filter("truncate", truncate);
-    env.add_template("telegram", template)?;
-    let tmpl = env.get_template("telegram")?;
-    let url = episode.enclosure().ok_or("Not enclosure")?.url();
-    let name = util::normalize(episode.title().ok_or("Not title")?)?;
-    let ext = util::get_extension_from_filename(url).ok_or("Not extension")?;
-    let filename = format!("{name}.{ext}");
-    let filepath = format!("/tmp/{filename}");
-    util::fetch_url(url, &filepath).await?;
-    let message = tmpl.render(ctx)?;
-    telegram.send_audio(&filename, &filepath, &message).await?;
-    tokio::fs::remove_file(filepath).await?;
-    Ok(())
-}
-
-async fn populate_in_twitter(ctx: &Value, template: &str, twitter: &Twitter) -> Result<(), Error>{
-    debug!("populate_in_twitter");
-    let mut env = Environment::new();
-    env.add_filter("truncate", truncate);
-    env.add_template("twitter", template)?;
-    let tmpl = env.get_template("twitter")?;
-    debug!("Template: {template}");
-    debug!("Context: {:?}", ctx);
-    debug!("Env: {:?}", env);
-    debug!("tmpl: {:?}", &tmpl);
-    let message = tmpl.render(ctx)?;
-    debug!("message: {message}");
-    twitter.post(&message).await?;
+/// Queues the announcements of the new episodes and tells the webhooks.
+async fn announce(pool: &SqlitePool, feed: &Feed, mut episodes: Vec<Episode>) -> Result<(), Error>{
+    let registry = Registry::load(pool).await?;
+    episodes.sort_by_key(|episode| episode.pub_date);
+    // The dispatcher announces them, even if this fails later
+    publication::enqueue(pool, &registry, &episodes).await?;
+    for episode in episodes.as_slice(){
+        if let Err(e) = webhook::notify(pool, webhook::EPISODE_NEW, webhook::episode_new(feed, episode)).await {
+            error!("Error notifying webhooks: {e}");
+        }
+    }
     Ok(())
 }
 
-pub fn item_comparator(a: &Item, b: &Item) -> std::cmp::Ordering {
-    let date_a = get_pub_date_timestamp(a);
-    let date_b = get_pub_date_timestamp(b);
-    date_b.cmp(&date_a)
-}
-
-pub fn get_pub_date_timestamp(item: &Item) -> i64{
-    if let Ok(pub_date) = DateTime::parse_from_rfc2822(item.pub_date().unwrap()){
-        pub_date.timestamp()
-    }else if let Ok(pub_date) = DateTime::parse_from_str(item.pub_date().unwrap(), "%a, %d %b %Y %H:%M:%S") {
-        pub_date.timestamp()
-    }else {
-        0
+/// Ingests a single podcast right away, with the content pushed by its hub,
+/// or fetching it when the hub only notified the change.
+async fn ingest(pool: &SqlitePool, older_than: i32, podcast_id: i64, channel: Option<Channel>) -> Result<(), Error>{
+    let feed = Feed::get(pool).await?;
+    let Some(mut podcast) = Podcast::get(pool).await?
+        .into_iter()
+        .find(|podcast| podcast.id == podcast_id) else {
+        return Err(format!("Unknown podcast {podcast_id}").into());
+    };
+    let complete = match channel {
+        Some(channel) => CompletePodcast{
+            podcast: podcast.clone(),
+            channel,
+        },
+        None => CompletePodcast::new(&podcast).await?,
+    };
+    let episodes = new_episodes(pool, &feed, &mut podcast, &complete).await;
+    if episodes.is_empty() {
+        return Ok(());
     }
-
+    announce(pool, &feed, episodes).await?;
+    feed::regenerate(pool, older_than).await
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[test]
-    fn truncate_test_0() {
-        let prueba = "1234567890".to_string();
-        let result = truncate(prueba.clone(), 100);
-        assert_eq!(prueba, result);
-    }
-    #[test]
-    fn truncate_test_1() {
-        let prueba = "1234567890".to_string();
-        let result = truncate(prueba.clone(), 1);
-        assert_eq!("1".to_string(), result);
-    }
-    #[test]
-    fn truncate_test_2() {
-        let prueba = "".to_string();
-        let result = truncate(prueba.clone(), 10);
-        assert_eq!(prueba, result);
-    }
-    #[test]
-    fn truncate_test_3() {
-        let prueba = "".to_string();
-        let result = truncate(prueba.clone(), 0);
-        assert_eq!(prueba, result);
-    }
-
-    #[test]
-    fn truncate_test_4() {
-        let prueba = "1234567890".to_string();
-        let result = truncate2(prueba.clone(), 100);
-        assert_eq!(prueba, result);
-    }
-    #[test]
-    fn truncate_test_5() {
-        let prueba = "1234567890".to_string();
-        let result = truncate2(prueba.clone(), 1);
-        assert_eq!("1".to_string(), result);
-    }
-    #[test]
-    fn truncate_test_6() {
-        let prueba = "".to_string();
-        let result = truncate2(prueba.clone(), 10);
-        assert_eq!(prueba, result);
-    }
-    #[test]
-    fn truncate_test_7() {
-        let prueba = "".to_string();
-        let result = truncate2(prueba.clone(), 0);
-        assert_eq!(prueba, result);
-    }
     #[test]
     fn convert_1() {
         let date1 = "Fri, 28 Feb 2025 16:08:58 +0100";
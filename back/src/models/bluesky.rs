@@ -0,0 +1,447 @@
+use async_trait::async_trait;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use regex::Regex;
+use reqwest::{Client, header};
+use serde::{Serialize, Deserialize};
+use serde_json::{Value, json};
+use tracing::{debug, error};
+use super::{Error, Episode, Publisher, Receipt, util};
+use sqlx::sqlite::SqlitePool;
+use crate::models::Param;
+
+const MAX_GRAPHEMES: usize = 300;
+const MAX_THUMB_BYTES: usize = 1_000_000;
+/// Refresh the session this long before the access token expires
+const EXPIRATION_MARGIN: i64 = 5;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Bluesky{
+    pub active: bool,
+    #[serde(default = "default_url")]
+    pub url: String,
+    pub handle: String,
+    pub password: String,
+    #[serde(default)]
+    pub did: String,
+    #[serde(default)]
+    pub access_jwt: String,
+    #[serde(default)]
+    pub refresh_jwt: String,
+    pub template: String,
+    /// Channel artwork, used when the episode has none
+    #[serde(skip)]
+    pub image_url: String,
+}
+
+fn default_url() -> String{
+    "https://bsky.social".to_string()
+}
+
+impl Bluesky{
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(active: bool, url: String, handle: String, password: String,
+               did: String, access_jwt: String, refresh_jwt: String,
+               template: String, image_url: String) -> Self{
+        Self{
+            active,
+            url,
+            handle,
+            password,
+            did,
+            access_jwt,
+            refresh_jwt,
+            template,
+            image_url,
+        }
+    }
+
+    pub async fn get(pool: &SqlitePool) -> Result<Bluesky, Error> {
+        debug!("get_bluesky");
+        let active = Param::get(pool, "bluesky_active").await? == "TRUE";
+        let url = Param::get(pool, "bluesky_url").await?;
+        let handle = Param::get(pool, "bluesky_handle").await?;
+        let password = Param::get(pool, "bluesky_password").await?;
+        let did = Param::get(pool, "bluesky_did").await?;
+        let access_jwt = Param::get(pool, "bluesky_access_jwt").await?;
+        let refresh_jwt = Param::get(pool, "bluesky_refresh_jwt").await?;
+        let template = Param::get(pool, "bluesky_template").await?;
+        let image_url = Param::get(pool, "feed_image_url").await?;
+        Ok(Bluesky::new(active, url, handle, password, did, access_jwt,
+            refresh_jwt, template, image_url))
+    }
+
+    pub async fn set(pool: &SqlitePool, bluesky: &Bluesky) -> Result<Bluesky, Error> {
+        debug!("save_bluesky, {:?}", bluesky);
+        let current = Self::get(pool).await?;
+        let url = bluesky.url.trim_end_matches('/');
+        if current.url != url || current.handle != bluesky.handle || current.password != bluesky.password {
+            // The session belongs to the old account
+            Param::set(pool, "bluesky_did", "").await?;
+            Param::set(pool, "bluesky_access_jwt", "").await?;
+            Param::set(pool, "bluesky_refresh_jwt", "").await?;
+        }
+        Param::set(pool, "bluesky_active", &bluesky.active.to_string().to_uppercase()).await?;
+        Param::set(pool, "bluesky_url", url).await?;
+        Param::set(pool, "bluesky_handle", &bluesky.handle).await?;
+        Param::set(pool, "bluesky_password", &bluesky.password).await?;
+        Param::set(pool, "bluesky_template", &bluesky.template).await?;
+        Self::get(pool).await
+    }
+
+    fn endpoint(&self, method: &str) -> String{
+        format!("{}/xrpc/{method}", self.url.trim_end_matches('/'))
+    }
+
+    fn update_session(&mut self, session: &Value) -> Result<(), Error>{
+        let field = |name: &str| -> Result<String, Error> {
+            Ok(session.get(name)
+                .and_then(|value| value.as_str())
+                .ok_or(format!("Session without {name}"))?
+                .to_string())
+        };
+        self.access_jwt = field("accessJwt")?;
+        self.refresh_jwt = field("refreshJwt")?;
+        self.did = field("did")?;
+        Ok(())
+    }
+
+    /// When the access token expires, from its `exp` claim.
+    pub fn expires_at(&self) -> Option<DateTime<Utc>>{
+        let payload = self.access_jwt.split('.').nth(1)?;
+        let claims: Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+        DateTime::from_timestamp(claims.get("exp")?.as_i64()?, 0)
+    }
+
+    pub fn needs_refresh(&self) -> bool{
+        match self.expires_at() {
+            _ if self.did.is_empty() => true,
+            Some(expires_at) => expires_at - Duration::minutes(EXPIRATION_MARGIN) <= Utc::now(),
+            None => true,
+        }
+    }
+
+    pub async fn login(&mut self) -> Result<(), Error>{
+        debug!("login");
+        let session: Value = Client::new()
+            .post(self.endpoint("com.atproto.server.createSession"))
+            .json(&json!({
+                "identifier": self.handle,
+                "password": self.password,
+            }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        self.update_session(&session)
+    }
+
+    pub async fn refresh_session(&mut self) -> Result<(), Error>{
+        debug!("refresh_session");
+        let session: Value = Client::new()
+            .post(self.endpoint("com.atproto.server.refreshSession"))
+            .bearer_auth(&self.refresh_jwt)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        self.update_session(&session)
+    }
+
+    pub async fn upload_blob(&self, url: &str) -> Result<Value, Error>{
+        debug!("upload_blob: {url}");
+        let response = Client::new()
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?;
+        let content_type = response.headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("image/jpeg")
+            .to_string();
+        let bytes = response.bytes().await?;
+        if bytes.len() > MAX_THUMB_BYTES {
+            return Err(format!("Thumbnail too big: {} bytes", bytes.len()).into());
+        }
+        let response: Value = Client::new()
+            .post(self.endpoint("com.atproto.repo.uploadBlob"))
+            .bearer_auth(&self.access_jwt)
+            .header(header::CONTENT_TYPE, content_type)
+            .body(bytes)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(response.get("blob").ok_or("Upload without blob")?.clone())
+    }
+
+    /// Link card shown below the post.
+    async fn embed(&self, episode: &Episode) -> Value{
+        let mut external = json!({
+            "uri": episode.link,
+            "title": episode.title,
+            "description": util::truncate_words(&episode.description, MAX_GRAPHEMES),
+        });
+        let image = episode.image.as_deref().unwrap_or(&self.image_url);
+        if !image.is_empty() {
+            match self.upload_blob(image).await {
+                Ok(blob) => external["thumb"] = blob,
+                Err(e) => error!("Could NOT upload thumbnail to Bluesky: {e}"),
+            }
+        }
+        json!({
+            "$type": "app.bsky.embed.external",
+            "external": external,
+        })
+    }
+
+    pub async fn post(&self, message: &str, embed: Option<Value>) -> Result<Value, Error>{
+        debug!("post: {message}");
+        let text = util::truncate_words(message, MAX_GRAPHEMES);
+        let mut record = json!({
+            "$type": "app.bsky.feed.post",
+            "text": text,
+            "facets": facets(&text),
+            "createdAt": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        });
+        if let Some(embed) = embed {
+            record["embed"] = embed;
+        }
+        Ok(Client::new()
+            .post(self.endpoint("com.atproto.repo.createRecord"))
+            .bearer_auth(&self.access_jwt)
+            .json(&json!({
+                "repo": self.did,
+                "collection": "app.bsky.feed.post",
+                "record": record,
+            }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    pub async fn delete_post(&self, uri: &str) -> Result<(), Error>{
+        debug!("delete_post: {uri}");
+        Client::new()
+            .post(self.endpoint("com.atproto.repo.deleteRecord"))
+            .bearer_auth(&self.access_jwt)
+            .json(&json!({
+                "repo": self.did,
+                "collection": "app.bsky.feed.post",
+                "rkey": rkey(uri)?,
+            }))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Record key of a post, the last segment of its `at://` uri.
+fn rkey(uri: &str) -> Result<&str, Error>{
+    uri.rsplit('/').next().filter(|rkey| !rkey.is_empty()).ok_or("Invalid post uri".into())
+}
+
+/// Rich text facets for the links and hashtags of the text. Bluesky
+/// indexes them by UTF-8 byte offsets.
+pub fn facets(text: &str) -> Vec<Value>{
+    let mut facets = Vec::new();
+    let links = Regex::new(r"https?://[^\s]+[^\s.,;:!?)]").unwrap();
+    for link in links.find_iter(text) {
+        facets.push(json!({
+            "index": {"byteStart": link.start(), "byteEnd": link.end()},
+            "features": [{"$type": "app.bsky.richtext.facet#link", "uri": link.as_str()}],
+        }));
+    }
+    let tags = Regex::new(r"(?:^|\s)(#[\p{L}\p{N}_]*\p{L}[\p{L}\p{N}_]*)").unwrap();
+    for capture in tags.captures_iter(text) {
+        let tag = capture.get(1).unwrap();
+        facets.push(json!({
+            "index": {"byteStart": tag.start(), "byteEnd": tag.end()},
+            "features": [{"$type": "app.bsky.richtext.facet#tag", "tag": &tag.as_str()[1..]}],
+        }));
+    }
+    facets
+}
+
+#[async_trait]
+impl Publisher for Bluesky{
+    fn name(&self) -> &'static str{
+        "bluesky"
+    }
+
+    fn is_active(&self) -> bool{
+        self.active
+    }
+
+    fn template(&self) -> &str{
+        &self.template
+    }
+
+    fn limit(&self) -> Option<usize>{
+        Some(MAX_GRAPHEMES)
+    }
+
+    fn validate(&self) -> Result<(), Error>{
+        if self.url.is_empty() {
+            return Err("Bluesky url is empty".into());
+        }
+        if self.handle.is_empty() || self.password.is_empty() {
+            return Err("Bluesky handle or app password are empty".into());
+        }
+        Ok(())
+    }
+
+    async fn prepare(&mut self, pool: &SqlitePool) -> Result<(), Error>{
+        if !self.needs_refresh() {
+            return Ok(());
+        }
+        let refreshed = if self.refresh_jwt.is_empty() {
+            false
+        } else {
+            match self.refresh_session().await {
+                Ok(()) => true,
+                Err(e) => {
+                    error!("Could NOT refresh Bluesky session: {e}");
+                    false
+                }
+            }
+        };
+        if !refreshed {
+            self.login().await?;
+        }
+        Param::set(pool, "bluesky_did", &self.did).await?;
+        Param::set(pool, "bluesky_access_jwt", &self.access_jwt).await?;
+        Param::set(pool, "bluesky_refresh_jwt", &self.refresh_jwt).await?;
+        Ok(())
+    }
+
+    async fn publish(&self, message: &str, episode: &Episode) -> Result<Receipt, Error>{
+        let embed = if episode.link.is_empty() {
+            None
+        } else {
+            Some(self.embed(episode).await)
+        };
+        let post = self.post(message, embed).await?;
+        let uri = post.get("uri")
+            .and_then(|uri| uri.as_str())
+            .ok_or("Post without uri")?
+            .to_string();
+        Ok(Receipt{
+            remote_url: Some(format!("https://bsky.app/profile/{}/post/{}", self.did, rkey(&uri)?)),
+            remote_id: uri,
+        })
+    }
+
+    async fn delete(&self, receipt: &Receipt) -> Result<(), Error>{
+        self.delete_post(&receipt.remote_id).await
+    }
+
+    async fn test_connection(&self) -> Result<(), Error>{
+        self.clone().login().await
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::{Bluesky, facets};
+    use crate::models::{Episode, Param, Publisher, mock};
+    use axum::{Json, Router, routing, extract::State};
+    use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+    use chrono::{Duration, Utc};
+    use serde_json::{Value, json};
+    use std::sync::{Arc, Mutex};
+
+    fn jwt(seconds: i64) -> String {
+        let exp = (Utc::now() + Duration::seconds(seconds)).timestamp();
+        format!("header.{}.signature", URL_SAFE_NO_PAD.encode(json!({"exp": exp}).to_string()))
+    }
+
+    #[tokio::test]
+    async fn test_session(){
+        let pool = mock::pool().await;
+        let mut bluesky = Bluesky::new(true, "https://bsky.social".to_string(), "podmixer.bsky.social".to_string(),
+            "app-password".to_string(), "did:plc:podmixer".to_string(), jwt(3600), "refresh".to_string(),
+            "".to_string(), "".to_string());
+        assert!(!bluesky.needs_refresh());
+        // Kept until it is about to expire, without asking for a new one
+        bluesky.prepare(&pool).await.unwrap();
+        bluesky.access_jwt = jwt(60);
+        assert!(bluesky.needs_refresh());
+        bluesky.access_jwt = "opaque".to_string();
+        assert!(bluesky.needs_refresh());
+
+        bluesky.access_jwt = jwt(3600);
+        Bluesky::set(&pool, &bluesky).await.unwrap();
+        Param::set(&pool, "bluesky_did", &bluesky.did).await.unwrap();
+        Param::set(&pool, "bluesky_access_jwt", &bluesky.access_jwt).await.unwrap();
+        let saved = Bluesky::set(&pool, &bluesky).await.unwrap();
+        assert_eq!(saved.did, "did:plc:podmixer");
+        bluesky.handle = "other.bsky.social".to_string();
+        let saved = Bluesky::set(&pool, &bluesky).await.unwrap();
+        assert!(saved.did.is_empty());
+        assert!(saved.access_jwt.is_empty());
+        assert!(saved.refresh_jwt.is_empty());
+    }
+
+    #[test]
+    fn test_facets(){
+        let text = "Episodio ñu #podcast https://example.com/1.";
+        let facets = facets(text);
+        assert_eq!(facets.len(), 2);
+        let link = &facets[0];
+        assert_eq!(link["features"][0]["uri"], "https://example.com/1");
+        let start = link["index"]["byteStart"].as_u64().unwrap() as usize;
+        let end = link["index"]["byteEnd"].as_u64().unwrap() as usize;
+        assert_eq!(&text[start..end], "https://example.com/1");
+        let tag = &facets[1];
+        assert_eq!(tag["features"][0]["tag"], "podcast");
+        let start = tag["index"]["byteStart"].as_u64().unwrap() as usize;
+        assert_eq!(&text[start..start + 8], "#podcast");
+    }
+
+    #[tokio::test]
+    async fn bluesky_publish(){
+        let records: Arc<Mutex<Vec<Value>>> = Arc::new(Mutex::new(Vec::new()));
+        let router = Router::new()
+            .route("/cover.jpg", routing::get(|| async { vec![0u8; 16] }))
+            .route("/xrpc/com.atproto.server.createSession", routing::post(|| async {
+                Json(json!({"accessJwt": "access", "refreshJwt": "refresh", "did": "did:plc:podmixer"}))
+            }))
+            .route("/xrpc/com.atproto.repo.uploadBlob", routing::post(|| async {
+                Json(json!({"blob": {"$type": "blob", "ref": {"$link": "cid"}, "mimeType": "image/jpeg", "size": 16}}))
+            }))
+            .route("/xrpc/com.atproto.repo.createRecord", routing::post(
+                |State(records): State<Arc<Mutex<Vec<Value>>>>, Json(record): Json<Value>| async move {
+                    records.lock().unwrap().push(record);
+                    Json(json!({"uri": "at://did:plc:podmixer/app.bsky.feed.post/1", "cid": "cid"}))
+                }))
+            .with_state(records.clone());
+        let url = mock::serve(router).await;
+        let mut bluesky = Bluesky::new(true, url.clone(), "podmixer.bsky.social".to_string(),
+            "app-password".to_string(), "".to_string(), "".to_string(), "".to_string(),
+            "".to_string(), format!("{url}/cover.jpg"));
+        bluesky.login().await.unwrap();
+        assert_eq!(bluesky.did, "did:plc:podmixer");
+        let episode = Episode{
+            title: "Episode 1".to_string(),
+            link: "https://example.com/1".to_string(),
+            ..Default::default()
+        };
+        let receipt = bluesky.publish("Nuevo #podcast https://example.com/1", &episode).await.unwrap();
+        assert_eq!(receipt.remote_url.as_deref(), Some("https://bsky.app/profile/did:plc:podmixer/post/1"));
+        let records = records.lock().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["repo"], "did:plc:podmixer");
+        let record = &records[0]["record"];
+        assert_eq!(record["facets"].as_array().unwrap().len(), 2);
+        assert_eq!(record["embed"]["external"]["uri"], "https://example.com/1");
+        assert_eq!(record["embed"]["external"]["thumb"]["ref"]["$link"], "cid");
+    }
+}
@@ -0,0 +1,192 @@
+use chrono::Utc;
+use sqlx::sqlite::SqlitePool;
+use std::time::Duration;
+use tracing::{debug, error, info};
+
+use super::{Error, Podcast, Registry, Telegram, feed, podcast::fetch_channel, publication::Publication, util};
+
+/// Seconds Telegram holds a request open waiting for new messages
+const POLL_TIMEOUT: u64 = 30;
+/// Seconds to wait when the bot is not configured or Telegram fails
+const IDLE: u64 = 60;
+/// Publications shown by `/last`
+const LAST: usize = 5;
+
+const HELP: &str = "/status - Podcasts and publications
+/podcasts - Source podcasts
+/add &lt;url&gt; - Add a podcast
+/pause &lt;name&gt; - Stop mixing a podcast
+/resume &lt;name&gt; - Mix a podcast again
+/regenerate - Regenerate the feeds
+/last - Last publications";
+
+/// Answers the commands sent to the Telegram bot from the admin chats. The
+/// configuration is read again in every round, so changes apply without a
+/// restart.
+pub async fn run(pool: &SqlitePool, older_than: i32) {
+    let mut offset = 0;
+    loop {
+        let telegram = match Telegram::get(pool).await {
+            Ok(telegram) if !telegram.token.is_empty() && !telegram.admin_chats.is_empty() => telegram,
+            Ok(_) => {
+                tokio::time::sleep(Duration::from_secs(IDLE)).await;
+                continue;
+            },
+            Err(e) => {
+                util::log_error("Bot config error", &e);
+                tokio::time::sleep(Duration::from_secs(IDLE)).await;
+                continue;
+            },
+        };
+        let updates = match telegram.get_updates(offset, POLL_TIMEOUT).await {
+            Ok(updates) => updates,
+            Err(e) => {
+                util::log_error("Bot updates error", &e);
+                tokio::time::sleep(Duration::from_secs(IDLE)).await;
+                continue;
+            },
+        };
+        for update in updates {
+            if let Some(id) = update["update_id"].as_i64() {
+                offset = offset.max(id + 1);
+            }
+            let message = &update["message"];
+            let (Some(chat_id), Some(text)) = (message["chat"]["id"].as_i64(), message["text"].as_str()) else {
+                continue;
+            };
+            let chat_id = chat_id.to_string();
+            if !telegram.admin_chats.contains(&chat_id) {
+                info!("Ignoring a command from the chat {chat_id}");
+                continue;
+            }
+            if !text.starts_with('/') {
+                continue;
+            }
+            let answer = handle(pool, text, older_than).await;
+            if let Err(e) = telegram.reply(&chat_id, &answer).await {
+                error!("Error answering the chat {chat_id}: {e}");
+            }
+        }
+    }
+}
+
+/// Runs a command and returns the answer, as Telegram html.
+pub async fn handle(pool: &SqlitePool, text: &str, older_than: i32) -> String {
+    let (command, argument) = match text.trim().split_once(char::is_whitespace) {
+        Some((command, argument)) => (command, argument.trim()),
+        None => (text.trim(), ""),
+    };
+    // In groups the commands come as /command@bot_name
+    let command = command.split('@').next().unwrap_or_default();
+    debug!("Bot command {command} {argument}");
+    let answer = match command {
+        "/start" | "/help" => Ok(HELP.to_string()),
+        "/status" => status(pool).await,
+        "/podcasts" => podcasts(pool).await,
+        "/add" => add(pool, argument).await,
+        "/pause" => set_active(pool, argument, false).await,
+        "/resume" => set_active(pool, argument, true).await,
+        "/regenerate" => feed::regenerate(pool, older_than)
+            .await
+            .map(|_| "Feeds regenerated".to_string()),
+        "/last" => last(pool).await,
+        _ => Ok(format!("Unknown command {}\n\n{HELP}", util::escape_html(command))),
+    };
+    answer.unwrap_or_else(|e| format!("Error: {}", util::escape_html(&e.to_string())))
+}
+
+async fn status(pool: &SqlitePool) -> Result<String, Error> {
+    let podcasts = Podcast::get(pool).await?;
+    let active = podcasts.iter().filter(|podcast| podcast.active).count();
+    let mut lines = vec![format!("<b>Podcasts</b>: {active} active of {}", podcasts.len())];
+    for (status, count) in Publication::count(pool).await? {
+        lines.push(format!("<b>{status}</b>: {count}"));
+    }
+    let registry = Registry::load(pool).await?;
+    let publishers: Vec<&str> = registry.active().map(|publisher| publisher.name()).collect();
+    lines.push(format!("<b>Publishers</b>: {}", if publishers.is_empty() {
+        "none".to_string()
+    } else {
+        publishers.join(", ")
+    }));
+    Ok(lines.join("\n"))
+}
+
+async fn podcasts(pool: &SqlitePool) -> Result<String, Error> {
+    let podcasts = Podcast::get(pool).await?;
+    if podcasts.is_empty() {
+        return Ok("There are no podcasts".to_string());
+    }
+    Ok(podcasts.iter()
+        .map(|podcast| format!("{} {}",
+            if podcast.active { "▶️" } else { "⏸️" },
+            util::escape_html(&podcast.name)))
+        .collect::<Vec<String>>()
+        .join("\n"))
+}
+
+async fn add(pool: &SqlitePool, url: &str) -> Result<String, Error> {
+    if url.is_empty() {
+        return Err("Usage: /add <url>".into());
+    }
+    let channel = fetch_channel(url).await?;
+    // Only the episodes published from now on are announced
+    let podcast = Podcast::create(pool, channel.title(), url, true, &Utc::now()).await?;
+    Ok(format!("Podcast {} added", util::escape_html(&podcast.name)))
+}
+
+async fn set_active(pool: &SqlitePool, name: &str, active: bool) -> Result<String, Error> {
+    if name.is_empty() {
+        return Err(format!("Usage: /{} <name>", if active { "resume" } else { "pause" }).into());
+    }
+    let mut podcast = Podcast::get(pool)
+        .await?
+        .into_iter()
+        .find(|podcast| podcast.name.eq_ignore_ascii_case(name))
+        .ok_or_else(|| format!("There is no podcast {name}"))?;
+    podcast.active = active;
+    let podcast = Podcast::update(pool, &podcast).await?;
+    Ok(format!("Podcast {} {}", util::escape_html(&podcast.name), if active { "resumed" } else { "paused" }))
+}
+
+async fn last(pool: &SqlitePool) -> Result<String, Error> {
+    let publications = Publication::get(pool, None, None).await?;
+    if publications.is_empty() {
+        return Ok("There are no publications".to_string());
+    }
+    Ok(publications.iter()
+        .take(LAST)
+        .map(|publication| format!("<b>{}</b> in {}: {}",
+            util::escape_html(&publication.episode.title),
+            publication.destination,
+            publication.status))
+        .collect::<Vec<String>>()
+        .join("\n"))
+}
+
+#[cfg(test)]
+mod test{
+    use axum::{Router, routing};
+    use super::handle;
+    use crate::models::{Podcast, mock};
+
+    const RSS: &str = r#"<?xml version="1.0"?><rss version="2.0"><channel><title>Atareao &amp; Co</title>
+        <link>https://atareao.es</link><description>Linux</description></channel></rss>"#;
+
+    #[tokio::test]
+    async fn test_commands(){
+        let pool = mock::pool().await;
+        let url = mock::serve(Router::new().route("/rss", routing::get(|| async { RSS }))).await;
+        assert_eq!(handle(&pool, "/podcasts", 30).await, "There are no podcasts");
+        let answer = handle(&pool, &format!("/add {url}/rss"), 30).await;
+        assert_eq!(answer, "Podcast Atareao &amp; Co added");
+        assert!(handle(&pool, "/add", 30).await.starts_with("Error: Usage"));
+        assert_eq!(handle(&pool, "/pause@podmixer_bot atareao & co", 30).await, "Podcast Atareao &amp; Co paused");
+        assert!(!Podcast::get(&pool).await.unwrap()[0].active);
+        assert_eq!(handle(&pool, "/podcasts", 30).await, "⏸️ Atareao &amp; Co");
+        assert!(handle(&pool, "/status", 30).await.starts_with("<b>Podcasts</b>: 0 active of 1"));
+        assert_eq!(handle(&pool, "/pause nobody", 30).await, "Error: There is no podcast nobody");
+        assert_eq!(handle(&pool, "/last", 30).await, "There are no publications");
+        assert!(handle(&pool, "/nope", 30).await.starts_with("Unknown command /nope"));
+    }
+}
@@ -0,0 +1,68 @@
+// Apple Podcasts category taxonomy
+// https://podcasters.apple.com/support/1691-apple-podcasts-categories
+const CATEGORIES: &[(&str, &[&str])] = &[
+    ("Arts", &["Books", "Design", "Fashion & Beauty", "Food", "Performing Arts",
+        "Visual Arts"]),
+    ("Business", &["Careers", "Entrepreneurship", "Investing", "Management",
+        "Marketing", "Non-Profit"]),
+    ("Comedy", &["Comedy Interviews", "Improv", "Stand-Up"]),
+    ("Education", &["Courses", "How To", "Language Learning", "Self-Improvement"]),
+    ("Fiction", &["Comedy Fiction", "Drama", "Science Fiction"]),
+    ("Government", &[]),
+    ("History", &[]),
+    ("Health & Fitness", &["Alternative Health", "Fitness", "Medicine",
+        "Mental Health", "Nutrition", "Sexuality"]),
+    ("Kids & Family", &["Education for Kids", "Parenting", "Pets & Animals",
+        "Stories for Kids"]),
+    ("Leisure", &["Animation & Manga", "Automotive", "Aviation", "Crafts",
+        "Games", "Hobbies", "Home & Garden", "Video Games"]),
+    ("Music", &["Music Commentary", "Music History", "Music Interviews"]),
+    ("News", &["Business News", "Daily News", "Entertainment News",
+        "News Commentary", "Politics", "Sports News", "Tech News"]),
+    ("Religion & Spirituality", &["Buddhism", "Christianity", "Hinduism",
+        "Islam", "Judaism", "Religion", "Spirituality"]),
+    ("Science", &["Astronomy", "Chemistry", "Earth Sciences", "Life Sciences",
+        "Mathematics", "Natural Sciences", "Nature", "Physics",
+        "Social Sciences"]),
+    ("Society & Culture", &["Documentary", "Personal Journals", "Philosophy",
+        "Places & Travel", "Relationships"]),
+    ("Sports", &["Baseball", "Basketball", "Cricket", "Fantasy Sports",
+        "Football", "Golf", "Hockey", "Rugby", "Running", "Soccer",
+        "Swimming", "Tennis", "Volleyball", "Wilderness", "Wrestling"]),
+    ("Technology", &[]),
+    ("True Crime", &[]),
+    ("TV & Film", &["After Shows", "Film History", "Film Interviews",
+        "Film Reviews", "TV Reviews"]),
+];
+
+pub fn is_valid_category(category: &str) -> bool {
+    CATEGORIES.iter().any(|(name, _)| *name == category)
+}
+
+pub fn is_valid_subcategory(category: &str, subcategory: &str) -> bool {
+    CATEGORIES.iter()
+        .find(|(name, _)| *name == category)
+        .map(|(_, subcategories)| subcategories.contains(&subcategory))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test{
+    use super::{is_valid_category, is_valid_subcategory};
+
+    #[test]
+    fn test_category(){
+        assert!(is_valid_category("Technology"));
+        assert!(is_valid_category("Society & Culture"));
+        assert!(!is_valid_category("Tecnología"));
+        assert!(!is_valid_category(""));
+    }
+
+    #[test]
+    fn test_subcategory(){
+        assert!(is_valid_subcategory("News", "Tech News"));
+        assert!(!is_valid_subcategory("News", "Podcasting"));
+        assert!(!is_valid_subcategory("Technology", "Tech News"));
+        assert!(!is_valid_subcategory("Unknown", "Tech News"));
+    }
+}
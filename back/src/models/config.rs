@@ -1,5 +1,5 @@
 use serde::{Serialize, Deserialize};
-use sqlx::{sqlite::{SqlitePool, SqliteRow}, query, Row};
+use sqlx::{sqlite::{Sqlite, SqlitePool, SqliteRow}, query, Executor, Row};
 use chrono::{DateTime, Utc};
 use tracing::debug;
 use super::Error;
@@ -37,7 +37,9 @@ impl Param{
             .map_err(|e| e.into())
     }
 
-    pub async fn set(pool: &SqlitePool, key: &str, value: &str) -> Result<Param, Error>{
+    /// Saves the value, in the pool or inside a transaction.
+    pub async fn set<'e, E>(executor: E, key: &str, value: &str) -> Result<Param, Error>
+    where E: Executor<'e, Database = Sqlite> {
         debug!("set {key}={value}");
         let current_ts = Utc::now();
         let sql = "INSERT INTO config(key, value, updated_at) \
@@ -51,7 +53,7 @@ impl Param{
             .bind(value)
             .bind(current_ts)
             .map(Self::from_row)
-            .fetch_one(pool)
+            .fetch_one(executor)
             .await
             .map_err(|e| e.into())
     }
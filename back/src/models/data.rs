@@ -5,7 +5,6 @@ use serde_json::Value;
 pub enum Data {
     None,
     One(Value),
-    #[allow(unused)]
     Some(Vec<Value>),
 }
 
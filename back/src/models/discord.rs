@@ -0,0 +1,279 @@
+use async_trait::async_trait;
+use chrono::SecondsFormat;
+use reqwest::{Client, Url};
+use serde::{Serialize, Deserialize};
+use serde_json::{Value, json};
+use tracing::{debug, error};
+use super::{Error, Episode, Publisher, Receipt, util};
+use sqlx::sqlite::SqlitePool;
+use crate::models::Param;
+
+const MAX_CONTENT: usize = 2000;
+const MAX_TITLE: usize = 256;
+const MAX_DESCRIPTION: usize = 300;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Discord{
+    pub active: bool,
+    pub webhooks: Vec<String>,
+    #[serde(default)]
+    pub username: String,
+    pub template: String,
+    /// Channel artwork, used when the episode has none
+    #[serde(skip)]
+    pub image_url: String,
+}
+
+impl Discord{
+    pub fn new(active: bool, webhooks: Vec<String>, username: String,
+               template: String, image_url: String) -> Self{
+        Self{
+            active,
+            webhooks,
+            username,
+            template,
+            image_url,
+        }
+    }
+
+    pub async fn get(pool: &SqlitePool) -> Result<Discord, Error> {
+        debug!("get_discord");
+        let active = Param::get(pool, "discord_active").await? == "TRUE";
+        let webhooks = util::lines(&Param::get(pool, "discord_webhooks").await?);
+        let username = Param::get(pool, "discord_username").await?;
+        let template = Param::get(pool, "discord_template").await?;
+        let image_url = Param::get(pool, "feed_image_url").await?;
+        Ok(Discord::new(active, webhooks, username, template, image_url))
+    }
+
+    pub async fn set(pool: &SqlitePool, discord: &Discord) -> Result<Discord, Error> {
+        debug!("save_discord, {:?}", discord);
+        Param::set(pool, "discord_active", &discord.active.to_string().to_uppercase()).await?;
+        Param::set(pool, "discord_webhooks", &discord.webhooks.join("\n")).await?;
+        Param::set(pool, "discord_username", &discord.username).await?;
+        Param::set(pool, "discord_template", &discord.template).await?;
+        Self::get(pool).await
+    }
+
+    /// Message with an embed card for the episode.
+    pub fn payload(&self, message: &str, episode: &Episode) -> Value{
+        let mut embed = json!({
+            "title": util::truncate_words(&episode.title, MAX_TITLE),
+            "description": util::truncate_words(&episode.description, MAX_DESCRIPTION),
+        });
+        if !episode.link.is_empty() {
+            embed["url"] = json!(episode.link);
+        }
+        if !episode.podcast.is_empty() {
+            embed["footer"] = json!({"text": episode.podcast});
+        }
+        if let Some(pub_date) = episode.pub_date {
+            embed["timestamp"] = json!(pub_date.to_rfc3339_opts(SecondsFormat::Secs, true));
+        }
+        let image = episode.image.as_deref().unwrap_or(&self.image_url);
+        if !image.is_empty() {
+            embed["thumbnail"] = json!({"url": image});
+        }
+        let mut payload = json!({
+            "content": util::truncate_words(message, MAX_CONTENT),
+            "embeds": [embed],
+        });
+        if !self.username.is_empty() {
+            payload["username"] = json!(self.username);
+        }
+        payload
+    }
+
+    /// Posts the message and returns it, with its id.
+    pub async fn post(&self, webhook: &str, payload: &Value) -> Result<Value, Error>{
+        debug!("post: {payload}");
+        let mut url = Url::parse(webhook)?;
+        url.query_pairs_mut().append_pair("wait", "true");
+        Ok(Client::new()
+            .post(url)
+            .json(payload)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// Url of a message posted by the webhook, keeping its query (thread_id).
+    fn message_url(webhook: &str, message_id: &str) -> Result<Url, Error>{
+        let mut url = Url::parse(webhook)?;
+        url.path_segments_mut()
+            .map_err(|_| "Invalid webhook")?
+            .pop_if_empty()
+            .extend(["messages", message_id]);
+        Ok(url)
+    }
+
+    pub async fn edit_message(&self, webhook: &str, message_id: &str, payload: &Value) -> Result<(), Error>{
+        debug!("edit_message: {message_id}");
+        Client::new()
+            .patch(Self::message_url(webhook, message_id)?)
+            .json(payload)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    pub async fn delete_message(&self, webhook: &str, message_id: &str) -> Result<(), Error>{
+        debug!("delete_message: {message_id}");
+        Client::new()
+            .delete(Self::message_url(webhook, message_id)?)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Webhook and message id of every message in the receipt, one pair per
+    /// line. Older receipts only have the ids, in the order of the webhooks.
+    fn messages<'a>(&'a self, receipt: &'a Receipt) -> impl Iterator<Item = (&'a str, &'a str)>{
+        receipt.remote_id.split('\n')
+            .enumerate()
+            .filter_map(|(index, line)| match line.split_once(' ') {
+                Some(pair) => Some(pair),
+                None => self.webhooks.get(index).map(|webhook| (webhook.as_str(), line)),
+            })
+            .filter(|(_, message_id)| !message_id.is_empty())
+    }
+}
+
+#[async_trait]
+impl Publisher for Discord{
+    fn name(&self) -> &'static str{
+        "discord"
+    }
+
+    fn is_active(&self) -> bool{
+        self.active
+    }
+
+    fn template(&self) -> &str{
+        &self.template
+    }
+
+    fn limit(&self) -> Option<usize>{
+        Some(MAX_CONTENT)
+    }
+
+    fn validate(&self) -> Result<(), Error>{
+        if self.webhooks.is_empty() {
+            return Err("Discord has no webhooks".into());
+        }
+        Ok(())
+    }
+
+    async fn publish(&self, message: &str, episode: &Episode) -> Result<Receipt, Error>{
+        let payload = self.payload(message, episode);
+        let mut messages = Vec::new();
+        for webhook in self.webhooks.iter() {
+            match self.post(webhook, &payload).await {
+                Ok(message) => {
+                    if let Some(id) = message.get("id").and_then(|id| id.as_str()) {
+                        messages.push(format!("{webhook} {id}"));
+                    }
+                },
+                Err(e) => error!("Could NOT post to Discord webhook: {e}"),
+            }
+        }
+        // Retrying would post it again in the webhooks that worked
+        if messages.is_empty() {
+            return Err("All the Discord webhooks failed".into());
+        }
+        Ok(Receipt{
+            remote_id: messages.join("\n"),
+            remote_url: None,
+        })
+    }
+
+    async fn edit(&self, receipt: &Receipt, message: &str, episode: &Episode) -> Result<(), Error>{
+        let payload = self.payload(message, episode);
+        for (webhook, message_id) in self.messages(receipt) {
+            self.edit_message(webhook, message_id, &payload).await?;
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, receipt: &Receipt) -> Result<(), Error>{
+        for (webhook, message_id) in self.messages(receipt) {
+            self.delete_message(webhook, message_id).await?;
+        }
+        Ok(())
+    }
+
+    async fn test_connection(&self) -> Result<(), Error>{
+        // A GET on the webhook returns its details without posting
+        for webhook in self.webhooks.iter() {
+            Client::new()
+                .get(webhook)
+                .send()
+                .await?
+                .error_for_status()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::Discord;
+    use crate::models::{Episode, Publisher, mock};
+    use axum::{Json, Router, routing, extract::{Path, State}};
+    use chrono::{TimeZone, Utc};
+    use serde_json::{Value, json};
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn discord_publish(){
+        let payloads: Arc<Mutex<Vec<Value>>> = Arc::new(Mutex::new(Vec::new()));
+        let router = Router::new()
+            .route("/webhooks/{id}/{token}", routing::post(
+                |State(payloads): State<Arc<Mutex<Vec<Value>>>>, Path((id, _)): Path<(String, String)>, Json(payload): Json<Value>| async move {
+                    payloads.lock().unwrap().push(payload);
+                    Json(json!({"id": format!("message-{id}")}))
+                }))
+            .with_state(payloads.clone());
+        let url = mock::serve(router).await;
+        let discord = Discord::new(true,
+            vec![format!("{url}/webhooks/1/a"), format!("{url}/webhooks/2/b")],
+            "podmixer".to_string(), "".to_string(),
+            "https://example.com/cover.jpg".to_string());
+        let episode = Episode{
+            podcast: "Podcast".to_string(),
+            title: "Episode 1".to_string(),
+            description: "Description".to_string(),
+            link: "https://example.com/1".to_string(),
+            pub_date: Some(Utc.with_ymd_and_hms(2025, 2, 28, 15, 8, 58).unwrap()),
+            ..Default::default()
+        };
+        let receipt = discord.publish("New episode", &episode).await.unwrap();
+        assert_eq!(receipt.remote_id, format!("{url}/webhooks/1/a message-1\n{url}/webhooks/2/b message-2"));
+        // Still edited where it was posted after the webhooks change
+        let changed = Discord{
+            webhooks: vec![format!("{url}/webhooks/2/b")],
+            ..discord.clone()
+        };
+        let messages: Vec<(&str, &str)> = changed.messages(&receipt).collect();
+        assert_eq!(messages, [
+            (format!("{url}/webhooks/1/a").as_str(), "message-1"),
+            (format!("{url}/webhooks/2/b").as_str(), "message-2"),
+        ]);
+        let payloads = payloads.lock().unwrap();
+        assert_eq!(payloads.len(), 2);
+        assert_eq!(payloads[0]["content"], "New episode");
+        assert_eq!(payloads[0]["username"], "podmixer");
+        assert_eq!(payloads[0]["embeds"][0], json!({
+            "title": "Episode 1",
+            "description": "Description",
+            "url": "https://example.com/1",
+            "footer": {"text": "Podcast"},
+            "timestamp": "2025-02-28T15:08:58Z",
+            "thumbnail": {"url": "https://example.com/cover.jpg"},
+        }));
+    }
+}
@@ -0,0 +1,150 @@
+use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+use minijinja::{context, Value};
+use html2text::from_read;
+use rss::Item;
+use super::{CompletePodcast, Feed, util};
+
+/// A new episode of a source podcast, as announced by the publishers.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Episode{
+    pub podcast: String,
+    pub title: String,
+    pub description: String,
+    pub link: String,
+    pub guid: String,
+    pub pub_date: Option<DateTime<Utc>>,
+    pub image: Option<String>,
+    pub enclosure_url: Option<String>,
+    pub enclosure_type: Option<String>,
+    pub enclosure_length: Option<u64>,
+    /// Seconds, from `itunes:duration`
+    pub duration: Option<u64>,
+    /// Feed of the source podcast
+    #[serde(default)]
+    pub podcast_url: String,
+    #[serde(default)]
+    pub episode_number: Option<u32>,
+    #[serde(default)]
+    pub season_number: Option<u32>,
+    /// From `itunes:keywords` and the categories of the item
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    /// Title of the mix
+    #[serde(default)]
+    pub mix: String,
+    /// Public url of the mixed feed
+    #[serde(default)]
+    pub feed_url: String,
+    /// Times the publication being sent was resent, not stored with it
+    #[serde(skip)]
+    pub resends: i64,
+}
+
+impl Episode {
+    pub fn new(feed: &Feed, complete: &CompletePodcast, item: &Item) -> Self{
+        let podcast = &complete.podcast;
+        let itunes = item.itunes_ext();
+        let mut keywords: Vec<String> = itunes
+            .and_then(|itunes| itunes.keywords())
+            .map(|keywords| keywords.split(',')
+                .map(|keyword| keyword.trim().to_string())
+                .filter(|keyword| !keyword.is_empty())
+                .collect())
+            .unwrap_or_default();
+        for category in item.categories() {
+            if !keywords.iter().any(|keyword| keyword.eq_ignore_ascii_case(category.name())) {
+                keywords.push(category.name().to_string());
+            }
+        }
+        // The artwork of the channel when the episode has none
+        let channel_image = complete.channel.itunes_ext()
+            .and_then(|itunes| itunes.image())
+            .or(complete.channel.image().map(|image| image.url()))
+            .map(|image| image.to_string());
+        let link = item.link()
+            .or(item.enclosure().map(|enclosure| enclosure.url()))
+            .unwrap_or("")
+            .to_string();
+        let guid = item.guid()
+            .map(|guid| guid.value().to_string())
+            .unwrap_or(link.clone());
+        Self{
+            podcast: podcast.name.clone(),
+            title: item.title().unwrap_or("").to_string(),
+            description: from_read(
+                item.description().unwrap_or("").as_bytes(),
+                5000).unwrap_or("".to_string()),
+            link,
+            guid,
+            pub_date: item.pub_date().and_then(util::parse_date),
+            image: itunes
+                .and_then(|itunes| itunes.image())
+                .map(|image| image.to_string())
+                .or(channel_image),
+            enclosure_url: item.enclosure().map(|enclosure| enclosure.url().to_string()),
+            enclosure_type: item.enclosure().map(|enclosure| enclosure.mime_type().to_string()),
+            enclosure_length: item.enclosure().and_then(|enclosure| enclosure.length().parse().ok()),
+            duration: itunes
+                .and_then(|itunes| itunes.duration())
+                .and_then(util::parse_duration),
+            podcast_url: podcast.url.clone(),
+            episode_number: itunes
+                .and_then(|itunes| itunes.episode())
+                .and_then(|number| number.trim().parse().ok()),
+            season_number: itunes
+                .and_then(|itunes| itunes.season())
+                .and_then(|number| number.trim().parse().ok()),
+            keywords,
+            mix: feed.title.clone(),
+            feed_url: feed.self_url("long.xml").unwrap_or_default(),
+            resends: 0,
+        }
+    }
+
+    /// Made up episode, to try the templates before the first real one.
+    pub fn sample() -> Self{
+        Self{
+            podcast: "Atareao con Linux".to_string(),
+            title: "Episode 1. Getting started with podmixer".to_string(),
+            description: "How to mix all your podcasts in a single feed and announce every new episode.".to_string(),
+            link: "https://atareao.es/podcast/getting-started-with-podmixer".to_string(),
+            guid: "sample".to_string(),
+            pub_date: Some(Utc::now()),
+            image: None,
+            enclosure_url: Some("https://atareao.es/podcast/episode-1.mp3".to_string()),
+            enclosure_type: Some("audio/mpeg".to_string()),
+            enclosure_length: Some(25_000_000),
+            duration: Some(1800),
+            podcast_url: "https://atareao.es/podcast/feed.xml".to_string(),
+            episode_number: Some(1),
+            season_number: Some(1),
+            keywords: vec!["Linux".to_string(), "open source".to_string()],
+            mix: "Podmixer".to_string(),
+            feed_url: "https://podmixer.es/rss/long.xml".to_string(),
+            resends: 0,
+        }
+    }
+
+    /// Context used to render the announcement templates.
+    pub fn context(&self) -> Value{
+        context!(
+            title => self.title,
+            description => self.description,
+            link => self.link,
+            podcast => self.podcast,
+            podcast_url => self.podcast_url,
+            image => self.image,
+            enclosure_url => self.enclosure_url,
+            enclosure_length => self.enclosure_length,
+            enclosure_type => self.enclosure_type,
+            duration => self.duration,
+            episode_number => self.episode_number,
+            season_number => self.season_number,
+            pub_date => self.pub_date.map(|pub_date| pub_date.to_rfc3339()),
+            keywords => self.keywords,
+            mix => self.mix,
+            feed_url => self.feed_url,
+        )
+    }
+}
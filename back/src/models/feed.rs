@@ -1,9 +1,11 @@
 use serde::{Deserialize, Serialize};
 use rss::{
+    Channel,
     ChannelBuilder,
     ImageBuilder,
     CategoryBuilder,
     Item,
+    extension::atom::{AtomExtensionBuilder, Link},
     extension::itunes::{
         ITunesChannelExtensionBuilder,
         ITunesCategoryBuilder,
@@ -11,8 +13,11 @@ use rss::{
     },
 };
 use std::collections::BTreeMap;
-use super::Error;
-use tracing::debug;
+use super::{Error, CompletePodcast, Podcast, category, hub, webhook};
+use chrono::DateTime;
+use tracing::{debug, error};
+use regex::Regex;
+use reqwest::Url;
 use sqlx::sqlite::SqlitePool;
 use crate::models::Param;
 
@@ -24,6 +29,8 @@ pub struct Feed{
     pub link: String,
     pub image_url: String,
     pub category: String,
+    #[serde(default)]
+    pub subcategory: String,
     pub rating: String,
     pub description: String,
     pub author: String,
@@ -31,14 +38,45 @@ pub struct Feed{
     pub keywords: String,
     pub owner_name: String,
     pub owner_email: String,
+    #[serde(default = "default_language")]
+    pub language: String,
+    #[serde(default = "default_itunes_type")]
+    pub itunes_type: String,
+    #[serde(default)]
+    pub copyright: String,
+    #[serde(default)]
+    pub block: bool,
+    #[serde(default)]
+    pub complete: bool,
+    #[serde(default)]
+    pub public_url: String,
+    #[serde(default)]
+    pub hub_url: String,
+    #[serde(default = "default_page_size")]
+    pub page_size: usize,
+}
+
+fn default_language() -> String{
+    "es".to_string()
+}
+
+fn default_itunes_type() -> String{
+    "episodic".to_string()
+}
+
+fn default_page_size() -> usize{
+    100
 }
 
 impl Feed {
     #[allow(clippy::too_many_arguments)]
     pub fn new(title: String, subtitle: String, summary: String, link: String,
-               image_url: String, category: String, rating: String,
-               description: String, author: String, explicit: bool,
-               keywords: String, owner_name: String, owner_email: String) -> Self{
+               image_url: String, category: String, subcategory: String,
+               rating: String, description: String, author: String,
+               explicit: bool, keywords: String, owner_name: String,
+               owner_email: String, language: String, itunes_type: String,
+               copyright: String, block: bool, complete: bool,
+               public_url: String, hub_url: String, page_size: usize) -> Self{
         Self{
             title,
             subtitle,
@@ -46,6 +84,7 @@ impl Feed {
             link,
             image_url,
             category,
+            subcategory,
             rating,
             description,
             author,
@@ -53,6 +92,14 @@ impl Feed {
             keywords,
             owner_name,
             owner_email,
+            language,
+            itunes_type,
+            copyright,
+            block,
+            complete,
+            public_url,
+            hub_url,
+            page_size,
         }
     }
 
@@ -64,6 +111,7 @@ impl Feed {
         let feed_link = Param::get(pool, "feed_link").await?;
         let feed_image_url = Param::get(pool, "feed_image_url").await?;
         let feed_category = Param::get(pool, "feed_category").await?;
+        let feed_subcategory = Param::get(pool, "feed_subcategory").await?;
         let feed_rating = Param::get(pool, "feed_rating").await?;
         let feed_description = Param::get(pool, "feed_description").await?;
         let feed_author = Param::get(pool, "feed_author").await?;
@@ -72,20 +120,54 @@ impl Feed {
         let feed_keywords = Param::get(pool, "feed_keywords").await?;
         let feed_owner_name = Param::get(pool, "feed_owner_name").await?;
         let feed_owner_email = Param::get(pool, "feed_owner_email").await?;
+        let feed_language = Param::get(pool, "feed_language").await?;
+        let feed_itunes_type = Param::get(pool, "feed_itunes_type").await?;
+        let feed_copyright = Param::get(pool, "feed_copyright").await?;
+        let feed_block = Param::get(pool, "feed_block").await? == "TRUE";
+        let feed_complete = Param::get(pool, "feed_complete").await? == "TRUE";
+        let feed_public_url = Param::get(pool, "feed_public_url").await?;
+        let feed_hub_url = Param::get(pool, "feed_hub_url").await?;
+        let feed_page_size = Param::get(pool, "feed_page_size").await?.parse()?;
         Ok(Feed::new(feed_title, feed_subtitle, feed_summary, feed_link,
-            feed_image_url, feed_category, feed_rating, feed_description,
-            feed_author, feed_explicit, feed_keywords, feed_owner_name,
-            feed_owner_email))
+            feed_image_url, feed_category, feed_subcategory, feed_rating,
+            feed_description, feed_author, feed_explicit, feed_keywords,
+            feed_owner_name, feed_owner_email, feed_language,
+            feed_itunes_type, feed_copyright, feed_block, feed_complete,
+            feed_public_url, feed_hub_url, feed_page_size))
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if !self.category.is_empty() && !category::is_valid_category(&self.category) {
+            return Err(format!("Invalid category: {}", self.category).into());
+        }
+        if !self.subcategory.is_empty() && !category::is_valid_subcategory(&self.category, &self.subcategory) {
+            return Err(format!("Invalid subcategory for {}: {}", self.category, self.subcategory).into());
+        }
+        let re = Regex::new(r"^[a-zA-Z]{2,3}(-[a-zA-Z0-9]{2,8})*$")?;
+        if !re.is_match(&self.language) {
+            return Err(format!("Invalid language: {}", self.language).into());
+        }
+        if self.itunes_type != "episodic" && self.itunes_type != "serial" {
+            return Err(format!("Invalid itunes type: {}", self.itunes_type).into());
+        }
+        for url in [&self.public_url, &self.hub_url] {
+            if !url.is_empty() && Url::parse(url).is_err() {
+                return Err(format!("Invalid url: {url}").into());
+            }
+        }
+        Ok(())
     }
 
     pub async fn set(pool: &SqlitePool, feed: &Feed) -> Result<Feed, Error> {
         debug!("save_feed");
+        feed.validate()?;
         Param::set(pool, "feed_title", &feed.title).await?;
         Param::set(pool, "feed_subtitle", &feed.subtitle).await?;
         Param::set(pool, "feed_summary", &feed.summary).await?;
         Param::set(pool, "feed_link", &feed.link).await?;
         Param::set(pool, "feed_image_url", &feed.image_url).await?;
         Param::set(pool, "feed_category", &feed.category).await?;
+        Param::set(pool, "feed_subcategory", &feed.subcategory).await?;
         Param::set(pool, "feed_rating", &feed.rating).await?;
         Param::set(pool, "feed_description", &feed.description).await?;
         Param::set(pool, "feed_author", &feed.author).await?;
@@ -93,10 +175,101 @@ impl Feed {
         Param::set(pool, "feed_keywords", &feed.keywords).await?;
         Param::set(pool, "feed_owner_name", &feed.owner_name).await?;
         Param::set(pool, "feed_owner_email", &feed.owner_email).await?;
+        Param::set(pool, "feed_language", &feed.language).await?;
+        Param::set(pool, "feed_itunes_type", &feed.itunes_type).await?;
+        Param::set(pool, "feed_copyright", &feed.copyright).await?;
+        Param::set(pool, "feed_block", &feed.block.to_string().to_uppercase()).await?;
+        Param::set(pool, "feed_complete", &feed.complete.to_string().to_uppercase()).await?;
+        Param::set(pool, "feed_public_url", feed.public_url.trim_end_matches('/')).await?;
+        Param::set(pool, "feed_hub_url", &feed.hub_url).await?;
+        Param::set(pool, "feed_page_size", &feed.page_size.to_string()).await?;
         Self::get(pool).await
     }
 
-    pub fn rss(&self, episodes: Vec<Item>) -> Result<String, Error>{
+    /// Public url of the generated feed `name`, used as the WebSub topic.
+    pub fn self_url(&self, name: &str) -> Option<String>{
+        if self.public_url.is_empty() {
+            None
+        } else {
+            Some(format!("{}/rss/{name}", self.public_url.trim_end_matches('/')))
+        }
+    }
+
+    /// Configured WebSub hub or, when empty, the built-in one.
+    pub fn hub(&self) -> Option<String>{
+        if !self.hub_url.is_empty() {
+            Some(self.hub_url.clone())
+        } else if !self.public_url.is_empty() {
+            Some(format!("{}/api/v1/hub", self.public_url.trim_end_matches('/')))
+        } else {
+            None
+        }
+    }
+
+    pub fn rss(&self, episodes: Vec<Item>, name: &str) -> Result<String, Error>{
+        Ok(self.channel(episodes, name).to_string())
+    }
+
+    /// Splits the feed `name` in pages of `page_size` episodes linked as a
+    /// RFC 5005 paged feed. The first page is `name` itself and the rest
+    /// are served as `name?page=n`.
+    pub fn rss_pages(&self, episodes: Vec<Item>, name: &str) -> Result<Vec<String>, Error>{
+        if self.page_size == 0 || episodes.len() <= self.page_size {
+            return Ok(vec![self.rss(episodes, name)?]);
+        }
+        let base = match self.self_url(name) {
+            Some(self_url) => self_url,
+            None => name.to_string(),
+        };
+        let page_url = |page: usize| if page == 1 {
+            base.clone()
+        } else {
+            format!("{base}?page={page}")
+        };
+        let chunks: Vec<Vec<Item>> = episodes.chunks(self.page_size)
+            .map(|chunk| chunk.to_vec())
+            .collect();
+        let last = chunks.len();
+        let mut pages = Vec::new();
+        for (index, chunk) in chunks.into_iter().enumerate() {
+            let page = index + 1;
+            let mut channel = self.channel(chunk, name);
+            let mut links: Vec<Link> = channel.atom_ext()
+                .map(|atom| atom.links().to_vec())
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|link| link.rel() != "self")
+                .collect();
+            links.push(atom_link("self", &page_url(page), Some("application/rss+xml")));
+            links.push(atom_link("first", &page_url(1), Some("application/rss+xml")));
+            links.push(atom_link("last", &page_url(last), Some("application/rss+xml")));
+            if page > 1 {
+                links.push(atom_link("prev", &page_url(page - 1), Some("application/rss+xml")));
+            }
+            if page < last {
+                links.push(atom_link("next", &page_url(page + 1), Some("application/rss+xml")));
+            }
+            channel.set_atom_ext(AtomExtensionBuilder::default().links(links).build());
+            pages.push(channel.to_string());
+        }
+        Ok(pages)
+    }
+
+    /// Writes the paged feed `name` in the rss directory, removing the
+    /// pages left over from a previous and longer version.
+    pub fn write_pages(&self, episodes: Vec<Item>, name: &str) -> Result<usize, Error>{
+        let pages = self.rss_pages(episodes, name)?;
+        for (index, page) in pages.iter().enumerate() {
+            std::fs::write(page_path(name, index + 1), page.as_bytes())?;
+        }
+        let mut page = pages.len() + 1;
+        while std::fs::remove_file(page_path(name, page)).is_ok() {
+            page += 1;
+        }
+        Ok(pages.len())
+    }
+
+    pub fn channel(&self, episodes: Vec<Item>, name: &str) -> Channel{
         let image = ImageBuilder::default()
             .url(&self.image_url)
             .title(self.title.clone())
@@ -105,8 +278,16 @@ impl Feed {
         let category = CategoryBuilder::default()
             .name(&self.category)
             .build();
+        let itunes_subcategory = if self.subcategory.is_empty() {
+            None
+        } else {
+            Some(Box::new(ITunesCategoryBuilder::default()
+                .text(&self.subcategory)
+                .build()))
+        };
         let itunes_category = ITunesCategoryBuilder::default()
             .text(&self.category)
+            .subcategory(itunes_subcategory)
             .build();
         let itunes_owner = ITunesOwnerBuilder::default()
             .name(self.owner_name.clone())
@@ -116,10 +297,14 @@ impl Feed {
             .subtitle(Some(self.subtitle.clone()))
             .summary(Some(self.summary.clone()))
             .category(itunes_category)
+            .image(Some(self.image_url.clone()))
             .keywords(Some(self.keywords.clone()))
-            .explicit(Some(if self.explicit { "yes".to_string() } else { "no".to_string() }))
+            .explicit(Some(self.explicit.to_string()))
             .author(Some(self.author.clone()))
             .owner(Some(itunes_owner))
+            .r#type(Some(self.itunes_type.clone()))
+            .block(if self.block { Some("Yes".to_string()) } else { None })
+            .complete(if self.complete { Some("Yes".to_string()) } else { None })
             .build();
         let mut channel = ChannelBuilder::default()
             .title(&self.title)
@@ -127,7 +312,8 @@ impl Feed {
             .image(Some(image))
             .category(category)
             .rating(Some(self.rating.clone()))
-            .language(Some("es".to_string()))
+            .language(Some(self.language.clone()))
+            .copyright(if self.copyright.is_empty() { None } else { Some(self.copyright.clone()) })
             .generator(Some("Podmixer".to_string()))
             .description(self.description.clone())
             .build();
@@ -145,9 +331,166 @@ impl Feed {
         namespaces.insert("podcast".to_string(), "https://podcastindex.org/namespace/1.0".to_string());
         channel.namespaces = namespaces;
         channel.set_itunes_ext(itunes);
+        if let Some(self_url) = self.self_url(name) {
+            let mut links = vec![atom_link("self", &self_url, Some("application/rss+xml"))];
+            if let Some(hub) = self.hub() {
+                links.push(atom_link("hub", &hub, None));
+            }
+            channel.set_atom_ext(AtomExtensionBuilder::default().links(links).build());
+        }
         channel.set_items(episodes);
-        channel.pretty_write_to(std::io::sink(), b' ', 4)?;
-        Ok(channel.to_string())
+        channel
     }
 }
 
+/// File of the page `page` of the feed `name`: `rss/long.xml` for the first
+/// one and `rss/long-2.xml` and so on for the rest.
+pub fn page_path(name: &str, page: usize) -> String{
+    if page <= 1 {
+        return format!("rss/{name}");
+    }
+    match name.rsplit_once('.') {
+        Some((stem, extension)) => format!("rss/{stem}-{page}.{extension}"),
+        None => format!("rss/{name}-{page}"),
+    }
+}
+
+fn atom_link(rel: &str, href: &str, mime_type: Option<&str>) -> Link{
+    let mut link = Link::default();
+    link.set_rel(rel);
+    link.set_href(href);
+    link.set_mime_type(mime_type.map(|mime_type| mime_type.to_string()));
+    link
+}
+
+/// Fetches every source podcast and writes the short and the long feeds,
+/// as the worker does when there are new episodes.
+pub async fn regenerate(pool: &SqlitePool, older_than: i32) -> Result<(), Error>{
+    debug!("Regenerate feed");
+    let podcasts = Podcast::get(pool).await?;
+    let mut all_episodes: Vec<Item> = Vec::new();
+    let mut older_than_episodes: Vec<Item> = Vec::new();
+    for podcast in podcasts.iter() {
+        match CompletePodcast::new(podcast).await {
+            Ok(complete) => {
+                match complete.get_older_than_days(older_than) {
+                    Ok(older) => older_than_episodes.extend(older),
+                    Err(e) => error!("Error collecting older episodes: {}", e),
+                };
+                all_episodes.extend(complete.get_all());
+            },
+            Err(e) => error!("Error creating CompletePodcast: {}", e),
+        }
+    }
+    let feed = Feed::get(pool).await?;
+    write(pool, &feed, all_episodes, older_than_episodes).await
+}
+
+/// Writes the short feed with the older episodes and the long one with all
+/// of them, and tells the hub and the webhooks.
+pub async fn write(pool: &SqlitePool, feed: &Feed, mut all_episodes: Vec<Item>, mut older_than_episodes: Vec<Item>) -> Result<(), Error>{
+    all_episodes.sort_by(item_comparator);
+    older_than_episodes.sort_by(item_comparator);
+    debug!("Making short feed");
+    let short_feed = feed.rss(older_than_episodes, "short.xml")?;
+    std::fs::write("rss/short.xml", short_feed.as_bytes())?;
+    if let Err(e) = hub::publish(pool, feed, "short.xml").await {
+        error!("Error publishing short feed to hub: {e}");
+    }
+    debug!("Making long feed");
+    let pages = feed.write_pages(all_episodes, "long.xml")?;
+    debug!("Long feed written successfully in {pages} pages");
+    if let Err(e) = hub::publish(pool, feed, "long.xml").await {
+        error!("Error publishing long feed to hub: {e}");
+    }
+    if let Err(e) = webhook::notify(pool, webhook::FEED_REGENERATED, serde_json::json!({"mix": webhook::mix(feed)})).await {
+        error!("Error notifying webhooks: {e}");
+    }
+    Ok(())
+}
+
+pub fn get_pub_date_timestamp(item: &Item) -> i64{
+    let Some(pub_date) = item.pub_date() else {
+        return 0;
+    };
+    if let Ok(pub_date) = DateTime::parse_from_rfc2822(pub_date){
+        pub_date.timestamp()
+    }else if let Ok(pub_date) = DateTime::parse_from_str(pub_date, "%a, %d %b %Y %H:%M:%S") {
+        pub_date.timestamp()
+    }else {
+        0
+    }
+}
+
+/// Newest first.
+pub fn item_comparator(a: &Item, b: &Item) -> std::cmp::Ordering {
+    let date_a = get_pub_date_timestamp(a);
+    let date_b = get_pub_date_timestamp(b);
+    date_b.cmp(&date_a)
+}
+
+#[cfg(test)]
+mod test{
+    use super::{Feed, page_path};
+    use rss::{Item, ItemBuilder};
+
+    fn sample() -> Feed{
+        Feed::new("Mix".to_string(), "".to_string(), "".to_string(),
+            "https://example.com".to_string(),
+            "https://example.com/cover.png".to_string(),
+            "News".to_string(), "Tech News".to_string(), "".to_string(),
+            "Description".to_string(), "Author".to_string(), false,
+            "".to_string(), "Owner".to_string(), "owner@example.com".to_string(),
+            "en-US".to_string(), "serial".to_string(),
+            "CC BY 4.0".to_string(), true, false,
+            "https://mix.example.com/".to_string(), "".to_string(), 2)
+    }
+
+    #[test]
+    fn test_validate(){
+        let mut feed = sample();
+        assert!(feed.validate().is_ok());
+        feed.subcategory = "Podcasting".to_string();
+        assert!(feed.validate().is_err());
+        feed.subcategory = "".to_string();
+        feed.language = "english".to_string();
+        assert!(feed.validate().is_err());
+        feed.language = "en".to_string();
+        feed.itunes_type = "daily".to_string();
+        assert!(feed.validate().is_err());
+    }
+
+    #[test]
+    fn test_rss_pages(){
+        let episodes: Vec<Item> = (0..5).map(|index| ItemBuilder::default()
+            .title(Some(format!("Episode {index}")))
+            .build())
+            .collect();
+        let pages = sample().rss_pages(episodes, "long.xml").unwrap();
+        assert_eq!(pages.len(), 3);
+        assert!(pages[0].contains("href=\"https://mix.example.com/rss/long.xml\" rel=\"self\""));
+        assert!(pages[0].contains("href=\"https://mix.example.com/rss/long.xml?page=2\" rel=\"next\""));
+        assert!(!pages[0].contains("rel=\"prev\""));
+        assert!(pages[1].contains("href=\"https://mix.example.com/rss/long.xml?page=2\" rel=\"self\""));
+        assert!(pages[1].contains("href=\"https://mix.example.com/rss/long.xml\" rel=\"prev\""));
+        assert!(pages[2].contains("href=\"https://mix.example.com/rss/long.xml?page=3\" rel=\"last\""));
+        assert!(!pages[2].contains("rel=\"next\""));
+        assert!(pages[2].contains("<title>Episode 4</title>"));
+        assert_eq!(page_path("long.xml", 1), "rss/long.xml");
+        assert_eq!(page_path("long.xml", 3), "rss/long-3.xml");
+    }
+
+    #[test]
+    fn test_rss(){
+        let rss = sample().rss(Vec::new(), "short.xml").unwrap();
+        assert!(rss.contains("<language>en-US</language>"));
+        assert!(rss.contains("<copyright>CC BY 4.0</copyright>"));
+        assert!(rss.contains("<itunes:category text=\"News\"><itunes:category text=\"Tech News\"></itunes:category></itunes:category>"));
+        assert!(rss.contains("<itunes:type>serial</itunes:type>"));
+        assert!(rss.contains("<itunes:block>Yes</itunes:block>"));
+        assert!(!rss.contains("<itunes:complete>"));
+        assert!(rss.contains("<itunes:image href=\"https://example.com/cover.png\"/>"));
+        assert!(rss.contains("<atom:link href=\"https://mix.example.com/rss/short.xml\" rel=\"self\" type=\"application/rss+xml\"/>"));
+        assert!(rss.contains("<atom:link href=\"https://mix.example.com/api/v1/hub\" rel=\"hub\"/>"));
+    }
+}
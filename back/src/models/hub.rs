@@ -0,0 +1,164 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{sqlite::{SqlitePool, SqliteRow}, query, Row};
+use chrono::{DateTime, Utc, Duration};
+use reqwest::{Client, Url, header};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tracing::{debug, error, info};
+use super::{Error, Feed, util};
+
+pub const DEFAULT_LEASE_SECONDS: i64 = 10 * 24 * 60 * 60;
+pub const MAX_LEASE_SECONDS: i64 = 30 * 24 * 60 * 60;
+
+/// A subscriber of the built-in WebSub hub.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Subscriber{
+    pub id: i64,
+    pub topic: String,
+    pub callback: String,
+    #[serde(skip_serializing)]
+    pub secret: String,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Subscriber{
+    fn from_row(row: SqliteRow) -> Self{
+        Self{
+            id: row.get("id"),
+            topic: row.get("topic"),
+            callback: row.get("callback"),
+            secret: row.get("secret"),
+            expires_at: row.get("expires_at"),
+            created_at: row.get("created_at"),
+            updated_at: row.get("updated_at"),
+        }
+    }
+
+    pub async fn get_by_topic(pool: &SqlitePool, topic: &str) -> Result<Vec<Subscriber>, sqlx::error::Error>{
+        let sql = "SELECT * FROM hub_subscribers WHERE topic = $1 AND expires_at > $2";
+        query(sql)
+            .bind(topic)
+            .bind(Utc::now())
+            .map(Self::from_row)
+            .fetch_all(pool)
+            .await
+    }
+
+    pub async fn create_or_update(pool: &SqlitePool, topic: &str, callback: &str, secret: &str, lease_seconds: i64) -> Result<Subscriber, sqlx::error::Error>{
+        let current_ts = Utc::now();
+        let sql = "INSERT INTO hub_subscribers (topic, callback, secret, expires_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT(topic, callback) DO UPDATE SET
+            secret=excluded.secret,
+            expires_at=excluded.expires_at,
+            updated_at=excluded.updated_at
+            RETURNING *";
+        query(sql)
+            .bind(topic)
+            .bind(callback)
+            .bind(secret)
+            .bind(current_ts + Duration::seconds(lease_seconds))
+            .bind(current_ts)
+            .map(Self::from_row)
+            .fetch_one(pool)
+            .await
+    }
+
+    pub async fn delete(pool: &SqlitePool, topic: &str, callback: &str) -> Result<Option<Subscriber>, sqlx::error::Error>{
+        let sql = "DELETE FROM hub_subscribers WHERE topic = $1 AND callback = $2 RETURNING *";
+        query(sql)
+            .bind(topic)
+            .bind(callback)
+            .map(Self::from_row)
+            .fetch_optional(pool)
+            .await
+    }
+
+    /// Confirms the intent of the subscriber by echoing a random challenge.
+    pub async fn verify_intent(callback: &str, mode: &str, topic: &str, lease_seconds: i64) -> Result<bool, Error>{
+        let challenge = util::random_string(32);
+        let mut url = Url::parse(callback)?;
+        url.query_pairs_mut()
+            .append_pair("hub.mode", mode)
+            .append_pair("hub.topic", topic)
+            .append_pair("hub.challenge", &challenge)
+            .append_pair("hub.lease_seconds", &lease_seconds.to_string());
+        let response = Client::new()
+            .get(url)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Ok(false);
+        }
+        Ok(response.text().await?.trim() == challenge)
+    }
+
+    /// Sends the new content of the topic to the subscriber.
+    pub async fn deliver(&self, hub: &str, content: &[u8]) -> Result<(), Error>{
+        debug!("Deliver {} to {}", self.topic, self.callback);
+        let mut request = Client::new()
+            .post(&self.callback)
+            .header(header::CONTENT_TYPE, "application/rss+xml")
+            .header(header::LINK, format!("<{hub}>; rel=\"hub\", <{}>; rel=\"self\"", self.topic));
+        if !self.secret.is_empty() {
+            request = request.header("X-Hub-Signature", format!("sha256={}", sign(&self.secret, content)?));
+        }
+        request.body(content.to_vec())
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// HMAC-SHA256 of `content` as lowercase hex.
+pub fn sign(secret: &str, content: &[u8]) -> Result<String, Error>{
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
+    mac.update(content);
+    Ok(hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Notifies the hub that the generated feed `name` has changed. With an
+/// external hub this is a publish ping, otherwise the built-in hub delivers
+/// the content to its subscribers.
+pub async fn publish(pool: &SqlitePool, feed: &Feed, name: &str) -> Result<(), Error>{
+    let (Some(topic), Some(hub)) = (feed.self_url(name), feed.hub()) else {
+        debug!("No public url, nothing to publish");
+        return Ok(());
+    };
+    if !feed.hub_url.is_empty() {
+        info!("Publish {topic} to {hub}");
+        Client::new()
+            .post(&hub)
+            .form(&[("hub.mode", "publish"), ("hub.url", &topic)])
+            .send()
+            .await?
+            .error_for_status()?;
+        return Ok(());
+    }
+    let subscribers = Subscriber::get_by_topic(pool, &topic).await?;
+    if subscribers.is_empty() {
+        return Ok(());
+    }
+    let content = tokio::fs::read(format!("rss/{name}")).await?;
+    info!("Deliver {topic} to {} subscribers", subscribers.len());
+    for subscriber in subscribers {
+        if let Err(e) = subscriber.deliver(&hub, &content).await {
+            error!("Can not deliver {topic} to {}: {e}", subscriber.callback);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test{
+    use super::sign;
+
+    #[test]
+    fn test_sign(){
+        let signature = sign("key", b"The quick brown fox jumps over the lazy dog").unwrap();
+        assert_eq!(signature, "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8");
+    }
+}
@@ -0,0 +1,370 @@
+use async_trait::async_trait;
+use reqwest::{Client, multipart};
+use serde::{Serialize, Deserialize};
+use serde_json::{Value, json};
+use std::time::Duration;
+use tracing::{debug, error};
+use super::{Error, Episode, Publisher, Receipt, util};
+use sqlx::sqlite::SqlitePool;
+use crate::models::Param;
+
+// Mastodon counts every url as 23 characters
+const URL_LENGTH: usize = 23;
+const MEDIA_RETRIES: u32 = 10;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Mastodon{
+    pub active: bool,
+    pub url: String,
+    pub token: String,
+    #[serde(default = "default_visibility")]
+    pub visibility: String,
+    #[serde(default)]
+    pub language: String,
+    #[serde(default)]
+    pub spoiler_text: String,
+    #[serde(default = "default_max_characters")]
+    pub max_characters: usize,
+    #[serde(default)]
+    pub upload_audio: bool,
+    pub template: String,
+}
+
+fn default_visibility() -> String{
+    "public".to_string()
+}
+
+fn default_max_characters() -> usize{
+    500
+}
+
+impl Mastodon{
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(active: bool, url: String, token: String, visibility: String,
+               language: String, spoiler_text: String, max_characters: usize,
+               upload_audio: bool, template: String) -> Self{
+        Self{
+            active,
+            url,
+            token,
+            visibility,
+            language,
+            spoiler_text,
+            max_characters,
+            upload_audio,
+            template,
+        }
+    }
+
+    pub async fn get(pool: &SqlitePool) -> Result<Mastodon, Error> {
+        debug!("get_mastodon");
+        let active = Param::get(pool, "mastodon_active").await? == "TRUE";
+        let url = Param::get(pool, "mastodon_url").await?;
+        let token = Param::get(pool, "mastodon_token").await?;
+        let visibility = Param::get(pool, "mastodon_visibility").await?;
+        let language = Param::get(pool, "mastodon_language").await?;
+        let spoiler_text = Param::get(pool, "mastodon_spoiler_text").await?;
+        let max_characters = Param::get(pool, "mastodon_max_characters").await?.parse()?;
+        let upload_audio = Param::get(pool, "mastodon_upload_audio").await? == "TRUE";
+        let template = Param::get(pool, "mastodon_template").await?;
+        Ok(Mastodon::new(active, url, token, visibility, language,
+            spoiler_text, max_characters, upload_audio, template))
+    }
+
+    pub async fn set(pool: &SqlitePool, mastodon: &Mastodon) -> Result<Mastodon, Error> {
+        debug!("save_mastodon, {:?}", mastodon);
+        Param::set(pool, "mastodon_active", &mastodon.active.to_string().to_uppercase()).await?;
+        Param::set(pool, "mastodon_url", mastodon.url.trim_end_matches('/')).await?;
+        Param::set(pool, "mastodon_token", &mastodon.token).await?;
+        Param::set(pool, "mastodon_visibility", &mastodon.visibility).await?;
+        Param::set(pool, "mastodon_language", &mastodon.language).await?;
+        Param::set(pool, "mastodon_spoiler_text", &mastodon.spoiler_text).await?;
+        Param::set(pool, "mastodon_max_characters", &mastodon.max_characters.to_string()).await?;
+        Param::set(pool, "mastodon_upload_audio", &mastodon.upload_audio.to_string().to_uppercase()).await?;
+        Param::set(pool, "mastodon_template", &mastodon.template).await?;
+        Self::get(pool).await
+    }
+
+    fn endpoint(&self, path: &str) -> String{
+        format!("{}{path}", self.url.trim_end_matches('/'))
+    }
+
+    /// Fits the status, including the content warning, in the instance limit.
+    pub fn fit(&self, message: &str) -> String{
+        let max = self.max_characters.saturating_sub(self.spoiler_text.chars().count());
+        util::truncate_weighted(message, max, URL_LENGTH)
+    }
+
+    pub async fn verify_credentials(&self) -> Result<Value, Error>{
+        Ok(Client::new()
+            .get(self.endpoint("/api/v1/accounts/verify_credentials"))
+            .bearer_auth(&self.token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// Uploads the file and waits until the instance has processed it.
+    pub async fn upload_media(&self, filename: &str, filepath: &str) -> Result<String, Error>{
+        debug!("upload_media");
+        let form = multipart::Form::new()
+            .part("file", util::file_part(filepath, filename).await?);
+        let client = Client::new();
+        let mut media: Value = client
+            .post(self.endpoint("/api/v2/media"))
+            .bearer_auth(&self.token)
+            .multipart(form)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let id = media.get("id")
+            .and_then(|id| id.as_str())
+            .ok_or("Media without id")?
+            .to_string();
+        let mut retries = 0;
+        while media.get("url").is_none_or(|url| url.is_null()) {
+            if retries == MEDIA_RETRIES {
+                return Err(format!("Media {id} not processed").into());
+            }
+            retries += 1;
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            media = client
+                .get(self.endpoint(&format!("/api/v1/media/{id}")))
+                .bearer_auth(&self.token)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+        }
+        Ok(id)
+    }
+
+    fn status(&self, message: &str, media_ids: &[String]) -> Value{
+        let mut status = json!({
+            "status": self.fit(message),
+            "media_ids": media_ids,
+        });
+        if !self.language.is_empty() {
+            status["language"] = json!(self.language);
+        }
+        if !self.spoiler_text.is_empty() {
+            status["spoiler_text"] = json!(self.spoiler_text);
+        }
+        status
+    }
+
+    pub async fn post(&self, message: &str, media_ids: &[String], idempotency_key: &str) -> Result<Value, Error>{
+        debug!("post: {message}");
+        let mut status = self.status(message, media_ids);
+        status["visibility"] = json!(self.visibility);
+        Ok(Client::new()
+            .post(self.endpoint("/api/v1/statuses"))
+            .bearer_auth(&self.token)
+            .header("Idempotency-Key", idempotency_key)
+            .json(&status)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// Replaces the text, keeping the attached media.
+    pub async fn edit_status(&self, id: &str, message: &str) -> Result<Value, Error>{
+        debug!("edit_status: {id}");
+        let client = Client::new();
+        let current: Value = client
+            .get(self.endpoint(&format!("/api/v1/statuses/{id}")))
+            .bearer_auth(&self.token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let media_ids: Vec<String> = current.get("media_attachments")
+            .and_then(|media| media.as_array())
+            .map(|media| media.iter()
+                .filter_map(|attachment| attachment.get("id")?.as_str().map(String::from))
+                .collect())
+            .unwrap_or_default();
+        Ok(client
+            .put(self.endpoint(&format!("/api/v1/statuses/{id}")))
+            .bearer_auth(&self.token)
+            .json(&self.status(message, &media_ids))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    pub async fn delete_status(&self, id: &str) -> Result<Value, Error>{
+        debug!("delete_status: {id}");
+        Ok(Client::new()
+            .delete(self.endpoint(&format!("/api/v1/statuses/{id}")))
+            .bearer_auth(&self.token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    async fn upload_audio(&self, episode: &Episode) -> Result<String, Error>{
+        let url = episode.enclosure_url.as_deref().ok_or("Not enclosure")?;
+        let name = util::normalize(&episode.title)?;
+        let ext = util::get_extension_from_filename(url).ok_or("Not extension")?;
+        let filename = format!("{name}.{ext}");
+        let file = util::TempFile::new(&format!("mastodon_{filename}"));
+        util::fetch_url(url, file.path()).await?;
+        self.upload_media(&filename, file.path()).await
+    }
+}
+
+#[async_trait]
+impl Publisher for Mastodon{
+    fn name(&self) -> &'static str{
+        "mastodon"
+    }
+
+    fn is_active(&self) -> bool{
+        self.active
+    }
+
+    fn template(&self) -> &str{
+        &self.template
+    }
+
+    fn limit(&self) -> Option<usize>{
+        Some(self.max_characters.saturating_sub(self.spoiler_text.chars().count()))
+    }
+
+    fn length(&self, message: &str) -> usize{
+        util::weighted_length(message, URL_LENGTH)
+    }
+
+    fn validate(&self) -> Result<(), Error>{
+        if self.url.is_empty() {
+            return Err("Mastodon url is empty".into());
+        }
+        if self.token.is_empty() {
+            return Err("Mastodon token is empty".into());
+        }
+        if !["public", "unlisted", "private", "direct"].contains(&self.visibility.as_str()) {
+            return Err(format!("Invalid Mastodon visibility: {}", self.visibility).into());
+        }
+        Ok(())
+    }
+
+    async fn publish(&self, message: &str, episode: &Episode) -> Result<Receipt, Error>{
+        let mut media_ids = Vec::new();
+        if self.upload_audio {
+            // Better announce it without audio than not announce it at all
+            match self.upload_audio(episode).await {
+                Ok(id) => media_ids.push(id),
+                Err(e) => error!("Could NOT upload audio to Mastodon: {e}"),
+            }
+        }
+        // The same key for the retries, so Mastodon drops the duplicates, but
+        // a new one for every resend, so it is really published again
+        let idempotency_key = match episode.resends {
+            0 => episode.guid.clone(),
+            resends => format!("{}-{resends}", episode.guid),
+        };
+        let status = self.post(message, &media_ids, &idempotency_key).await?;
+        Ok(Receipt{
+            remote_id: status.get("id")
+                .and_then(|id| id.as_str())
+                .ok_or("Status without id")?
+                .to_string(),
+            remote_url: status.get("url").and_then(|url| url.as_str()).map(String::from),
+        })
+    }
+
+    async fn edit(&self, receipt: &Receipt, message: &str, _episode: &Episode) -> Result<(), Error>{
+        self.edit_status(&receipt.remote_id, message).await.map(|_| ())
+    }
+
+    async fn delete(&self, receipt: &Receipt) -> Result<(), Error>{
+        self.delete_status(&receipt.remote_id).await.map(|_| ())
+    }
+
+    async fn test_connection(&self) -> Result<(), Error>{
+        self.verify_credentials().await.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::Mastodon;
+    use crate::models::{Episode, Publisher, mock};
+    use axum::{Json, Router, routing, extract::State, http::{HeaderMap, StatusCode}};
+    use serde_json::{Value, json};
+    use std::sync::{Arc, Mutex};
+
+    type Statuses = Arc<Mutex<Vec<(String, Value)>>>;
+
+    async fn server() -> (String, Statuses) {
+        let statuses: Statuses = Arc::new(Mutex::new(Vec::new()));
+        let router = Router::new()
+            .route("/audio.mp3", routing::get(|| async { vec![0u8; 16] }))
+            .route("/api/v2/media", routing::post(|| async {
+                (StatusCode::ACCEPTED, Json(json!({"id": "10", "url": null})))
+            }))
+            .route("/api/v1/media/10", routing::get(|| async {
+                Json(json!({"id": "10", "url": "https://files.example.com/10.mp3"}))
+            }))
+            .route("/api/v1/statuses", routing::post(
+                |State(statuses): State<Statuses>, headers: HeaderMap, Json(status): Json<Value>| async move {
+                    let key = headers.get("Idempotency-Key").unwrap().to_str().unwrap().to_string();
+                    statuses.lock().unwrap().push((key, status));
+                    Json(json!({"id": "1", "url": "https://mastodon.example.com/@podmixer/1"}))
+                }))
+            .with_state(statuses.clone());
+        (mock::serve(router).await, statuses)
+    }
+
+    fn episode(url: &str) -> Episode {
+        Episode{
+            podcast: "Podcast".to_string(),
+            title: "Episode 1".to_string(),
+            description: "Description".to_string(),
+            link: "https://example.com/1".to_string(),
+            guid: "guid-1".to_string(),
+            enclosure_url: Some(format!("{url}/audio.mp3")),
+            enclosure_type: Some("audio/mpeg".to_string()),
+            enclosure_length: Some(16),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn mastodon_publish(){
+        let (url, statuses) = server().await;
+        let mastodon = Mastodon::new(true, url.clone(), "token".to_string(),
+            "unlisted".to_string(), "es".to_string(), "podcast".to_string(),
+            30, true, "".to_string());
+        assert!(mastodon.validate().is_ok());
+        let message = "Nuevo episodio de mi podcast favorito https://example.com/1";
+        let receipt = mastodon.publish(message, &episode(&url)).await.unwrap();
+        assert_eq!(receipt.remote_id, "1");
+        assert_eq!(receipt.remote_url.as_deref(), Some("https://mastodon.example.com/@podmixer/1"));
+        let resent = Episode{resends: 2, ..episode(&url)};
+        mastodon.publish(message, &resent).await.unwrap();
+        let statuses = statuses.lock().unwrap();
+        assert_eq!(statuses.len(), 2);
+        assert_eq!(statuses[1].0, "guid-1-2");
+        let (key, status) = &statuses[0];
+        assert_eq!(key, "guid-1");
+        assert_eq!(status["status"], "Nuevo episodio de mi…");
+        assert_eq!(status["visibility"], "unlisted");
+        assert_eq!(status["language"], "es");
+        assert_eq!(status["spoiler_text"], "podcast");
+        assert_eq!(status["media_ids"], json!(["10"]));
+    }
+}
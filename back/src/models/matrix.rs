@@ -0,0 +1,362 @@
+use async_trait::async_trait;
+use html2text::from_read;
+use reqwest::{Client, Url, header};
+use serde::{Serialize, Deserialize};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use tokio_util::io::ReaderStream;
+use tracing::{debug, error};
+use super::{Error, Episode, Publisher, Receipt, util};
+use sqlx::sqlite::SqlitePool;
+use crate::models::Param;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Matrix{
+    pub active: bool,
+    pub url: String,
+    pub token: String,
+    /// Room ids (`!id:server`) or aliases (`#alias:server`)
+    pub rooms: Vec<String>,
+    #[serde(default)]
+    pub upload_audio: bool,
+    pub template: String,
+}
+
+impl Matrix{
+    pub fn new(active: bool, url: String, token: String, rooms: Vec<String>,
+               upload_audio: bool, template: String) -> Self{
+        Self{
+            active,
+            url,
+            token,
+            rooms,
+            upload_audio,
+            template,
+        }
+    }
+
+    pub async fn get(pool: &SqlitePool) -> Result<Matrix, Error> {
+        debug!("get_matrix");
+        let active = Param::get(pool, "matrix_active").await? == "TRUE";
+        let url = Param::get(pool, "matrix_url").await?;
+        let token = Param::get(pool, "matrix_token").await?;
+        let rooms = util::lines(&Param::get(pool, "matrix_rooms").await?);
+        let upload_audio = Param::get(pool, "matrix_upload_audio").await? == "TRUE";
+        let template = Param::get(pool, "matrix_template").await?;
+        Ok(Matrix::new(active, url, token, rooms, upload_audio, template))
+    }
+
+    pub async fn set(pool: &SqlitePool, matrix: &Matrix) -> Result<Matrix, Error> {
+        debug!("save_matrix, {:?}", matrix);
+        Param::set(pool, "matrix_active", &matrix.active.to_string().to_uppercase()).await?;
+        Param::set(pool, "matrix_url", matrix.url.trim_end_matches('/')).await?;
+        Param::set(pool, "matrix_token", &matrix.token).await?;
+        Param::set(pool, "matrix_rooms", &matrix.rooms.join("\n")).await?;
+        Param::set(pool, "matrix_upload_audio", &matrix.upload_audio.to_string().to_uppercase()).await?;
+        Param::set(pool, "matrix_template", &matrix.template).await?;
+        Self::get(pool).await
+    }
+
+    /// Url of the endpoint, escaping every segment as room ids and aliases
+    /// contain reserved characters.
+    fn endpoint(&self, segments: &[&str]) -> Result<Url, Error>{
+        let mut url = Url::parse(&self.url)?;
+        url.path_segments_mut()
+            .map_err(|_| "Invalid Matrix url")?
+            .pop_if_empty()
+            .extend(segments);
+        Ok(url)
+    }
+
+    pub async fn whoami(&self) -> Result<Value, Error>{
+        Ok(Client::new()
+            .get(self.endpoint(&["_matrix", "client", "v3", "account", "whoami"])?)
+            .bearer_auth(&self.token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// Returns the id of the room, resolving it first if it is an alias.
+    pub async fn room_id(&self, room: &str) -> Result<String, Error>{
+        if !room.starts_with('#') {
+            return Ok(room.to_string());
+        }
+        let response: Value = Client::new()
+            .get(self.endpoint(&["_matrix", "client", "v3", "directory", "room", room])?)
+            .bearer_auth(&self.token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(response.get("room_id")
+            .and_then(|room_id| room_id.as_str())
+            .ok_or(format!("Can not resolve {room}"))?
+            .to_string())
+    }
+
+    /// Uploads the file to the media repository and returns its `mxc://` uri.
+    pub async fn upload(&self, filename: &str, content_type: &str, filepath: &str) -> Result<String, Error>{
+        debug!("upload: {filename}");
+        let file = tokio::fs::File::open(filepath).await?;
+        let length = file.metadata().await?.len();
+        let mut url = self.endpoint(&["_matrix", "media", "v3", "upload"])?;
+        url.query_pairs_mut().append_pair("filename", filename);
+        let response: Value = Client::new()
+            .post(url)
+            .bearer_auth(&self.token)
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::CONTENT_LENGTH, length)
+            .body(reqwest::Body::wrap_stream(ReaderStream::new(file)))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(response.get("content_uri")
+            .and_then(|uri| uri.as_str())
+            .ok_or("Upload without content_uri")?
+            .to_string())
+    }
+
+    /// Sends the event to the room. Retries with the same transaction id are
+    /// deduplicated by the homeserver.
+    pub async fn send(&self, room_id: &str, txn_id: &str, content: &Value) -> Result<Value, Error>{
+        debug!("send to {room_id}: {content}");
+        Ok(Client::new()
+            .put(self.endpoint(&["_matrix", "client", "v3", "rooms", room_id,
+                "send", "m.room.message", txn_id])?)
+            .bearer_auth(&self.token)
+            .json(content)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    pub async fn redact(&self, room_id: &str, event_id: &str) -> Result<Value, Error>{
+        debug!("redact {event_id} in {room_id}");
+        let txn_id = util::random_string(32);
+        Ok(Client::new()
+            .put(self.endpoint(&["_matrix", "client", "v3", "rooms", room_id,
+                "redact", event_id, &txn_id])?)
+            .bearer_auth(&self.token)
+            .json(&json!({}))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    async fn upload_audio(&self, episode: &Episode) -> Result<Value, Error>{
+        let url = episode.enclosure_url.as_deref().ok_or("Not enclosure")?;
+        let content_type = episode.enclosure_type.as_deref().unwrap_or("audio/mpeg");
+        let name = util::normalize(&episode.title)?;
+        let ext = util::get_extension_from_filename(url).ok_or("Not extension")?;
+        let filename = format!("{name}.{ext}");
+        let file = util::TempFile::new(&format!("matrix_{filename}"));
+        util::fetch_url(url, file.path()).await?;
+        let uri = self.upload(&filename, content_type, file.path()).await?;
+        let mut info = json!({"mimetype": content_type});
+        if let Some(length) = episode.enclosure_length {
+            info["size"] = json!(length);
+        }
+        Ok(json!({
+            "msgtype": "m.audio",
+            "body": filename,
+            "url": uri,
+            "info": info,
+        }))
+    }
+}
+
+/// Html message with its plain text fallback.
+fn content(message: &str) -> Value{
+    let text = from_read(message.as_bytes(), usize::MAX)
+        .unwrap_or(message.to_string());
+    json!({
+        "msgtype": "m.text",
+        "body": text.trim(),
+        "format": "org.matrix.custom.html",
+        "formatted_body": message,
+    })
+}
+
+/// Room and event ids of the receipt, one pair per line.
+fn events(receipt: &Receipt) -> impl Iterator<Item = (&str, &str)>{
+    receipt.remote_id.lines().filter_map(|line| line.split_once(' '))
+}
+
+/// Transaction id of the event, stable for the same episode, room and kind.
+fn txn_id(guid: &str, room: &str, kind: &str) -> String{
+    let mut hasher = Sha256::new();
+    hasher.update(guid.as_bytes());
+    hasher.update(room.as_bytes());
+    hasher.update(kind.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+#[async_trait]
+impl Publisher for Matrix{
+    fn name(&self) -> &'static str{
+        "matrix"
+    }
+
+    fn is_active(&self) -> bool{
+        self.active
+    }
+
+    fn template(&self) -> &str{
+        &self.template
+    }
+
+    fn validate(&self) -> Result<(), Error>{
+        if self.url.is_empty() {
+            return Err("Matrix homeserver url is empty".into());
+        }
+        if self.token.is_empty() {
+            return Err("Matrix access token is empty".into());
+        }
+        if self.rooms.is_empty() {
+            return Err("Matrix has no rooms".into());
+        }
+        Ok(())
+    }
+
+    async fn publish(&self, message: &str, episode: &Episode) -> Result<Receipt, Error>{
+        let content = content(message);
+        let audio = if self.upload_audio {
+            // Better announce it without audio than not announce it at all
+            match self.upload_audio(episode).await {
+                Ok(audio) => Some(audio),
+                Err(e) => {
+                    error!("Could NOT upload audio to Matrix: {e}");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let mut failed = 0;
+        let mut events = Vec::new();
+        for room in self.rooms.iter() {
+            let result = async {
+                let room_id = self.room_id(room).await?;
+                let response = self.send(&room_id, &txn_id(&episode.guid, &room_id, "text"), &content).await?;
+                if let Some(audio) = &audio {
+                    self.send(&room_id, &txn_id(&episode.guid, &room_id, "audio"), audio).await?;
+                }
+                let event_id = response.get("event_id")
+                    .and_then(|event_id| event_id.as_str())
+                    .ok_or("Response without event_id")?
+                    .to_string();
+                Ok::<(String, String), Error>((room_id, event_id))
+            }.await;
+            match result {
+                Ok((room_id, event_id)) => events.push(format!("{room_id} {event_id}")),
+                Err(e) => {
+                    error!("Could NOT send to Matrix room {room}: {e}");
+                    failed += 1;
+                },
+            }
+        }
+        // Retries are safe, the transaction ids avoid duplicates
+        if failed > 0 {
+            return Err(format!("{failed} of {} Matrix rooms failed", self.rooms.len()).into());
+        }
+        Ok(Receipt{
+            remote_id: events.join("\n"),
+            remote_url: None,
+        })
+    }
+
+    async fn edit(&self, receipt: &Receipt, message: &str, _episode: &Episode) -> Result<(), Error>{
+        let new_content = content(message);
+        for (room_id, event_id) in events(receipt) {
+            let mut content = new_content.clone();
+            content["body"] = json!(format!("* {}", new_content["body"].as_str().unwrap_or("")));
+            content["m.new_content"] = new_content.clone();
+            content["m.relates_to"] = json!({"rel_type": "m.replace", "event_id": event_id});
+            self.send(room_id, &util::random_string(32), &content).await?;
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, receipt: &Receipt) -> Result<(), Error>{
+        for (room_id, event_id) in events(receipt) {
+            self.redact(room_id, event_id).await?;
+        }
+        Ok(())
+    }
+
+    async fn test_connection(&self) -> Result<(), Error>{
+        self.whoami().await?;
+        for room in self.rooms.iter() {
+            self.room_id(room).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::{Matrix, txn_id};
+    use crate::models::{Episode, Publisher, mock};
+    use axum::{Json, Router, routing, body::Bytes, extract::{Path, State}, http::HeaderMap};
+    use serde_json::{Value, json};
+    use std::sync::{Arc, Mutex};
+
+    type Events = Arc<Mutex<Vec<(String, String, Value)>>>;
+
+    #[tokio::test]
+    async fn matrix_publish(){
+        let events: Events = Arc::new(Mutex::new(Vec::new()));
+        let router = Router::new()
+            .route("/audio.mp3", routing::get(|| async { vec![0u8; 16] }))
+            .route("/_matrix/media/v3/upload", routing::post(|headers: HeaderMap, body: Bytes| async move {
+                // Streamed from the file, with its length
+                assert_eq!(headers["content-length"], "16");
+                assert_eq!(body.len(), 16);
+                Json(json!({"content_uri": "mxc://example.com/audio"}))
+            }))
+            .route("/_matrix/client/v3/directory/room/{alias}", routing::get(|| async {
+                Json(json!({"room_id": "!resolved:example.com"}))
+            }))
+            .route("/_matrix/client/v3/rooms/{room}/send/m.room.message/{txn}", routing::put(
+                |State(events): State<Events>, Path((room, txn)): Path<(String, String)>, Json(event): Json<Value>| async move {
+                    events.lock().unwrap().push((room, txn, event));
+                    Json(json!({"event_id": "$1"}))
+                }))
+            .with_state(events.clone());
+        let url = mock::serve(router).await;
+        let matrix = Matrix::new(true, url.clone(), "token".to_string(),
+            vec!["!room:example.com".to_string(), "#podcasts:example.com".to_string()],
+            true, "".to_string());
+        let episode = Episode{
+            title: "Episode 1".to_string(),
+            guid: "guid-1".to_string(),
+            enclosure_url: Some(format!("{url}/audio.mp3")),
+            enclosure_type: Some("audio/mpeg".to_string()),
+            enclosure_length: Some(16),
+            ..Default::default()
+        };
+        let receipt = matrix.publish("<b>Episode 1</b>", &episode).await.unwrap();
+        assert_eq!(receipt.remote_id, "!room:example.com $1\n!resolved:example.com $1");
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 4);
+        let (room, txn, event) = &events[0];
+        assert_eq!(room, "!room:example.com");
+        assert_eq!(txn, &txn_id("guid-1", "!room:example.com", "text"));
+        assert_eq!(event["body"], "Episode 1");
+        assert_eq!(event["formatted_body"], "<b>Episode 1</b>");
+        let (_, _, audio) = &events[1];
+        assert_eq!(audio["msgtype"], "m.audio");
+        assert_eq!(audio["url"], "mxc://example.com/audio");
+        assert_eq!(events[2].0, "!resolved:example.com");
+    }
+}
@@ -0,0 +1,32 @@
+use axum::Router;
+use sqlx::{migrate::Migrator, sqlite::{SqlitePool, SqlitePoolOptions}};
+use std::path::Path;
+use tokio::net::TcpListener;
+
+/// Serves `router` on a random local port and returns its base url.
+pub async fn serve(router: Router) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        axum::serve(listener, router).await.unwrap();
+    });
+    url
+}
+
+/// In memory database with every migration applied.
+pub async fn pool() -> SqlitePool {
+    // A single connection, every new one would be a new empty database
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    let migrations = Path::new(env!("CARGO_MANIFEST_DIR")).join("migrations");
+    Migrator::new(migrations)
+        .await
+        .unwrap()
+        .run(&pool)
+        .await
+        .unwrap();
+    pool
+}
@@ -1,30 +1,64 @@
 mod user;
 mod api_response;
 mod data;
-mod feed;
+pub mod feed;
+mod category;
 mod id;
 mod podcast;
 mod config;
-mod telegram;
+pub mod telegram;
 mod twitter;
+mod mastodon;
+mod bluesky;
+mod discord;
+mod slack;
+mod matrix;
+pub mod newsletter;
+pub mod hub;
+pub mod subscription;
+pub mod webhook;
+mod episode;
+mod publisher;
+pub mod publication;
+pub mod bot;
+mod schedule;
+pub mod render;
+#[cfg(test)]
+mod mock;
 pub mod util;
+pub mod validator;
 
 pub use data::Data;
 pub use id::Id;
 pub use api_response::ApiResponse;
 pub use user::{User, TokenClaims, UserSchema, UserRegister};
-pub type Error = Box<dyn std::error::Error>;
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
 pub use podcast::{NewPodcast, Podcast, CompletePodcast};
 pub use config::Param;
 pub use feed::Feed;
+pub use schedule::Schedule;
 pub use telegram::Telegram;
 pub use twitter::Twitter;
+pub use mastodon::Mastodon;
+pub use bluesky::Bluesky;
+pub use discord::Discord;
+pub use slack::Slack;
+pub use matrix::Matrix;
+pub use newsletter::Newsletter;
+pub use subscription::Subscription;
+pub use episode::Episode;
+pub use publisher::{Preview, Publisher, Receipt, Registry};
 
 use sqlx::sqlite::SqlitePool;
+use tokio::sync::mpsc::UnboundedSender;
+use rss::Channel;
 
 #[derive(Clone)]
 pub struct AppState {
     pub pool: SqlitePool,
     pub secret: String,
+    /// Wakes up the worker to ingest the given podcast right now, with the
+    /// content pushed by its hub when it is a whole feed
+    pub worker: UnboundedSender<(i64, Option<Channel>)>,
 }
 
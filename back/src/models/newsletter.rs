@@ -0,0 +1,437 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc, Duration};
+use lettre::{
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+    message::{MultiPart, header::{HeaderName, HeaderValue}},
+    transport::smtp::authentication::Credentials,
+};
+use minijinja::context;
+use serde::{Serialize, Deserialize};
+use sqlx::{sqlite::{SqlitePool, SqliteRow}, query, Row};
+use tracing::{debug, info};
+use super::{Error, Episode, Feed, Publisher, Receipt, Param, render, util};
+
+/// Email digest of the new episodes, sent to the confirmed subscribers.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Newsletter{
+    pub active: bool,
+    pub smtp_host: String,
+    pub smtp_port: u16,
+    pub smtp_username: String,
+    pub smtp_password: String,
+    /// `tls`, `starttls` or `none`
+    pub smtp_security: String,
+    pub from: String,
+    /// `daily` or `weekly`
+    pub frequency: String,
+    pub subject: String,
+    pub html_template: String,
+    pub text_template: String,
+    #[serde(skip_deserializing)]
+    pub last_sent_at: Option<DateTime<Utc>>,
+    /// Queues the episodes in `publish`, set in `prepare`
+    #[serde(skip)]
+    pool: Option<SqlitePool>,
+}
+
+/// A reader of the newsletter. It only receives the digest once confirmed.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Subscriber{
+    pub id: i64,
+    pub email: String,
+    #[serde(skip_serializing)]
+    pub token: String,
+    pub confirmed: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Newsletter{
+    pub async fn get(pool: &SqlitePool) -> Result<Newsletter, Error> {
+        debug!("get_newsletter");
+        let last_sent_at = Param::get(pool, "newsletter_last_sent_at").await?;
+        Ok(Newsletter{
+            active: Param::get(pool, "newsletter_active").await? == "TRUE",
+            smtp_host: Param::get(pool, "newsletter_smtp_host").await?,
+            smtp_port: Param::get(pool, "newsletter_smtp_port").await?.parse()?,
+            smtp_username: Param::get(pool, "newsletter_smtp_username").await?,
+            smtp_password: Param::get(pool, "newsletter_smtp_password").await?,
+            smtp_security: Param::get(pool, "newsletter_smtp_security").await?,
+            from: Param::get(pool, "newsletter_from").await?,
+            frequency: Param::get(pool, "newsletter_frequency").await?,
+            subject: Param::get(pool, "newsletter_subject").await?,
+            html_template: Param::get(pool, "newsletter_html_template").await?,
+            text_template: Param::get(pool, "newsletter_text_template").await?,
+            last_sent_at: DateTime::parse_from_rfc3339(&last_sent_at).ok().map(|date| date.to_utc()),
+            pool: None,
+        })
+    }
+
+    pub async fn set(pool: &SqlitePool, newsletter: &Newsletter) -> Result<Newsletter, Error> {
+        debug!("save_newsletter, {:?}", newsletter);
+        newsletter.validate()?;
+        Param::set(pool, "newsletter_active", &newsletter.active.to_string().to_uppercase()).await?;
+        Param::set(pool, "newsletter_smtp_host", &newsletter.smtp_host).await?;
+        Param::set(pool, "newsletter_smtp_port", &newsletter.smtp_port.to_string()).await?;
+        Param::set(pool, "newsletter_smtp_username", &newsletter.smtp_username).await?;
+        Param::set(pool, "newsletter_smtp_password", &newsletter.smtp_password).await?;
+        Param::set(pool, "newsletter_smtp_security", &newsletter.smtp_security).await?;
+        Param::set(pool, "newsletter_from", &newsletter.from).await?;
+        Param::set(pool, "newsletter_frequency", &newsletter.frequency).await?;
+        Param::set(pool, "newsletter_subject", &newsletter.subject).await?;
+        Param::set(pool, "newsletter_html_template", &newsletter.html_template).await?;
+        Param::set(pool, "newsletter_text_template", &newsletter.text_template).await?;
+        Self::get(pool).await
+    }
+
+    fn period(&self) -> Duration{
+        match self.frequency.as_str() {
+            "weekly" => Duration::weeks(1),
+            _ => Duration::days(1),
+        }
+    }
+
+    pub fn is_due(&self, now: DateTime<Utc>) -> bool{
+        self.last_sent_at.is_none_or(|last_sent_at| last_sent_at + self.period() <= now)
+    }
+
+    fn transport(&self) -> Result<AsyncSmtpTransport<Tokio1Executor>, Error>{
+        let builder = match self.smtp_security.as_str() {
+            "tls" => AsyncSmtpTransport::<Tokio1Executor>::relay(&self.smtp_host)?,
+            "starttls" => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&self.smtp_host)?,
+            _ => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&self.smtp_host),
+        };
+        let mut builder = builder.port(self.smtp_port);
+        if !self.smtp_username.is_empty() {
+            builder = builder.credentials(Credentials::new(
+                self.smtp_username.clone(), self.smtp_password.clone()));
+        }
+        Ok(builder.build())
+    }
+
+    /// Renders the digest, the html template is escaped.
+    pub fn render(&self, feed: &Feed, episodes: &[Episode], unsubscribe_url: &str) -> Result<(String, String, String), Error>{
+        let ctx = context!(
+            mix => context!(title => feed.title, link => feed.link, image_url => feed.image_url),
+            episodes => episodes,
+            unsubscribe_url => unsubscribe_url,
+        );
+        let env = render::environment();
+        let subject = env.render_str(&self.subject, &ctx)?;
+        let html = env.template_from_named_str("digest.html", &self.html_template)?.render(&ctx)?;
+        let text = env.render_str(&self.text_template, &ctx)?;
+        Ok((subject, html, text))
+    }
+
+    fn message(&self, to: &str, subject: &str, html: String, text: String, unsubscribe_url: Option<&str>) -> Result<Message, Error>{
+        let mut builder = Message::builder()
+            .from(self.from.parse()?)
+            .to(to.parse()?)
+            .subject(subject);
+        if let Some(unsubscribe_url) = unsubscribe_url {
+            builder = builder.raw_header(HeaderValue::new(
+                HeaderName::new_from_ascii_str("List-Unsubscribe"),
+                format!("<{unsubscribe_url}>")));
+        }
+        Ok(builder.multipart(MultiPart::alternative_plain_html(text, html))?)
+    }
+
+    /// Sends the link that confirms the subscription.
+    pub async fn send_confirmation(&self, subscriber: &Subscriber, feed: &Feed) -> Result<(), Error>{
+        let url = format!("{}/api/v1/newsletter/confirm?token={}",
+            feed.public_url.trim_end_matches('/'), subscriber.token);
+        let subject = format!("Confirm your subscription to {}", feed.title);
+        let text = format!("To receive the new episodes of {} by email, confirm your subscription:\n\n{url}\n\nIf you did not ask for it, just ignore this email.", feed.title);
+        let html = format!("<p>To receive the new episodes of {} by email, <a href=\"{url}\">confirm your subscription</a>.</p><p>If you did not ask for it, just ignore this email.</p>",
+            html_escape(&feed.title));
+        let message = self.message(&subscriber.email, &subject, html, text, None)?;
+        self.transport()?.send(message).await?;
+        Ok(())
+    }
+
+    /// Sends the queued episodes to every confirmed subscriber when the digest
+    /// is due, or right away if `force` is set. If it could not be sent to
+    /// anybody, the episodes stay queued for the next try.
+    pub async fn send_digest(pool: &SqlitePool, force: bool) -> Result<usize, Error>{
+        let newsletter = Self::get(pool).await?;
+        let now = Utc::now();
+        if !newsletter.active || !(force || newsletter.is_due(now)) {
+            return Ok(0);
+        }
+        let episodes = queued(pool).await?;
+        if episodes.is_empty() {
+            debug!("No episodes for the newsletter");
+            return Ok(0);
+        }
+        let feed = Feed::get(pool).await?;
+        let subscribers = Subscriber::get_confirmed(pool).await?;
+        info!("Send newsletter with {} episodes to {} subscribers", episodes.len(), subscribers.len());
+        let transport = newsletter.transport()?;
+        let mut sent = 0;
+        for subscriber in subscribers.iter() {
+            let unsubscribe_url = format!("{}/api/v1/newsletter/unsubscribe?token={}",
+                feed.public_url.trim_end_matches('/'), subscriber.token);
+            let result = async {
+                let (subject, html, text) = newsletter.render(&feed, &episodes, &unsubscribe_url)?;
+                let message = newsletter.message(&subscriber.email, &subject, html, text, Some(&unsubscribe_url))?;
+                transport.send(message).await?;
+                Ok::<(), Error>(())
+            }.await;
+            match result {
+                Ok(()) => sent += 1,
+                Err(e) => util::log_error(&format!("Could NOT send newsletter to {}", subscriber.email), &e),
+            }
+        }
+        if sent == 0 && !subscribers.is_empty() {
+            return Err("Could NOT send the newsletter to any subscriber".into());
+        }
+        dequeue(pool, episodes.len()).await?;
+        Param::set(pool, "newsletter_last_sent_at", &now.to_rfc3339()).await?;
+        Ok(sent)
+    }
+}
+
+impl Subscriber{
+    fn from_row(row: SqliteRow) -> Self{
+        Self{
+            id: row.get("id"),
+            email: row.get("email"),
+            token: row.get("token"),
+            confirmed: row.get("confirmed"),
+            created_at: row.get("created_at"),
+            updated_at: row.get("updated_at"),
+        }
+    }
+
+    pub async fn get(pool: &SqlitePool) -> Result<Vec<Subscriber>, sqlx::error::Error>{
+        let sql = "SELECT * FROM newsletter_subscribers ORDER BY email";
+        query(sql)
+            .map(Self::from_row)
+            .fetch_all(pool)
+            .await
+    }
+
+    pub async fn get_confirmed(pool: &SqlitePool) -> Result<Vec<Subscriber>, sqlx::error::Error>{
+        let sql = "SELECT * FROM newsletter_subscribers WHERE confirmed = TRUE ORDER BY id";
+        query(sql)
+            .map(Self::from_row)
+            .fetch_all(pool)
+            .await
+    }
+
+    /// Creates a pending subscriber or renews the token of an existing one.
+    pub async fn create(pool: &SqlitePool, email: &str) -> Result<Subscriber, sqlx::error::Error>{
+        let sql = "INSERT INTO newsletter_subscribers (email, token, confirmed, updated_at)
+            VALUES ($1, $2, FALSE, $3)
+            ON CONFLICT(email) DO UPDATE SET
+            token=excluded.token,
+            updated_at=excluded.updated_at
+            RETURNING *";
+        query(sql)
+            .bind(email.trim().to_lowercase())
+            .bind(util::random_string(32))
+            .bind(Utc::now())
+            .map(Self::from_row)
+            .fetch_one(pool)
+            .await
+    }
+
+    pub async fn confirm(pool: &SqlitePool, token: &str) -> Result<Option<Subscriber>, sqlx::error::Error>{
+        let sql = "UPDATE newsletter_subscribers SET confirmed = TRUE, updated_at = $1
+                   WHERE token = $2 RETURNING *";
+        query(sql)
+            .bind(Utc::now())
+            .bind(token)
+            .map(Self::from_row)
+            .fetch_optional(pool)
+            .await
+    }
+
+    pub async fn unsubscribe(pool: &SqlitePool, token: &str) -> Result<Option<Subscriber>, sqlx::error::Error>{
+        let sql = "DELETE FROM newsletter_subscribers WHERE token = $1 RETURNING *";
+        query(sql)
+            .bind(token)
+            .map(Self::from_row)
+            .fetch_optional(pool)
+            .await
+    }
+
+    pub async fn delete(pool: &SqlitePool, id: i64) -> Result<Subscriber, sqlx::error::Error>{
+        let sql = "DELETE FROM newsletter_subscribers WHERE id = $1 RETURNING *";
+        query(sql)
+            .bind(id)
+            .map(Self::from_row)
+            .fetch_one(pool)
+            .await
+    }
+}
+
+/// Adds the episode to the next digest, once.
+pub async fn enqueue(pool: &SqlitePool, episode: &Episode) -> Result<(), Error>{
+    let sql = "INSERT OR IGNORE INTO newsletter_queue (guid, episode) VALUES ($1, $2)";
+    query(sql)
+        .bind(&episode.guid)
+        .bind(serde_json::to_string(episode)?)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn queued(pool: &SqlitePool) -> Result<Vec<Episode>, Error>{
+    let sql = "SELECT episode FROM newsletter_queue ORDER BY id";
+    let rows = query(sql)
+        .map(|row: SqliteRow| -> String { row.get(0) })
+        .fetch_all(pool)
+        .await?;
+    Ok(rows.iter()
+        .filter_map(|episode| serde_json::from_str(episode).ok())
+        .collect())
+}
+
+/// Removes the first `count` episodes, the ones already sent.
+async fn dequeue(pool: &SqlitePool, count: usize) -> Result<(), Error>{
+    let sql = "DELETE FROM newsletter_queue WHERE id IN
+               (SELECT id FROM newsletter_queue ORDER BY id LIMIT $1)";
+    query(sql)
+        .bind(count as i64)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+fn html_escape(text: &str) -> String{
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[async_trait]
+impl Publisher for Newsletter{
+    fn name(&self) -> &'static str{
+        "newsletter"
+    }
+
+    fn is_active(&self) -> bool{
+        self.active
+    }
+
+    fn template(&self) -> &str{
+        &self.subject
+    }
+
+    fn validate(&self) -> Result<(), Error>{
+        if self.smtp_host.is_empty() {
+            return Err("Newsletter SMTP host is empty".into());
+        }
+        if self.from.parse::<lettre::message::Mailbox>().is_err() {
+            return Err(format!("Invalid newsletter sender: '{}'", self.from).into());
+        }
+        if !["tls", "starttls", "none"].contains(&self.smtp_security.as_str()) {
+            return Err(format!("Invalid SMTP security: {}", self.smtp_security).into());
+        }
+        if !["daily", "weekly"].contains(&self.frequency.as_str()) {
+            return Err(format!("Invalid newsletter frequency: {}", self.frequency).into());
+        }
+        Ok(())
+    }
+
+    async fn prepare(&mut self, pool: &SqlitePool) -> Result<(), Error>{
+        self.pool = Some(pool.clone());
+        Ok(())
+    }
+
+    /// Only queues the episode, the worker sends the digest when it is due.
+    async fn publish(&self, _message: &str, episode: &Episode) -> Result<Receipt, Error>{
+        let pool = self.pool.as_ref().ok_or("Newsletter not prepared")?;
+        enqueue(pool, episode).await?;
+        Ok(Receipt::default())
+    }
+
+    async fn test_connection(&self) -> Result<(), Error>{
+        if self.transport()?.test_connection().await? {
+            Ok(())
+        } else {
+            Err(format!("Can not connect to {}", self.smtp_host).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::{Newsletter, Subscriber, enqueue, queued, dequeue};
+    use crate::models::{Episode, Feed, Param, Publisher, mock};
+
+    #[tokio::test]
+    async fn test_subscribers(){
+        let pool = mock::pool().await;
+        let subscriber = Subscriber::create(&pool, " Reader@Example.com").await.unwrap();
+        assert_eq!(subscriber.email, "reader@example.com");
+        assert!(!subscriber.confirmed);
+        assert!(Subscriber::get_confirmed(&pool).await.unwrap().is_empty());
+        assert!(Subscriber::confirm(&pool, "wrong").await.unwrap().is_none());
+        let confirmed = Subscriber::confirm(&pool, &subscriber.token).await.unwrap().unwrap();
+        assert!(confirmed.confirmed);
+        assert_eq!(Subscriber::get_confirmed(&pool).await.unwrap().len(), 1);
+        Subscriber::unsubscribe(&pool, &subscriber.token).await.unwrap().unwrap();
+        assert!(Subscriber::get(&pool).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_queue(){
+        let pool = mock::pool().await;
+        let mut newsletter = Newsletter::get(&pool).await.unwrap();
+        newsletter.prepare(&pool).await.unwrap();
+        for guid in ["1", "2", "1"] {
+            let episode = Episode{
+                guid: guid.to_string(),
+                title: format!("Episode {guid}"),
+                ..Default::default()
+            };
+            newsletter.publish("", &episode).await.unwrap();
+        }
+        enqueue(&pool, &Episode{ guid: "3".to_string(), ..Default::default() }).await.unwrap();
+        assert_eq!(queued(&pool).await.unwrap().len(), 3);
+        dequeue(&pool, 2).await.unwrap();
+        let episodes = queued(&pool).await.unwrap();
+        assert_eq!(episodes.len(), 1);
+        assert_eq!(episodes[0].guid, "3");
+    }
+
+    #[tokio::test]
+    async fn test_digest_not_sent(){
+        let pool = mock::pool().await;
+        Param::set(&pool, "newsletter_active", "TRUE").await.unwrap();
+        Param::set(&pool, "newsletter_smtp_host", "127.0.0.1").await.unwrap();
+        Param::set(&pool, "newsletter_smtp_port", "1").await.unwrap();
+        Param::set(&pool, "newsletter_smtp_security", "none").await.unwrap();
+        Param::set(&pool, "newsletter_from", "podmixer@example.com").await.unwrap();
+        let subscriber = Subscriber::create(&pool, "user@example.com").await.unwrap();
+        Subscriber::confirm(&pool, &subscriber.token).await.unwrap().unwrap();
+        enqueue(&pool, &Episode{ guid: "1".to_string(), ..Default::default() }).await.unwrap();
+        assert!(Newsletter::send_digest(&pool, true).await.is_err());
+        assert_eq!(queued(&pool).await.unwrap().len(), 1);
+        assert_eq!(Param::get(&pool, "newsletter_last_sent_at").await.unwrap(), "");
+    }
+
+    #[tokio::test]
+    async fn test_render(){
+        let pool = mock::pool().await;
+        let newsletter = Newsletter::get(&pool).await.unwrap();
+        let mut feed = Feed::get(&pool).await.unwrap();
+        feed.title = "Mix".to_string();
+        let episodes = vec![Episode{
+            podcast: "Podcast".to_string(),
+            title: "Q&A <live>".to_string(),
+            link: "https://example.com/1".to_string(),
+            ..Default::default()
+        }];
+        let (subject, html, text) = newsletter.render(&feed, &episodes,
+            "https://mix.example.com/unsubscribe").unwrap();
+        assert_eq!(subject, "New episodes of Mix");
+        assert!(html.contains("Q&amp;A &lt;live&gt;"));
+        assert!(html.contains("mix.example.com&#x2f;unsubscribe"));
+        assert!(text.contains("Q&A <live>"));
+        assert!(text.contains("https://example.com/1"));
+    }
+}
@@ -1,7 +1,8 @@
+use std::collections::BTreeMap;
 use serde::{Deserialize, Serialize};
 use sqlx::{sqlite::{SqlitePool, SqliteRow}, query, Row};
 use rss::{Channel, Item};
-use super::Error;
+use super::{Episode, Error, render};
 use chrono::{DateTime, Utc, Duration};
 use tracing::debug;
 
@@ -19,10 +20,24 @@ pub struct Podcast{
     pub url: String,
     pub active: bool,
     pub last_pub_date: DateTime<Utc>,
+    /// Whether its new episodes are announced, it is mixed anyway
+    #[serde(default = "default_announce")]
+    pub announce: bool,
+    /// Destinations where it is announced, all of them when empty
+    #[serde(default)]
+    pub destinations: Vec<String>,
+    /// Templates by destination, instead of the ones of the destinations and
+    /// of their targets, like the Telegram chats
+    #[serde(default)]
+    pub templates: BTreeMap<String, String>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
 
+fn default_announce() -> bool{
+    true
+}
+
 #[derive(Debug)]
 pub struct CompletePodcast{
     pub podcast: Podcast,
@@ -37,6 +52,9 @@ impl Podcast{
             url: row.get("url"),
             active: row.get("active"),
             last_pub_date: row.get("last_pub_date"),
+            announce: row.get("announce"),
+            destinations: serde_json::from_str(row.get("destinations")).unwrap_or_default(),
+            templates: serde_json::from_str(row.get("templates")).unwrap_or_default(),
             created_at: row.get("created_at"),
             updated_at: row.get("updated_at"),
         }
@@ -51,12 +69,16 @@ impl Podcast{
 
     pub async fn update(pool: &SqlitePool, podcast: &Podcast) -> Result<Podcast, sqlx::error::Error>{
         let sql = "UPDATE podcasts SET name=$1, url=$2, active=$3,
-                   last_pub_date=$4, updated_at=$5 WHERE id=$6 RETURNING *";
+                   last_pub_date=$4, announce=$5, destinations=$6, templates=$7,
+                   updated_at=$8 WHERE id=$9 RETURNING *";
         query(sql)
             .bind(podcast.name.to_owned())
             .bind(podcast.url.to_owned())
             .bind(podcast.active)
             .bind(podcast.last_pub_date)
+            .bind(podcast.announce)
+            .bind(serde_json::to_string(&podcast.destinations).unwrap_or("[]".to_string()))
+            .bind(serde_json::to_string(&podcast.templates).unwrap_or("{}".to_string()))
             .bind(Utc::now())
             .bind(podcast.id)
             .map(Self::from_row)
@@ -96,6 +118,28 @@ impl Podcast{
             .await
     }
 
+    /// Whether its episodes go to the destination.
+    pub fn announces_in(&self, destination: &str) -> bool{
+        self.announce && (self.destinations.is_empty()
+            || self.destinations.iter().any(|name| name == destination))
+    }
+
+    /// Its own template for the destination, if any.
+    pub fn template(&self, destination: &str) -> Option<&str>{
+        self.templates.get(destination)
+            .map(|template| template.as_str())
+            .filter(|template| !template.trim().is_empty())
+    }
+
+    /// Checks the overridden templates, so they do not fail when announcing.
+    pub fn validate(&self) -> Result<(), Error>{
+        for (destination, template) in self.templates.iter() {
+            render::check(template, &Episode::sample().context())
+                .map_err(|e| format!("Template for {destination}: {e}"))?;
+        }
+        Ok(())
+    }
+
     pub async fn delete(pool: &SqlitePool, id: i64) -> Result<Podcast, sqlx::error::Error>{
         let sql = "DELETE FROM podcasts WHERE id = $1 RETURNING *";
         query(sql)
@@ -107,24 +151,40 @@ impl Podcast{
 
 }
 
+/// Downloads and parses the feed of a podcast.
+pub async fn fetch_channel(url: &str) -> Result<Channel, Error>{
+    debug!("Url: {}", url);
+    let content = reqwest::Client::builder()
+        .user_agent("Mozilla/5.0 (Macintosh; Intel Mac OS X 10_10_1) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/39.0.2171.95 Safari/537.36")
+        .build()?
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    Ok(Channel::read_from(&content[..])?)
+}
+
 impl CompletePodcast {
     pub async fn new(podcast: &Podcast) -> Result<Self, Error>{
-        debug!("Url: {}", &podcast.url);
-        let content = reqwest::Client::builder()
-            .user_agent("Mozilla/5.0 (Macintosh; Intel Mac OS X 10_10_1) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/39.0.2171.95 Safari/537.36")
-            .build()?
-            .get(&podcast.url)
-            .send()
-            .await?
-            .error_for_status()?
-            .bytes()
-            .await?;
-        let channel = Channel::read_from(&content[..])?;
+        let channel = fetch_channel(&podcast.url).await?;
         Ok(Self {
             podcast: podcast.clone(),
             channel,
         })
     }
+    /// WebSub hub and topic advertised by the source channel.
+    pub fn hub(&self) -> Option<(String, String)>{
+        let links = self.channel.atom_ext()?.links();
+        let hub = links.iter().find(|link| link.rel() == "hub")?.href().to_string();
+        let topic = links.iter()
+            .find(|link| link.rel() == "self")
+            .map(|link| link.href().to_string())
+            .unwrap_or(self.podcast.url.clone());
+        Some((hub, topic))
+    }
+
     pub fn get_all(&self) -> Vec<Item>{
         let mut all: Vec<Item> = Vec::new();
         for item in self.channel.items.as_slice(){
@@ -162,3 +222,38 @@ impl CompletePodcast {
     }
 }
 
+
+#[cfg(test)]
+mod test{
+    use super::Podcast;
+    use chrono::Utc;
+
+    #[test]
+    fn test_overrides(){
+        let mut podcast = Podcast{
+            id: 1,
+            name: "Atareao".to_string(),
+            url: "https://atareao.es/feed".to_string(),
+            active: true,
+            last_pub_date: Utc::now(),
+            announce: true,
+            destinations: Vec::new(),
+            templates: Default::default(),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        };
+        assert!(podcast.announces_in("telegram"));
+        podcast.destinations = vec!["twitter".to_string()];
+        assert!(!podcast.announces_in("telegram"));
+        assert!(podcast.announces_in("twitter"));
+        podcast.announce = false;
+        assert!(!podcast.announces_in("twitter"));
+        podcast.templates.insert("twitter".to_string(), " ".to_string());
+        assert_eq!(podcast.template("twitter"), None);
+        podcast.templates.insert("twitter".to_string(), "{{ title }} #{{ podcast }}".to_string());
+        assert_eq!(podcast.template("twitter"), Some("{{ title }} #{{ podcast }}"));
+        assert!(podcast.validate().is_ok());
+        podcast.templates.insert("telegram".to_string(), "{% if title %}".to_string());
+        assert!(podcast.validate().unwrap_err().to_string().starts_with("Template for telegram: line 1"));
+    }
+}
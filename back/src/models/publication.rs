@@ -0,0 +1,514 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{sqlite::{SqlitePool, SqliteRow}, query, Row};
+use chrono::{DateTime, Utc, Duration};
+use tracing::{debug, info, error};
+use std::collections::HashMap;
+
+use super::{Error, Episode, Podcast, Receipt, Registry, Schedule, render, util};
+
+pub const PENDING: &str = "pending";
+pub const SENDING: &str = "sending";
+pub const PUBLISHED: &str = "published";
+pub const FAILED: &str = "failed";
+pub const DELETED: &str = "deleted";
+
+const MAX_ATTEMPTS: i64 = 8;
+const HISTORY_LIMIT: i64 = 100;
+
+/// The announcement of an episode in a destination. There is only one per
+/// episode and destination, so an episode is never announced twice.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Publication{
+    pub id: i64,
+    pub guid: String,
+    pub destination: String,
+    pub episode: Episode,
+    /// `pending`, `sending`, `published`, `failed` or `deleted`
+    pub status: String,
+    pub attempts: i64,
+    /// Times it was queued again by hand
+    pub resends: i64,
+    pub last_error: Option<String>,
+    /// Id of the post in the destination, one per line for several targets
+    pub remote_id: Option<String>,
+    pub remote_url: Option<String>,
+    /// The message as it was published
+    pub message: Option<String>,
+    pub next_attempt_at: DateTime<Utc>,
+    pub published_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Publication{
+    fn from_row(row: SqliteRow) -> Self{
+        Self{
+            id: row.get("id"),
+            guid: row.get("guid"),
+            destination: row.get("destination"),
+            episode: serde_json::from_str(row.get("episode")).unwrap_or_default(),
+            status: row.get("status"),
+            attempts: row.get("attempts"),
+            resends: row.get("resends"),
+            last_error: row.get("last_error"),
+            remote_id: row.get("remote_id"),
+            remote_url: row.get("remote_url"),
+            message: row.get("message"),
+            next_attempt_at: row.get("next_attempt_at"),
+            published_at: row.get("published_at"),
+            created_at: row.get("created_at"),
+            updated_at: row.get("updated_at"),
+        }
+    }
+
+    /// Queues the episode for the destination, unless it is already queued.
+    pub async fn enqueue(pool: &SqlitePool, destination: &str, episode: &Episode) -> Result<bool, Error>{
+        let sql = "INSERT OR IGNORE INTO publications (guid, destination, episode, next_attempt_at)
+                   VALUES ($1, $2, $3, $4)";
+        let result = query(sql)
+            .bind(&episode.guid)
+            .bind(destination)
+            .bind(serde_json::to_string(episode)?)
+            .bind(Utc::now())
+            .execute(pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// The last publications, optionally of an episode or a destination.
+    pub async fn get(pool: &SqlitePool, guid: Option<&str>, destination: Option<&str>) -> Result<Vec<Publication>, sqlx::error::Error>{
+        let sql = "SELECT * FROM publications
+                   WHERE ($1 IS NULL OR guid = $1) AND ($2 IS NULL OR destination = $2)
+                   ORDER BY id DESC LIMIT $3";
+        query(sql)
+            .bind(guid)
+            .bind(destination)
+            .bind(HISTORY_LIMIT)
+            .map(Self::from_row)
+            .fetch_all(pool)
+            .await
+    }
+
+    /// How many publications there are in every status.
+    pub async fn count(pool: &SqlitePool) -> Result<Vec<(String, i64)>, sqlx::error::Error>{
+        let sql = "SELECT status, COUNT(*) AS count FROM publications GROUP BY status ORDER BY status";
+        query(sql)
+            .map(|row: SqliteRow| (row.get("status"), row.get("count")))
+            .fetch_all(pool)
+            .await
+    }
+
+    pub async fn get_by_id(pool: &SqlitePool, id: i64) -> Result<Option<Publication>, sqlx::error::Error>{
+        let sql = "SELECT * FROM publications WHERE id = $1";
+        query(sql)
+            .bind(id)
+            .map(Self::from_row)
+            .fetch_optional(pool)
+            .await
+    }
+
+    pub async fn get_due(pool: &SqlitePool) -> Result<Vec<Publication>, sqlx::error::Error>{
+        let sql = "SELECT * FROM publications WHERE status = $1 AND next_attempt_at <= $2
+                   ORDER BY next_attempt_at, id";
+        query(sql)
+            .bind(PENDING)
+            .bind(Utc::now())
+            .map(Self::from_row)
+            .fetch_all(pool)
+            .await
+    }
+
+    /// Marks it as being sent. Returns `None` if someone else claimed it.
+    pub async fn claim(&self, pool: &SqlitePool) -> Result<Option<Publication>, sqlx::error::Error>{
+        let sql = "UPDATE publications SET status = $1, updated_at = $2
+                   WHERE id = $3 AND status = $4 RETURNING *";
+        query(sql)
+            .bind(SENDING)
+            .bind(Utc::now())
+            .bind(self.id)
+            .bind(PENDING)
+            .map(Self::from_row)
+            .fetch_optional(pool)
+            .await
+    }
+
+    /// Records what was published and where, to edit or delete it later.
+    pub async fn published(&self, pool: &SqlitePool, message: &str, receipt: &Receipt) -> Result<Publication, sqlx::error::Error>{
+        let sql = "UPDATE publications SET status = $1, attempts = attempts + 1,
+                   last_error = NULL, remote_id = $2, remote_url = $3, message = $4,
+                   published_at = $5, updated_at = $5 WHERE id = $6 RETURNING *";
+        query(sql)
+            .bind(PUBLISHED)
+            .bind(Some(&receipt.remote_id).filter(|remote_id| !remote_id.is_empty()))
+            .bind(&receipt.remote_url)
+            .bind(message)
+            .bind(Utc::now())
+            .bind(self.id)
+            .map(Self::from_row)
+            .fetch_one(pool)
+            .await
+    }
+
+    /// Moves it to the next slot of the schedule of the destination.
+    pub async fn defer(&self, pool: &SqlitePool, next_attempt_at: DateTime<Utc>) -> Result<Publication, sqlx::error::Error>{
+        let sql = "UPDATE publications SET next_attempt_at = $1, updated_at = $2
+                   WHERE id = $3 RETURNING *";
+        query(sql)
+            .bind(next_attempt_at)
+            .bind(Utc::now())
+            .bind(self.id)
+            .map(Self::from_row)
+            .fetch_one(pool)
+            .await
+    }
+
+    /// When the destination posted lately, enough for the daily limit in
+    /// any timezone.
+    pub async fn sent(pool: &SqlitePool, destination: &str) -> Result<Vec<DateTime<Utc>>, sqlx::error::Error>{
+        let sql = "SELECT published_at FROM publications
+                   WHERE destination = $1 AND published_at >= $2";
+        query(sql)
+            .bind(destination)
+            .bind(Utc::now() - Duration::days(2))
+            .map(|row: SqliteRow| -> DateTime<Utc> {row.get(0)})
+            .fetch_all(pool)
+            .await
+    }
+
+    /// Records the error and schedules the next attempt with an exponential
+    /// backoff, giving up after `MAX_ATTEMPTS`. Errors that will not go away
+    /// by retrying fail right away.
+    pub async fn failed(&self, pool: &SqlitePool, error: &str, retry: bool) -> Result<Publication, sqlx::error::Error>{
+        let attempts = self.attempts + 1;
+        let status = if retry && attempts < MAX_ATTEMPTS { PENDING } else { FAILED };
+        let next_attempt_at = Utc::now() + backoff(attempts);
+        let sql = "UPDATE publications SET status = $1, attempts = $2, last_error = $3,
+                   next_attempt_at = $4, updated_at = $5 WHERE id = $6 RETURNING *";
+        query(sql)
+            .bind(status)
+            .bind(attempts)
+            .bind(error)
+            .bind(next_attempt_at)
+            .bind(Utc::now())
+            .bind(self.id)
+            .map(Self::from_row)
+            .fetch_one(pool)
+            .await
+    }
+
+    /// Queues it again to be published as soon as possible. Whatever was
+    /// published before stays in the destination.
+    pub async fn resend(pool: &SqlitePool, id: i64) -> Result<Publication, Error>{
+        let sql = "UPDATE publications SET status = $1, attempts = 0, resends = resends + 1,
+                   last_error = NULL, next_attempt_at = $2, updated_at = $2 WHERE id = $3 AND status != $4
+                   RETURNING *";
+        query(sql)
+            .bind(PENDING)
+            .bind(Utc::now())
+            .bind(id)
+            .bind(SENDING)
+            .map(Self::from_row)
+            .fetch_optional(pool)
+            .await?
+            .ok_or_else(|| "Publication not found or being sent".into())
+    }
+
+    async fn edited(&self, pool: &SqlitePool, message: &str) -> Result<Publication, sqlx::error::Error>{
+        let sql = "UPDATE publications SET message = $1, updated_at = $2 WHERE id = $3 RETURNING *";
+        query(sql)
+            .bind(message)
+            .bind(Utc::now())
+            .bind(self.id)
+            .map(Self::from_row)
+            .fetch_one(pool)
+            .await
+    }
+
+    async fn deleted(&self, pool: &SqlitePool) -> Result<Publication, sqlx::error::Error>{
+        let sql = "UPDATE publications SET status = $1, updated_at = $2 WHERE id = $3 RETURNING *";
+        query(sql)
+            .bind(DELETED)
+            .bind(Utc::now())
+            .bind(self.id)
+            .map(Self::from_row)
+            .fetch_one(pool)
+            .await
+    }
+
+    /// What the destination returned when it was published.
+    fn receipt(&self) -> Result<Receipt, Error>{
+        if self.status != PUBLISHED {
+            return Err(format!("The publication is {}, not {PUBLISHED}", self.status).into());
+        }
+        let remote_id = self.remote_id.clone()
+            .ok_or("The destination did not return the id of the post")?;
+        Ok(Receipt{
+            remote_id,
+            remote_url: self.remote_url.clone(),
+        })
+    }
+
+    /// Publications left in `sending` by a crash may have been posted or
+    /// not. Better to miss them than to post them twice, so they fail.
+    pub async fn fail_interrupted(pool: &SqlitePool) -> Result<u64, sqlx::error::Error>{
+        let sql = "UPDATE publications SET status = $1, last_error = $2, updated_at = $3
+                   WHERE status = $4";
+        let result = query(sql)
+            .bind(FAILED)
+            .bind("Interrupted while sending, check the destination before retrying")
+            .bind(Utc::now())
+            .bind(SENDING)
+            .execute(pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+}
+
+/// 1, 2, 4, 8... minutes, up to a day.
+fn backoff(attempts: i64) -> Duration{
+    Duration::minutes(1 << (attempts - 1).clamp(0, 12)).min(Duration::days(1))
+}
+
+/// Queues the episodes for every active destination.
+pub async fn enqueue(pool: &SqlitePool, registry: &Registry, episodes: &[Episode]) -> Result<(), Error>{
+    let podcasts = podcasts(pool).await?;
+    for episode in episodes {
+        for publisher in registry.active() {
+            if podcasts.get(&episode.podcast).is_some_and(|podcast| !podcast.announces_in(publisher.name()))
+                || !publisher.accepts(episode) {
+                continue;
+            }
+            if Publication::enqueue(pool, publisher.name(), episode).await? {
+                debug!("Queued for {}: {}", publisher.name(), episode.title);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn published_by_id(pool: &SqlitePool, id: i64) -> Result<Publication, Error>{
+    Publication::get_by_id(pool, id).await?
+        .ok_or_else(|| "Publication not found".into())
+}
+
+/// Replaces the published message, where the destination allows it.
+pub async fn edit(pool: &SqlitePool, id: i64, message: &str) -> Result<Publication, Error>{
+    let publication = published_by_id(pool, id).await?;
+    let receipt = publication.receipt()?;
+    let mut registry = Registry::load(pool).await?;
+    registry.prepare(pool).await;
+    let publisher = registry.get(&publication.destination)
+        .ok_or("Destination not available")?;
+    publisher.edit(&receipt, message, &publication.episode).await?;
+    Ok(publication.edited(pool, message).await?)
+}
+
+/// Removes the published message, where the destination allows it.
+pub async fn delete(pool: &SqlitePool, id: i64) -> Result<Publication, Error>{
+    let publication = published_by_id(pool, id).await?;
+    let receipt = publication.receipt()?;
+    let mut registry = Registry::load(pool).await?;
+    registry.prepare(pool).await;
+    let publisher = registry.get(&publication.destination)
+        .ok_or("Destination not available")?;
+    publisher.delete(&receipt).await?;
+    Ok(publication.deleted(pool).await?)
+}
+
+/// The source podcasts by name, as the episodes refer to them.
+async fn podcasts(pool: &SqlitePool) -> Result<HashMap<String, Podcast>, Error>{
+    Ok(Podcast::get(pool)
+        .await?
+        .into_iter()
+        .map(|podcast| (podcast.name.clone(), podcast))
+        .collect())
+}
+
+/// Publishes the due publications, one by one. Those outside the schedule
+/// of their destination wait for its next slot.
+pub async fn dispatch(pool: &SqlitePool) -> Result<(), Error>{
+    let due = Publication::get_due(pool).await?;
+    if due.is_empty() {
+        return Ok(());
+    }
+    let mut registry = Registry::load(pool).await?;
+    registry.prepare(pool).await;
+    let podcasts = podcasts(pool).await?;
+    let mut schedules: HashMap<String, (Schedule, Vec<DateTime<Utc>>)> = HashMap::new();
+    for publication in due {
+        if !schedules.contains_key(&publication.destination) {
+            let schedule = Schedule::get(pool, &publication.destination).await?;
+            let sent = Publication::sent(pool, &publication.destination).await?;
+            schedules.insert(publication.destination.clone(), (schedule, sent));
+        }
+        let (schedule, sent) = schedules.get_mut(&publication.destination).unwrap();
+        let now = Utc::now();
+        let slot = schedule.next_slot(now, sent);
+        if slot > now {
+            debug!("Deferred in {} until {slot}: {}", publication.destination, publication.episode.title);
+            publication.defer(pool, slot).await?;
+            continue;
+        }
+        let Some(publication) = publication.claim(pool).await? else {
+            continue;
+        };
+        let episode = &Episode{resends: publication.resends, ..publication.episode.clone()};
+        let Some(publisher) = registry.get(&publication.destination) else {
+            // Either unknown or it could not be prepared, maybe next time
+            publication.failed(pool, "Destination not available", true).await?;
+            continue;
+        };
+        if !publisher.is_active() {
+            publication.failed(pool, "Destination disabled", false).await?;
+            continue;
+        }
+        let podcast = podcasts.get(&episode.podcast);
+        if podcast.is_some_and(|podcast| !podcast.announces_in(publisher.name())) {
+            // Changed since it was queued
+            publication.failed(pool, "Not announced for this podcast", false).await?;
+            continue;
+        }
+        if !publisher.accepts(episode) {
+            publication.failed(pool, "No target for this podcast", false).await?;
+            continue;
+        }
+        info!("Trying to populate in {}: {}", publisher.name(), episode.title);
+        let rendered = match podcast.and_then(|podcast| podcast.template(publisher.name())) {
+            Some(template) => render::render(template, &episode.context()),
+            None => publisher.render(episode),
+        };
+        let message = match rendered {
+            Ok(message) => message,
+            Err(e) => {
+                // A broken template will not fix itself
+                publication.failed(pool, &e.to_string(), false).await?;
+                util::log_error(&format!("Could NOT render for {}", publisher.name()), &e);
+                continue;
+            },
+        };
+        match publisher.publish(&message, episode).await {
+            Ok(receipt) => {
+                info!("Populated in {}: {}", publisher.name(), episode.title);
+                let publication = publication.published(pool, &message, &receipt).await?;
+                sent.extend(publication.published_at);
+            },
+            Err(e) => {
+                util::log_error(&format!("Could NOT populate in {}", publisher.name()), &e);
+                let publication = publication.failed(pool, &e.to_string(), true).await?;
+                if publication.status == FAILED {
+                    error!("Giving up populating in {}: {}", publisher.name(), episode.title);
+                }
+            },
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test{
+    use super::{Publication, PENDING, SENDING, FAILED, PUBLISHED, backoff, enqueue};
+    use crate::models::{Episode, Param, Podcast, Receipt, Registry, mock, telegram::{NewTarget, Target}};
+    use chrono::Duration;
+
+    #[test]
+    fn test_backoff(){
+        assert_eq!(backoff(1), Duration::minutes(1));
+        assert_eq!(backoff(4), Duration::minutes(8));
+        assert_eq!(backoff(30), Duration::days(1));
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle(){
+        let pool = mock::pool().await;
+        let episode = Episode{
+            guid: "guid-1".to_string(),
+            title: "Episode 1".to_string(),
+            ..Default::default()
+        };
+        assert!(Publication::enqueue(&pool, "telegram", &episode).await.unwrap());
+        assert!(!Publication::enqueue(&pool, "telegram", &episode).await.unwrap());
+        assert!(Publication::enqueue(&pool, "mastodon", &episode).await.unwrap());
+        let due = Publication::get_due(&pool).await.unwrap();
+        assert_eq!(due.len(), 2);
+        assert_eq!(due[0].episode.title, "Episode 1");
+        let telegram = due[0].claim(&pool).await.unwrap().unwrap();
+        assert_eq!(telegram.status, SENDING);
+        assert!(due[0].claim(&pool).await.unwrap().is_none());
+        let telegram = telegram.failed(&pool, "timeout", true).await.unwrap();
+        assert_eq!(telegram.status, PENDING);
+        assert_eq!(telegram.attempts, 1);
+        // Waiting for the backoff
+        assert_eq!(Publication::get_due(&pool).await.unwrap().len(), 1);
+        let mastodon = due[1].claim(&pool).await.unwrap().unwrap();
+        let receipt = Receipt{
+            remote_id: "1".to_string(),
+            remote_url: Some("https://mastodon.social/@podmixer/1".to_string()),
+        };
+        let mastodon = mastodon.published(&pool, "New episode", &receipt).await.unwrap();
+        assert_eq!(mastodon.status, PUBLISHED);
+        assert_eq!(mastodon.remote_id.as_deref(), Some("1"));
+        assert_eq!(mastodon.message.as_deref(), Some("New episode"));
+        assert_eq!(Publication::sent(&pool, "mastodon").await.unwrap(), vec![mastodon.published_at.unwrap()]);
+        assert_eq!(mastodon.receipt().unwrap().remote_url, receipt.remote_url);
+        let history = Publication::get(&pool, Some("guid-1"), None).await.unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].destination, "mastodon");
+        assert_eq!(Publication::get(&pool, None, Some("telegram")).await.unwrap().len(), 1);
+        let telegram = telegram.claim(&pool).await.unwrap().unwrap();
+        assert_eq!(Publication::fail_interrupted(&pool).await.unwrap(), 1);
+        assert!(telegram.claim(&pool).await.unwrap().is_none());
+        assert!(Publication::get_due(&pool).await.unwrap().is_empty());
+        let sql = "SELECT status FROM publications WHERE id = $1";
+        let status: String = sqlx::query_scalar(sql).bind(telegram.id).fetch_one(&pool).await.unwrap();
+        assert_eq!(status, FAILED);
+        assert!(telegram.receipt().is_err());
+        let telegram = Publication::resend(&pool, telegram.id).await.unwrap();
+        assert_eq!(telegram.status, PENDING);
+        assert_eq!(telegram.attempts, 0);
+        assert_eq!(telegram.resends, 1);
+        assert_eq!(Publication::get_due(&pool).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_podcast_overrides(){
+        let pool = mock::pool().await;
+        Param::set(&pool, "telegram_active", "TRUE").await.unwrap();
+        Param::set(&pool, "discord_active", "TRUE").await.unwrap();
+        Target::create(&pool, &NewTarget{
+            name: "Other".to_string(),
+            chat_id: "@other".to_string(),
+            thread_id: "".to_string(),
+            template: "".to_string(),
+            podcasts: vec!["Other".to_string()],
+            active: true,
+        }).await.unwrap();
+        let registry = Registry::load(&pool).await.unwrap();
+        let now = chrono::Utc::now();
+        let mut quiet = Podcast::create(&pool, "Quiet", "https://quiet.es/feed", true, &now).await.unwrap();
+        quiet.announce = false;
+        Podcast::update(&pool, &quiet).await.unwrap();
+        let mut only = Podcast::create(&pool, "Only", "https://only.es/feed", true, &now).await.unwrap();
+        only.destinations = vec!["discord".to_string()];
+        let only = Podcast::update(&pool, &only).await.unwrap();
+        assert_eq!(only.destinations, vec!["discord"]);
+        let episodes: Vec<Episode> = ["Quiet", "Only", "Unknown"].iter()
+            .map(|podcast| Episode{
+                guid: podcast.to_string(),
+                podcast: podcast.to_string(),
+                ..Default::default()
+            })
+            .collect();
+        enqueue(&pool, &registry, &episodes).await.unwrap();
+        let queued: Vec<(String, String)> = Publication::get(&pool, None, None).await.unwrap()
+            .into_iter()
+            .map(|publication| (publication.guid, publication.destination))
+            .collect();
+        assert_eq!(queued.len(), 2);
+        assert!(!queued.iter().any(|(guid, _)| guid == "Quiet"));
+        assert!(queued.contains(&("Only".to_string(), "discord".to_string())));
+        assert!(!queued.contains(&("Only".to_string(), "telegram".to_string())));
+        // No Telegram chat takes it
+        assert!(queued.contains(&("Unknown".to_string(), "discord".to_string())));
+        assert!(!queued.contains(&("Unknown".to_string(), "telegram".to_string())));
+    }
+}
@@ -0,0 +1,198 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqlitePool;
+use tracing::debug;
+use super::{Error, Episode, Telegram, Twitter, Mastodon, Bluesky, Discord, Slack, Matrix, Newsletter, publication::Publication, render, util};
+
+/// What a destination returns for a published announcement, to find it
+/// later. Destinations with several targets keep one id per line.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Receipt{
+    pub remote_id: String,
+    pub remote_url: Option<String>,
+}
+
+/// A destination where new episodes are announced.
+#[async_trait]
+pub trait Publisher: Send + Sync {
+    /// Name of the destination, also the prefix of its keys in `config`
+    fn name(&self) -> &'static str;
+
+    fn is_active(&self) -> bool;
+
+    fn template(&self) -> &str;
+
+    /// Checks that the configuration is complete enough to publish.
+    fn validate(&self) -> Result<(), Error>;
+
+    fn render(&self, episode: &Episode) -> Result<String, Error>{
+        render::render(self.template(), &episode.context())
+    }
+
+    /// Whether the destination announces the episode at all, i.e. when its
+    /// targets only take some podcasts.
+    fn accepts(&self, _episode: &Episode) -> bool{
+        true
+    }
+
+    /// Longest announcement the destination takes, if there is a limit.
+    fn limit(&self) -> Option<usize>{
+        None
+    }
+
+    /// Length of the announcement as the destination counts it.
+    fn length(&self, message: &str) -> usize{
+        message.chars().count()
+    }
+
+    /// Called before publishing a batch of episodes, i.e. to refresh tokens.
+    async fn prepare(&mut self, _pool: &SqlitePool) -> Result<(), Error>{
+        Ok(())
+    }
+
+    async fn publish(&self, message: &str, episode: &Episode) -> Result<Receipt, Error>;
+
+    /// Replaces the text of a published announcement.
+    async fn edit(&self, _receipt: &Receipt, _message: &str, _episode: &Episode) -> Result<(), Error>{
+        Err(format!("{} announcements can not be edited", self.name()).into())
+    }
+
+    /// Removes a published announcement.
+    async fn delete(&self, _receipt: &Receipt) -> Result<(), Error>{
+        Err(format!("{} announcements can not be deleted", self.name()).into())
+    }
+
+    /// Checks the credentials against the destination without publishing.
+    async fn test_connection(&self) -> Result<(), Error>;
+}
+
+/// A template rendered for a destination, before saving it.
+#[derive(Clone, Debug, Serialize)]
+pub struct Preview{
+    pub destination: String,
+    pub message: Option<String>,
+    pub error: Option<render::TemplateError>,
+    pub length: usize,
+    pub limit: Option<usize>,
+    pub fits: bool,
+}
+
+impl Preview{
+    /// Renders `template`, or the saved one, with an episode already
+    /// announced or with a sample one.
+    pub async fn new(pool: &SqlitePool, destination: &str, template: Option<&str>, guid: Option<&str>) -> Result<Preview, Error>{
+        let registry = Registry::load(pool).await?;
+        let publisher = registry.get(destination)
+            .ok_or_else(|| format!("Unknown destination {destination}"))?;
+        let episode = match guid {
+            Some(guid) => Publication::get(pool, Some(guid), None)
+                .await?
+                .into_iter()
+                .next()
+                .map(|publication| publication.episode)
+                .ok_or_else(|| format!("Unknown episode {guid}"))?,
+            None => Episode::sample(),
+        };
+        let template = template.unwrap_or(publisher.template());
+        let limit = publisher.limit();
+        Ok(match render::check(template, &episode.context()) {
+            Ok(message) => {
+                let length = publisher.length(&message);
+                Preview{
+                    destination: destination.to_string(),
+                    message: Some(message),
+                    error: None,
+                    length,
+                    limit,
+                    fits: limit.is_none_or(|limit| length <= limit),
+                }
+            },
+            Err(error) => Preview{
+                destination: destination.to_string(),
+                message: None,
+                error: Some(error),
+                length: 0,
+                limit,
+                fits: false,
+            },
+        })
+    }
+}
+
+/// Every destination podmixer knows how to publish to.
+pub struct Registry{
+    publishers: Vec<Box<dyn Publisher>>,
+}
+
+impl Registry {
+    pub async fn load(pool: &SqlitePool) -> Result<Registry, Error>{
+        debug!("Load publishers");
+        let publishers: Vec<Box<dyn Publisher>> = vec![
+            Box::new(Telegram::get(pool).await?),
+            Box::new(Twitter::get(pool).await?),
+            Box::new(Mastodon::get(pool).await?),
+            Box::new(Bluesky::get(pool).await?),
+            Box::new(Discord::get(pool).await?),
+            Box::new(Slack::get(pool).await?),
+            Box::new(Matrix::get(pool).await?),
+            Box::new(Newsletter::get(pool).await?),
+        ];
+        Ok(Self{ publishers })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Publisher>{
+        self.publishers.iter()
+            .find(|publisher| publisher.name() == name)
+            .map(|publisher| publisher.as_ref())
+    }
+
+    pub fn all(&self) -> impl Iterator<Item = &dyn Publisher>{
+        self.publishers.iter().map(|publisher| publisher.as_ref())
+    }
+
+    pub fn active(&self) -> impl Iterator<Item = &dyn Publisher>{
+        self.all().filter(|publisher| publisher.is_active())
+    }
+
+    /// Prepares the active publishers, disabling the ones that fail.
+    pub async fn prepare(&mut self, pool: &SqlitePool){
+        let mut failed = Vec::new();
+        for publisher in self.publishers.iter_mut().filter(|publisher| publisher.is_active()) {
+            let result = match publisher.validate() {
+                Ok(()) => publisher.prepare(pool).await,
+                Err(e) => Err(e),
+            };
+            if let Err(error) = result {
+                util::log_error(&format!("Could NOT prepare {}", publisher.name()), &error);
+                failed.push(publisher.name());
+            }
+        }
+        self.publishers.retain(|publisher| !failed.contains(&publisher.name()));
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::Preview;
+    use crate::models::mock;
+
+    #[tokio::test]
+    async fn test_preview(){
+        let pool = mock::pool().await;
+        let preview = Preview::new(&pool, "telegram", None, None).await.unwrap();
+        assert!(preview.error.is_none());
+        assert!(preview.fits);
+        assert_eq!(preview.limit, Some(1024));
+        let preview = Preview::new(&pool, "telegram", Some("<b>{{ title }}</b>"), None).await.unwrap();
+        assert_eq!(preview.length, "Episode 1. Getting started with podmixer".len());
+        let preview = Preview::new(&pool, "twitter", Some("{{ title }}\n{{ link | nope }}"), None).await.unwrap();
+        let error = preview.error.unwrap();
+        assert_eq!((error.line, error.column), (Some(2), Some(11)));
+        assert!(!preview.fits);
+        let long = "{% for i in range(30) %}{{ title }} {% endfor %}";
+        let preview = Preview::new(&pool, "twitter", Some(long), None).await.unwrap();
+        assert!(preview.length > 280 && !preview.fits);
+        assert!(Preview::new(&pool, "twitter", None, Some("missing")).await.is_err());
+        assert!(Preview::new(&pool, "myspace", None, None).await.is_err());
+    }
+}
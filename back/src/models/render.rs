@@ -0,0 +1,290 @@
+use std::{fmt::{self, Write}, sync::OnceLock};
+use chrono::{DateTime, Datelike, format::{Fixed, Item, StrftimeItems}};
+use minijinja::{Environment, ErrorKind, Value};
+use serde::Serialize;
+use tracing::debug;
+use super::{Error, util};
+
+const MONTHS_ES: [&str; 12] = ["enero", "febrero", "marzo", "abril", "mayo", "junio", "julio",
+    "agosto", "septiembre", "octubre", "noviembre", "diciembre"];
+const WEEKDAYS_ES: [&str; 7] = ["lunes", "martes", "miércoles", "jueves", "viernes", "sábado", "domingo"];
+const MONTHS_EN: [&str; 12] = ["January", "February", "March", "April", "May", "June", "July",
+    "August", "September", "October", "November", "December"];
+const WEEKDAYS_EN: [&str; 7] = ["Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday", "Sunday"];
+
+static ENVIRONMENT: OnceLock<Environment<'static>> = OnceLock::new();
+static DEBUG_ENVIRONMENT: OnceLock<Environment<'static>> = OnceLock::new();
+
+fn new_environment() -> Environment<'static> {
+    let mut env = Environment::new();
+    env.add_filter("truncate", truncate);
+    env.add_filter("truncate_words", truncate_words);
+    env.add_filter("hashtags", hashtags);
+    env.add_filter("date", date);
+    env.add_filter("duration", duration);
+    env.add_filter("escape_html", escape_html);
+    env
+}
+
+/// Environment shared by every publisher, with the custom filters.
+pub fn environment() -> &'static Environment<'static> {
+    ENVIRONMENT.get_or_init(new_environment)
+}
+
+pub fn render(template: &str, ctx: &Value) -> Result<String, Error> {
+    Ok(environment().render_str(template, ctx)?)
+}
+
+/// Where and why a template does not work.
+#[derive(Clone, Debug, Serialize)]
+pub struct TemplateError {
+    pub message: String,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => write!(f, "line {line}, column {column}: {}", self.message),
+            (Some(line), None) => write!(f, "line {line}: {}", self.message),
+            _ => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// Compiles and renders the template, telling where it fails.
+pub fn check(template: &str, ctx: &Value) -> Result<String, TemplateError> {
+    // Only the debug environment keeps the position of the errors
+    let env = DEBUG_ENVIRONMENT.get_or_init(|| {
+        let mut env = new_environment();
+        env.set_debug(true);
+        env
+    });
+    env.render_str(template, ctx).map_err(|error| {
+        let column = error.range().map(|range| {
+            let start = range.start.min(template.len());
+            let line_start = template[..start].rfind('\n').map(|index| index + 1).unwrap_or(0);
+            template[line_start..start].chars().count() + 1
+        });
+        TemplateError {
+            message: match error.detail() {
+                Some(detail) => format!("{}: {detail}", error.kind()),
+                None => error.kind().to_string(),
+            },
+            line: error.line(),
+            column,
+        }
+    })
+}
+
+pub fn truncate(value: String, length: usize) -> String {
+    debug!("truncate");
+    match value.char_indices().nth(length) {
+        Some((idx, _)) => value[..idx].to_string(),
+        None => value,
+    }
+}
+
+/// Cuts at a word boundary and ends with an ellipsis.
+pub fn truncate_words(value: String, length: usize) -> String {
+    util::truncate_words(&value, length)
+}
+
+/// Hashtags from a list of keywords, or from a comma separated string:
+/// `open source` becomes `#OpenSource`.
+pub fn hashtags(value: Value, max: Option<usize>) -> String {
+    let keywords: Vec<String> = match value.as_str() {
+        Some(keywords) => keywords.split(',').map(|keyword| keyword.to_string()).collect(),
+        None => value.try_iter()
+            .map(|keywords| keywords.map(|keyword| keyword.to_string()).collect())
+            .unwrap_or_default(),
+    };
+    let mut tags: Vec<String> = Vec::new();
+    for keyword in keywords {
+        let tag: String = keyword
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(|word| {
+                let mut chars = word.chars();
+                chars.next()
+                    .map(|first| first.to_uppercase().chain(chars).collect())
+                    .unwrap_or_default()
+            })
+            .collect::<Vec<String>>()
+            .concat();
+        let tag = format!("#{tag}");
+        if tag.len() > 1 && !tags.iter().any(|other| other.to_lowercase() == tag.to_lowercase()) {
+            tags.push(tag);
+        }
+    }
+    tags.truncate(max.unwrap_or(usize::MAX));
+    tags.join(" ")
+}
+
+/// Formats an RFC 3339 or RFC 2822 date, with the month and day names in
+/// the locale, `es` or `en`. An invalid format is an error of the template.
+pub fn date(value: Option<String>, format: Option<String>, locale: Option<String>) -> Result<String, minijinja::Error> {
+    let format = format.unwrap_or("%d/%m/%Y".to_string());
+    let items: Vec<Item> = StrftimeItems::new(&format).collect();
+    if items.contains(&Item::Error) {
+        return Err(minijinja::Error::new(ErrorKind::InvalidOperation,
+            format!("invalid date format {format}")));
+    }
+    let Some(value) = value else {
+        return Ok(String::new());
+    };
+    let Some(datetime) = DateTime::parse_from_rfc3339(&value)
+        .or_else(|_| DateTime::parse_from_rfc2822(&value))
+        .ok() else {
+        return Ok(value);
+    };
+    let (months, weekdays) = match locale.as_deref().unwrap_or("en").get(..2) {
+        Some("es") => (MONTHS_ES, WEEKDAYS_ES),
+        _ => (MONTHS_EN, WEEKDAYS_EN),
+    };
+    let month = months[datetime.month0() as usize];
+    let weekday = weekdays[datetime.weekday().num_days_from_monday() as usize];
+    let short = |name: &str| name.chars().take(3).collect::<String>();
+    // The names in the locale, the rest as chrono formats it
+    let mut output = String::new();
+    for item in items {
+        let written = match item {
+            Item::Fixed(Fixed::LongMonthName) => output.write_str(month),
+            Item::Fixed(Fixed::ShortMonthName) => output.write_str(&short(month)),
+            Item::Fixed(Fixed::LongWeekdayName) => output.write_str(weekday),
+            Item::Fixed(Fixed::ShortWeekdayName) => output.write_str(&short(weekday)),
+            item => write!(output, "{}", datetime.format_with_items(std::iter::once(item))),
+        };
+        written.map_err(|_| minijinja::Error::new(ErrorKind::InvalidOperation,
+            format!("can not format the date with {format}")))?;
+    }
+    Ok(output)
+}
+
+/// Seconds as `1 h 5 min`, `45 min` or `30 s`.
+pub fn duration(seconds: Option<u64>) -> String {
+    let Some(seconds) = seconds else {
+        return String::new();
+    };
+    let (hours, minutes) = (seconds / 3600, seconds % 3600 / 60);
+    match (hours, minutes) {
+        (0, 0) => format!("{seconds} s"),
+        (0, minutes) => format!("{minutes} min"),
+        (hours, 0) => format!("{hours} h"),
+        (hours, minutes) => format!("{hours} h {minutes} min"),
+    }
+}
+
+/// For the templates sent as Telegram html.
+pub fn escape_html(value: String) -> String {
+    util::escape_html(&value)
+}
+
+#[allow(unused)]
+pub fn truncate2(value: String, length: usize) -> String {
+    debug!("truncate");
+    let mut cloned = value.clone();
+    cloned.truncate(length);
+    cloned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use minijinja::context;
+
+    #[test]
+    fn truncate_test_0() {
+        let prueba = "1234567890".to_string();
+        let result = truncate(prueba.clone(), 100);
+        assert_eq!(prueba, result);
+    }
+    #[test]
+    fn truncate_test_1() {
+        let prueba = "1234567890".to_string();
+        let result = truncate(prueba.clone(), 1);
+        assert_eq!("1".to_string(), result);
+    }
+    #[test]
+    fn truncate_test_2() {
+        let prueba = "".to_string();
+        let result = truncate(prueba.clone(), 10);
+        assert_eq!(prueba, result);
+    }
+    #[test]
+    fn truncate_test_3() {
+        let prueba = "".to_string();
+        let result = truncate(prueba.clone(), 0);
+        assert_eq!(prueba, result);
+    }
+
+    #[test]
+    fn truncate_test_4() {
+        let prueba = "1234567890".to_string();
+        let result = truncate2(prueba.clone(), 100);
+        assert_eq!(prueba, result);
+    }
+    #[test]
+    fn truncate_test_5() {
+        let prueba = "1234567890".to_string();
+        let result = truncate2(prueba.clone(), 1);
+        assert_eq!("1".to_string(), result);
+    }
+    #[test]
+    fn truncate_test_6() {
+        let prueba = "".to_string();
+        let result = truncate2(prueba.clone(), 10);
+        assert_eq!(prueba, result);
+    }
+    #[test]
+    fn truncate_test_7() {
+        let prueba = "".to_string();
+        let result = truncate2(prueba.clone(), 0);
+        assert_eq!(prueba, result);
+    }
+    #[test]
+    fn check_test() {
+        let ctx = context!(title => "Título");
+        assert_eq!(check("{{ title }}", &ctx).unwrap(), "Título");
+        let error = check("{{ title }}\n  {{ title | nope }}", &ctx).unwrap_err();
+        assert_eq!(error.line, Some(2));
+        assert_eq!(error.column, Some(14));
+        assert!(error.message.contains("nope"));
+        let error = check("{{ title }}\n{% if title %}", &ctx).unwrap_err();
+        assert_eq!(error.line, Some(2));
+        assert!(error.to_string().starts_with("line 2"));
+    }
+
+    #[test]
+    fn filters_test() {
+        let episode = crate::models::Episode::sample();
+        let ctx = episode.context();
+        assert_eq!(render("{{ keywords | hashtags }}", &ctx).unwrap(), "#Linux #OpenSource");
+        assert_eq!(render("{{ 'rust, Rust,  podcast ' | hashtags(1) }}", &ctx).unwrap(), "#Rust");
+        assert_eq!(render("{{ duration | duration }}", &ctx).unwrap(), "30 min");
+        assert_eq!(duration(Some(3900)), "1 h 5 min");
+        assert_eq!(duration(Some(45)), "45 s");
+        let date = "{{ '2025-03-07T10:00:00+01:00' | date('%A %d de %B', 'es_ES') }}";
+        assert_eq!(render(date, &ctx).unwrap(), "viernes 07 de marzo");
+        assert_eq!(render("{{ 'Fri, 07 Mar 2025 10:00:00 GMT' | date('%b %d') }}", &ctx).unwrap(), "Mar 07");
+        assert_eq!(render("{{ nothing | date }}", &ctx).unwrap(), "");
+        assert!(render("{{ pub_date | date('%Q') }}", &ctx).is_err());
+        assert!(check("{{ pub_date | date('%d %') }}", &ctx).is_err());
+        let escaped = "{{ '2025-03-07T10:00:00+01:00' | date('%%B %B %%A %a', 'es') }}";
+        assert_eq!(render(escaped, &ctx).unwrap(), "%B marzo %A vie");
+        assert_eq!(render("{{ 'a b&c' | urlencode }}", &ctx).unwrap(), "a%20b%26c");
+        assert_eq!(render("{{ '<b>&</b>' | escape_html }}", &ctx).unwrap(), "&lt;b&gt;&amp;&lt;/b&gt;");
+        assert_eq!(render("{{ 'uno dos tres' | truncate_words(9) }}", &ctx).unwrap(), "uno dos…");
+        assert_eq!(render("{{ mix }} {{ season_number }}x{{ episode_number }}", &ctx).unwrap(), "Podmixer 1x1");
+    }
+
+    #[test]
+    fn render_test() {
+        let ctx = context!(title => "Título", description => "1234567890");
+        let result = render("{{title}}: {{description|truncate(3)}}", &ctx).unwrap();
+        assert_eq!("Título: 123", result);
+    }
+}
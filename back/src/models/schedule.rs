@@ -0,0 +1,206 @@
+use std::str::FromStr;
+
+use serde::{Serialize, Deserialize};
+use sqlx::{sqlite::{SqlitePool, SqliteRow}, query, Row};
+use chrono::{DateTime, Days, Duration, NaiveDate, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use tracing::debug;
+use super::Error;
+
+/// When a destination may post. Without a row it posts right away.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Schedule{
+    #[serde(default)]
+    pub destination: String,
+    /// IANA name, like `Europe/Madrid`
+    pub timezone: String,
+    /// Posting window in the timezone, it may cross midnight
+    pub window_start: Option<NaiveTime>,
+    pub window_end: Option<NaiveTime>,
+    /// Minutes between two posts, 0 for no limit
+    pub min_interval: i64,
+    /// Posts per day in the timezone, 0 for no limit
+    pub max_per_day: i64,
+}
+
+impl Schedule{
+    pub fn new(destination: &str) -> Self{
+        Self{
+            destination: destination.to_string(),
+            timezone: "UTC".to_string(),
+            window_start: None,
+            window_end: None,
+            min_interval: 0,
+            max_per_day: 0,
+        }
+    }
+
+    fn from_row(row: SqliteRow) -> Self{
+        Self{
+            destination: row.get("destination"),
+            timezone: row.get("timezone"),
+            window_start: row.get("window_start"),
+            window_end: row.get("window_end"),
+            min_interval: row.get("min_interval"),
+            max_per_day: row.get("max_per_day"),
+        }
+    }
+
+    pub async fn get(pool: &SqlitePool, destination: &str) -> Result<Schedule, Error>{
+        debug!("get_schedule {destination}");
+        let sql = "SELECT * FROM schedules WHERE destination = $1";
+        Ok(query(sql)
+            .bind(destination)
+            .map(Self::from_row)
+            .fetch_optional(pool)
+            .await?
+            .unwrap_or_else(|| Self::new(destination)))
+    }
+
+    pub async fn set(pool: &SqlitePool, schedule: &Schedule) -> Result<Schedule, Error>{
+        debug!("save_schedule, {:?}", schedule);
+        schedule.validate()?;
+        let sql = "INSERT INTO schedules (destination, timezone, window_start, window_end,
+                   min_interval, max_per_day, updated_at) VALUES ($1, $2, $3, $4, $5, $6, $7)
+                   ON CONFLICT(destination) DO UPDATE SET
+                   timezone = excluded.timezone,
+                   window_start = excluded.window_start,
+                   window_end = excluded.window_end,
+                   min_interval = excluded.min_interval,
+                   max_per_day = excluded.max_per_day,
+                   updated_at = excluded.updated_at
+                   RETURNING *";
+        Ok(query(sql)
+            .bind(&schedule.destination)
+            .bind(&schedule.timezone)
+            .bind(schedule.window_start)
+            .bind(schedule.window_end)
+            .bind(schedule.min_interval)
+            .bind(schedule.max_per_day)
+            .bind(Utc::now())
+            .map(Self::from_row)
+            .fetch_one(pool)
+            .await?)
+    }
+
+    pub fn validate(&self) -> Result<(), Error>{
+        self.tz()?;
+        match (self.window_start, self.window_end) {
+            (Some(start), Some(end)) if start == end => Err("The window can not start and end at the same time".into()),
+            (Some(_), None) | (None, Some(_)) => Err("The window needs a start and an end".into()),
+            _ if self.min_interval < 0 => Err("The minimum interval can not be negative".into()),
+            _ if self.max_per_day < 0 => Err("The maximum per day can not be negative".into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn tz(&self) -> Result<Tz, Error>{
+        Tz::from_str(&self.timezone)
+            .map_err(|_| format!("Unknown timezone {}", self.timezone).into())
+    }
+
+    /// First moment from `now` when it can post, given the times of the
+    /// last posts in the destination.
+    pub fn next_slot(&self, now: DateTime<Utc>, sent: &[DateTime<Utc>]) -> DateTime<Utc>{
+        let tz = self.tz().unwrap_or(Tz::UTC);
+        let mut slot = now;
+        // Every rule only moves the slot forward, so it settles quickly
+        loop {
+            let previous = slot;
+            if let (true, Some(last)) = (self.min_interval > 0, sent.iter().max()) {
+                slot = slot.max(*last + Duration::minutes(self.min_interval));
+            }
+            if let (Some(start), Some(end)) = (self.window_start, self.window_end) {
+                let local = slot.with_timezone(&tz);
+                let time = local.time();
+                let inside = if start < end {
+                    start <= time && time < end
+                } else {
+                    start <= time || time < end
+                };
+                if !inside {
+                    let day = if time < start {
+                        local.date_naive()
+                    } else {
+                        local.date_naive() + Days::new(1)
+                    };
+                    slot = at(&tz, day, start);
+                }
+            }
+            if self.max_per_day > 0 {
+                let day = slot.with_timezone(&tz).date_naive();
+                let count = sent.iter()
+                    .filter(|sent| sent.with_timezone(&tz).date_naive() == day)
+                    .count();
+                if count as i64 >= self.max_per_day {
+                    slot = at(&tz, day + Days::new(1), NaiveTime::MIN);
+                }
+            }
+            if slot == previous {
+                return slot;
+            }
+        }
+    }
+}
+
+/// The time of the day in the timezone. When it does not exist, because of
+/// a daylight saving change, the first moment after it.
+fn at(tz: &Tz, day: NaiveDate, time: NaiveTime) -> DateTime<Utc>{
+    let mut local = day.and_time(time);
+    loop {
+        if let Some(datetime) = tz.from_local_datetime(&local).earliest() {
+            return datetime.with_timezone(&Utc);
+        }
+        local += Duration::minutes(15);
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::Schedule;
+    use chrono::{NaiveTime, TimeZone, Utc};
+
+    #[test]
+    fn test_next_slot(){
+        let now = Utc.with_ymd_and_hms(2025, 7, 1, 5, 0, 0).unwrap();
+        let mut schedule = Schedule::new("telegram");
+        assert_eq!(schedule.next_slot(now, &[now]), now);
+        // 08:00 in Madrid is 06:00 UTC in summer
+        schedule.timezone = "Europe/Madrid".to_string();
+        schedule.window_start = NaiveTime::from_hms_opt(8, 0, 0);
+        schedule.window_end = NaiveTime::from_hms_opt(22, 0, 0);
+        let opening = Utc.with_ymd_and_hms(2025, 7, 1, 6, 0, 0).unwrap();
+        assert_eq!(schedule.next_slot(now, &[]), opening);
+        let night = Utc.with_ymd_and_hms(2025, 7, 1, 21, 0, 0).unwrap();
+        assert_eq!(schedule.next_slot(night, &[]), Utc.with_ymd_and_hms(2025, 7, 2, 6, 0, 0).unwrap());
+        schedule.min_interval = 30;
+        let last = Utc.with_ymd_and_hms(2025, 7, 1, 6, 10, 0).unwrap();
+        assert_eq!(schedule.next_slot(last, &[last]), Utc.with_ymd_and_hms(2025, 7, 1, 6, 40, 0).unwrap());
+        schedule.max_per_day = 2;
+        let sent = [opening, Utc.with_ymd_and_hms(2025, 7, 1, 7, 0, 0).unwrap()];
+        let later = Utc.with_ymd_and_hms(2025, 7, 1, 12, 0, 0).unwrap();
+        assert_eq!(schedule.next_slot(later, &sent), Utc.with_ymd_and_hms(2025, 7, 2, 6, 0, 0).unwrap());
+    }
+
+    #[test]
+    fn test_window_over_midnight(){
+        let mut schedule = Schedule::new("telegram");
+        schedule.window_start = NaiveTime::from_hms_opt(22, 0, 0);
+        schedule.window_end = NaiveTime::from_hms_opt(2, 0, 0);
+        let inside = Utc.with_ymd_and_hms(2025, 7, 1, 1, 0, 0).unwrap();
+        assert_eq!(schedule.next_slot(inside, &[]), inside);
+        let outside = Utc.with_ymd_and_hms(2025, 7, 1, 12, 0, 0).unwrap();
+        assert_eq!(schedule.next_slot(outside, &[]), Utc.with_ymd_and_hms(2025, 7, 1, 22, 0, 0).unwrap());
+    }
+
+    #[test]
+    fn test_validate(){
+        let mut schedule = Schedule::new("telegram");
+        assert!(schedule.validate().is_ok());
+        schedule.timezone = "Mars/Olympus".to_string();
+        assert!(schedule.validate().is_err());
+        schedule.timezone = "UTC".to_string();
+        schedule.window_start = NaiveTime::from_hms_opt(8, 0, 0);
+        assert!(schedule.validate().is_err());
+    }
+}
@@ -0,0 +1,220 @@
+use async_trait::async_trait;
+use reqwest::{Client, StatusCode};
+use serde::{Serialize, Deserialize};
+use serde_json::{Value, json};
+use tracing::{debug, error};
+use super::{Error, Episode, Publisher, Receipt, util};
+use sqlx::sqlite::SqlitePool;
+use crate::models::Param;
+
+// Limit of the text of a section block
+const MAX_SECTION: usize = 3000;
+const MAX_DESCRIPTION: usize = 300;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Slack{
+    pub active: bool,
+    pub webhooks: Vec<String>,
+    pub template: String,
+    /// Channel artwork, used when the episode has none
+    #[serde(skip)]
+    pub image_url: String,
+}
+
+impl Slack{
+    pub fn new(active: bool, webhooks: Vec<String>, template: String,
+               image_url: String) -> Self{
+        Self{
+            active,
+            webhooks,
+            template,
+            image_url,
+        }
+    }
+
+    pub async fn get(pool: &SqlitePool) -> Result<Slack, Error> {
+        debug!("get_slack");
+        let active = Param::get(pool, "slack_active").await? == "TRUE";
+        let webhooks = util::lines(&Param::get(pool, "slack_webhooks").await?);
+        let template = Param::get(pool, "slack_template").await?;
+        let image_url = Param::get(pool, "feed_image_url").await?;
+        Ok(Slack::new(active, webhooks, template, image_url))
+    }
+
+    pub async fn set(pool: &SqlitePool, slack: &Slack) -> Result<Slack, Error> {
+        debug!("save_slack, {:?}", slack);
+        Param::set(pool, "slack_active", &slack.active.to_string().to_uppercase()).await?;
+        Param::set(pool, "slack_webhooks", &slack.webhooks.join("\n")).await?;
+        Param::set(pool, "slack_template", &slack.template).await?;
+        Self::get(pool).await
+    }
+
+    /// Message with Block Kit blocks for the episode. `text` is the
+    /// fallback used in notifications.
+    pub fn payload(&self, message: &str, episode: &Episode) -> Value{
+        let message = util::truncate_words(message, MAX_SECTION);
+        let mut card = format!("*{}*", escape(&episode.title));
+        if !episode.link.is_empty() {
+            card = format!("*<{}|{}>*", episode.link, escape(&episode.title));
+        }
+        if !episode.description.is_empty() {
+            card.push('\n');
+            card.push_str(&escape(&util::truncate_words(&episode.description, MAX_DESCRIPTION)));
+        }
+        let mut episode_block = json!({
+            "type": "section",
+            "text": {"type": "mrkdwn", "text": card},
+        });
+        let image = episode.image.as_deref().unwrap_or(&self.image_url);
+        if !image.is_empty() {
+            episode_block["accessory"] = json!({
+                "type": "image",
+                "image_url": image,
+                "alt_text": episode.podcast,
+            });
+        }
+        let mut blocks = vec![
+            json!({
+                "type": "section",
+                "text": {"type": "mrkdwn", "text": escape(&message)},
+            }),
+            episode_block,
+        ];
+        if let Some(pub_date) = episode.pub_date {
+            // Slack shows the date in the timezone of the reader
+            let date = format!("<!date^{}^{{date_short_pretty}}|{}>",
+                pub_date.timestamp(), pub_date.format("%Y-%m-%d"));
+            blocks.push(json!({
+                "type": "context",
+                "elements": [{"type": "mrkdwn", "text": date}],
+            }));
+        }
+        json!({
+            "text": message,
+            "blocks": blocks,
+        })
+    }
+
+    pub async fn post(&self, webhook: &str, payload: &Value) -> Result<(), Error>{
+        debug!("post: {payload}");
+        Client::new()
+            .post(webhook)
+            .json(payload)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Escapes the control characters of Slack mrkdwn.
+fn escape(text: &str) -> String{
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[async_trait]
+impl Publisher for Slack{
+    fn name(&self) -> &'static str{
+        "slack"
+    }
+
+    fn is_active(&self) -> bool{
+        self.active
+    }
+
+    fn template(&self) -> &str{
+        &self.template
+    }
+
+    fn limit(&self) -> Option<usize>{
+        Some(MAX_SECTION)
+    }
+
+    fn validate(&self) -> Result<(), Error>{
+        if self.webhooks.is_empty() {
+            return Err("Slack has no webhooks".into());
+        }
+        Ok(())
+    }
+
+    /// Incoming webhooks do not return the message, so it can not be edited
+    /// nor deleted later.
+    async fn publish(&self, message: &str, episode: &Episode) -> Result<Receipt, Error>{
+        let payload = self.payload(message, episode);
+        let mut failed = 0;
+        for webhook in self.webhooks.iter() {
+            if let Err(e) = self.post(webhook, &payload).await {
+                error!("Could NOT post to Slack webhook: {e}");
+                failed += 1;
+            }
+        }
+        // Retrying would post it again in the webhooks that worked
+        if failed == self.webhooks.len() {
+            return Err("All the Slack webhooks failed".into());
+        }
+        Ok(Receipt::default())
+    }
+
+    async fn test_connection(&self) -> Result<(), Error>{
+        // Incoming webhooks can not be read, but an empty message is
+        // rejected as a bad request only when the webhook exists
+        for webhook in self.webhooks.iter() {
+            let response = Client::new()
+                .post(webhook)
+                .json(&json!({}))
+                .send()
+                .await?;
+            if response.status() != StatusCode::BAD_REQUEST {
+                response.error_for_status()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::Slack;
+    use crate::models::{Episode, Publisher, mock};
+    use axum::{Json, Router, routing, extract::State};
+    use chrono::{TimeZone, Utc};
+    use serde_json::{Value, json};
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn slack_publish(){
+        let payloads: Arc<Mutex<Vec<Value>>> = Arc::new(Mutex::new(Vec::new()));
+        let router = Router::new()
+            .route("/services/{id}", routing::post(
+                |State(payloads): State<Arc<Mutex<Vec<Value>>>>, Json(payload): Json<Value>| async move {
+                    payloads.lock().unwrap().push(payload);
+                    "ok"
+                }))
+            .with_state(payloads.clone());
+        let url = mock::serve(router).await;
+        let slack = Slack::new(true, vec![format!("{url}/services/1")],
+            "".to_string(), "https://example.com/cover.jpg".to_string());
+        let episode = Episode{
+            podcast: "Podcast".to_string(),
+            title: "Q&A <live>".to_string(),
+            description: "Description".to_string(),
+            link: "https://example.com/1".to_string(),
+            pub_date: Some(Utc.with_ymd_and_hms(2025, 2, 28, 15, 8, 58).unwrap()),
+            ..Default::default()
+        };
+        slack.publish("New episode", &episode).await.unwrap();
+        let payloads = payloads.lock().unwrap();
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(payloads[0]["text"], "New episode");
+        let blocks = &payloads[0]["blocks"];
+        assert_eq!(blocks[1]["text"]["text"], "*<https://example.com/1|Q&amp;A &lt;live&gt;>*\nDescription");
+        assert_eq!(blocks[1]["accessory"], json!({
+            "type": "image",
+            "image_url": "https://example.com/cover.jpg",
+            "alt_text": "Podcast",
+        }));
+        assert_eq!(blocks[2]["elements"][0]["text"], "<!date^1740755338^{date_short_pretty}|2025-02-28>");
+    }
+}
@@ -0,0 +1,233 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{sqlite::{SqlitePool, SqliteRow}, query, Row};
+use chrono::{DateTime, Utc, Duration};
+use reqwest::Client;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::{Sha256, Sha384, Sha512};
+use tracing::{debug, info};
+use super::{Error, Feed, CompletePodcast, util};
+
+/// Lease asked to the hubs, and assumed when they do not tell theirs
+pub const LEASE_SECONDS: i64 = 10 * 24 * 60 * 60;
+
+/// A WebSub subscription of podmixer to the hub advertised by a source.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Subscription{
+    pub id: i64,
+    pub podcast_id: i64,
+    pub hub: String,
+    pub topic: String,
+    #[serde(skip_serializing)]
+    pub secret: String,
+    /// Secret of a renewal the hub has not verified yet
+    #[serde(skip_serializing)]
+    pub pending_secret: Option<String>,
+    pub active: bool,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Subscription{
+    fn from_row(row: SqliteRow) -> Self{
+        Self{
+            id: row.get("id"),
+            podcast_id: row.get("podcast_id"),
+            hub: row.get("hub"),
+            topic: row.get("topic"),
+            secret: row.get("secret"),
+            pending_secret: row.get("pending_secret"),
+            active: row.get("active"),
+            expires_at: row.get("expires_at"),
+            created_at: row.get("created_at"),
+            updated_at: row.get("updated_at"),
+        }
+    }
+
+    pub async fn get_by_podcast(pool: &SqlitePool, podcast_id: i64) -> Result<Option<Subscription>, sqlx::error::Error>{
+        let sql = "SELECT * FROM subscriptions WHERE podcast_id = $1";
+        query(sql)
+            .bind(podcast_id)
+            .map(Self::from_row)
+            .fetch_optional(pool)
+            .await
+    }
+
+    async fn create_or_update(pool: &SqlitePool, podcast_id: i64, hub: &str, topic: &str, secret: &str) -> Result<Subscription, sqlx::error::Error>{
+        // The current secret stays until the hub verifies the new one, the
+        // content it pushes meanwhile is still signed with it
+        let sql = "INSERT INTO subscriptions (podcast_id, hub, topic, secret, pending_secret, active, updated_at)
+            VALUES ($1, $2, $3, $4, $4, FALSE, $5)
+            ON CONFLICT(podcast_id) DO UPDATE SET
+            hub=excluded.hub,
+            topic=excluded.topic,
+            pending_secret=excluded.pending_secret,
+            updated_at=excluded.updated_at
+            RETURNING *";
+        query(sql)
+            .bind(podcast_id)
+            .bind(hub)
+            .bind(topic)
+            .bind(secret)
+            .bind(Utc::now())
+            .map(Self::from_row)
+            .fetch_one(pool)
+            .await
+    }
+
+    pub async fn activate(pool: &SqlitePool, id: i64, lease_seconds: i64) -> Result<Subscription, sqlx::error::Error>{
+        let current_ts = Utc::now();
+        let sql = "UPDATE subscriptions SET active = TRUE, expires_at = $1,
+                   secret = COALESCE(pending_secret, secret), pending_secret = NULL,
+                   updated_at = $2 WHERE id = $3 RETURNING *";
+        query(sql)
+            .bind(current_ts + Duration::seconds(lease_seconds))
+            .bind(current_ts)
+            .bind(id)
+            .map(Self::from_row)
+            .fetch_one(pool)
+            .await
+    }
+
+    pub async fn delete(pool: &SqlitePool, id: i64) -> Result<Subscription, sqlx::error::Error>{
+        let sql = "DELETE FROM subscriptions WHERE id = $1 RETURNING *";
+        query(sql)
+            .bind(id)
+            .map(Self::from_row)
+            .fetch_one(pool)
+            .await
+    }
+
+    fn needs_renewal(&self, hub: &str, topic: &str) -> bool{
+        if self.hub != hub || self.topic != topic {
+            return true;
+        }
+        match self.expires_at {
+            Some(expires_at) => expires_at - Duration::days(1) < Utc::now(),
+            // Pending verification, give the hub some time to answer
+            None => self.updated_at + Duration::hours(1) < Utc::now(),
+        }
+    }
+
+    /// Subscribes to the hub advertised by the source, if any, when there is
+    /// no subscription yet or the current one is about to expire.
+    pub async fn ensure(pool: &SqlitePool, feed: &Feed, complete: &CompletePodcast) -> Result<(), Error>{
+        let Some((hub, topic)) = complete.hub() else {
+            return Ok(());
+        };
+        if feed.public_url.is_empty() {
+            debug!("No public url, can not subscribe to {hub}");
+            return Ok(());
+        }
+        let podcast_id = complete.podcast.id;
+        if let Some(subscription) = Self::get_by_podcast(pool, podcast_id).await? {
+            if !subscription.needs_renewal(&hub, &topic) {
+                return Ok(());
+            }
+        }
+        let secret = util::random_string(32);
+        Self::create_or_update(pool, podcast_id, &hub, &topic, &secret).await?;
+        let callback = format!("{}/api/v1/websub/{podcast_id}", feed.public_url.trim_end_matches('/'));
+        info!("Subscribe to {topic} in {hub}");
+        Client::new()
+            .post(&hub)
+            .form(&[
+                ("hub.mode", "subscribe"),
+                ("hub.topic", &topic),
+                ("hub.callback", &callback),
+                ("hub.secret", &secret),
+                ("hub.lease_seconds", &LEASE_SECONDS.to_string()),
+            ])
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Checks the `X-Hub-Signature` header (`method=signature`) of a content
+    /// distribution request, with the current secret or the one being
+    /// verified.
+    pub fn verify_signature(&self, signature: &str, content: &[u8]) -> bool{
+        std::iter::once(&self.secret)
+            .chain(self.pending_secret.iter())
+            .any(|secret| check_signature(secret, signature, content))
+    }
+}
+
+fn check_signature(secret: &str, signature: &str, content: &[u8]) -> bool{
+    let Some((method, signature)) = signature.split_once('=') else {
+        return false;
+    };
+    let Ok(signature) = hex::decode(signature) else {
+        return false;
+    };
+    let secret = secret.as_bytes();
+    match method {
+        "sha1" => Hmac::<Sha1>::new_from_slice(secret)
+            .map(|mac| mac.chain_update(content).verify_slice(&signature).is_ok())
+            .unwrap_or(false),
+        "sha256" => Hmac::<Sha256>::new_from_slice(secret)
+            .map(|mac| mac.chain_update(content).verify_slice(&signature).is_ok())
+            .unwrap_or(false),
+        "sha384" => Hmac::<Sha384>::new_from_slice(secret)
+            .map(|mac| mac.chain_update(content).verify_slice(&signature).is_ok())
+            .unwrap_or(false),
+        "sha512" => Hmac::<Sha512>::new_from_slice(secret)
+            .map(|mac| mac.chain_update(content).verify_slice(&signature).is_ok())
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::Subscription;
+    use crate::models::{Podcast, hub::sign, mock};
+    use chrono::Utc;
+
+    #[test]
+    fn test_verify_signature(){
+        let subscription = Subscription{
+            id: 1,
+            podcast_id: 1,
+            hub: "https://hub.example.com".to_string(),
+            topic: "https://example.com/feed.xml".to_string(),
+            secret: "secret".to_string(),
+            pending_secret: None,
+            active: true,
+            expires_at: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        };
+        let content = b"<rss></rss>";
+        let signature = format!("sha256={}", sign("secret", content).unwrap());
+        assert!(subscription.verify_signature(&signature, content));
+        assert!(!subscription.verify_signature(&signature, b"<rss/>"));
+        assert!(!subscription.verify_signature("sha256=zz", content));
+        assert!(!subscription.verify_signature("md5=00", content));
+    }
+
+    #[tokio::test]
+    async fn test_renewal_keeps_secret(){
+        let pool = mock::pool().await;
+        let podcast = Podcast::create(&pool, "Atareao", "https://atareao.es/feed", true, &Utc::now()).await.unwrap();
+        let hub = "https://hub.example.com";
+        let topic = "https://atareao.es/feed";
+        let first = Subscription::create_or_update(&pool, podcast.id, hub, topic, "old").await.unwrap();
+        let first = Subscription::activate(&pool, first.id, 60).await.unwrap();
+        assert_eq!(first.secret, "old");
+        assert!(first.pending_secret.is_none());
+        let renewal = Subscription::create_or_update(&pool, podcast.id, hub, topic, "new").await.unwrap();
+        assert_eq!(renewal.secret, "old");
+        let content = b"<rss></rss>";
+        for secret in ["old", "new"] {
+            let signature = format!("sha256={}", sign(secret, content).unwrap());
+            assert!(renewal.verify_signature(&signature, content));
+        }
+        let renewed = Subscription::activate(&pool, renewal.id, 60).await.unwrap();
+        assert_eq!(renewed.secret, "new");
+        let signature = format!("sha256={}", sign("old", content).unwrap());
+        assert!(!renewed.verify_signature(&signature, content));
+    }
+}
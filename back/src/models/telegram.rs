@@ -1,73 +1,318 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use reqwest::{Client, multipart};
 use serde::{Serialize, Deserialize};
-use tracing::debug;
-use super::Error;
-use sqlx::sqlite::SqlitePool;
+use serde_json::{Value, json};
+use std::collections::HashSet;
+use sqlx::{sqlite::{SqlitePool, SqliteRow}, query, Row};
+use tracing::{debug, error};
+use super::{Error, Episode, Podcast, Publisher, Receipt, render, util, validator};
 use crate::models::Param;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Telegram{
+    /// Bot API server, a local one accepts bigger files
+    #[serde(default = "default_url")]
+    pub url: String,
     pub token: String,
+    /// Default template of the chats without their own
+    pub template: String,
+    pub active: bool,
+    /// Label of the button to the episode page, empty for none
+    #[serde(default)]
+    pub listen_button: String,
+    /// Label of the button to subscribe to the feed, empty for none
+    #[serde(default)]
+    pub subscribe_button: String,
+    /// Chats allowed to send commands to the bot
+    #[serde(default)]
+    pub admin_chats: Vec<String>,
+    /// Channel artwork, used when the episode has none
+    #[serde(skip)]
+    pub image_url: String,
+    #[serde(skip)]
+    pub feed_url: String,
+    /// Chats where the episodes are announced, managed on their own
+    #[serde(skip)]
+    pub targets: Vec<Target>,
+    /// Source podcasts with their own Telegram template
+    #[serde(skip)]
+    pub overrides: HashSet<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NewTarget{
+    pub name: String,
     pub chat_id: String,
-    #[serde(default = "default_thread_id")]
+    /// Forum topic, empty for the general one
+    #[serde(default)]
     pub thread_id: String,
+    /// Empty to use the template of Telegram
+    #[serde(default)]
     pub template: String,
+    /// Source podcasts announced in the chat, all of them when empty
+    #[serde(default)]
+    pub podcasts: Vec<String>,
     pub active: bool,
 }
 
-fn default_thread_id() -> String{
-    "0".to_string()
+/// A chat, and optionally a topic, where the bot announces the episodes.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Target{
+    pub id: i64,
+    pub name: String,
+    pub chat_id: String,
+    pub thread_id: String,
+    pub template: String,
+    pub podcasts: Vec<String>,
+    pub active: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
 }
 
-const URL: &str = "https://api.telegram.org";
+/// How the audio gets to the chats.
+enum Audio{
+    /// Downloaded, to be uploaded to the first chat
+    File{
+        path: String,
+        filename: String,
+        thumbnail: Option<Vec<u8>>,
+    },
+    /// Already uploaded, sent again by its Telegram id
+    Id(String),
+    /// Too big, only the link is sent
+    Link,
+}
+
+const DEFAULT_URL: &str = "https://api.telegram.org";
+// Longest caption of an audio
+const MAX_CAPTION: usize = 1024;
+// Largest thumbnail accepted with the audio, in bytes and pixels per side
+const MAX_THUMBNAIL: usize = 200 * 1024;
+const MAX_THUMBNAIL_SIDE: u32 = 320;
+// Largest upload for the public Bot API and for a local server
+const MAX_UPLOAD: u64 = 50 * 1024 * 1024;
+const MAX_LOCAL_UPLOAD: u64 = 2000 * 1024 * 1024;
+// Kinds of message in the receipt, the text ones only have the link
+const AUDIO: &str = "audio";
+const TEXT: &str = "text";
+
+fn default_url() -> String{
+    DEFAULT_URL.to_string()
+}
+
+impl NewTarget{
+    pub fn validate(&self) -> Result<(), Error>{
+        if self.chat_id.trim().is_empty() {
+            return Err("The chat_id is empty".into());
+        }
+        if !self.thread_id.is_empty() && self.thread_id.parse::<i64>().is_err() {
+            return Err(format!("Invalid thread_id: {}", self.thread_id).into());
+        }
+        if !self.template.is_empty() {
+            render::check(&self.template, &Episode::sample().context())?;
+        }
+        Ok(())
+    }
+}
+
+impl Target{
+    fn from_row(row: SqliteRow) -> Self{
+        Self{
+            id: row.get("id"),
+            name: row.get("name"),
+            chat_id: row.get("chat_id"),
+            thread_id: row.get("thread_id"),
+            template: row.get("template"),
+            podcasts: serde_json::from_str(row.get("podcasts")).unwrap_or_default(),
+            active: row.get("active"),
+            created_at: row.get("created_at"),
+            updated_at: row.get("updated_at"),
+        }
+    }
+
+    pub async fn get(pool: &SqlitePool) -> Result<Vec<Target>, sqlx::error::Error>{
+        let sql = "SELECT * FROM telegram_targets ORDER BY id";
+        query(sql)
+            .map(Self::from_row)
+            .fetch_all(pool)
+            .await
+    }
+
+    pub async fn create(pool: &SqlitePool, target: &NewTarget) -> Result<Target, Error>{
+        target.validate()?;
+        let sql = "INSERT INTO telegram_targets (name, chat_id, thread_id, template, podcasts, active)
+                   VALUES ($1, $2, $3, $4, $5, $6) RETURNING *";
+        Ok(query(sql)
+            .bind(&target.name)
+            .bind(target.chat_id.trim())
+            .bind(&target.thread_id)
+            .bind(&target.template)
+            .bind(serde_json::to_string(&target.podcasts)?)
+            .bind(target.active)
+            .map(Self::from_row)
+            .fetch_one(pool)
+            .await?)
+    }
+
+    pub async fn update(pool: &SqlitePool, id: i64, target: &NewTarget) -> Result<Target, Error>{
+        target.validate()?;
+        let sql = "UPDATE telegram_targets SET name = $1, chat_id = $2, thread_id = $3,
+                   template = $4, podcasts = $5, active = $6, updated_at = $7
+                   WHERE id = $8 RETURNING *";
+        Ok(query(sql)
+            .bind(&target.name)
+            .bind(target.chat_id.trim())
+            .bind(&target.thread_id)
+            .bind(&target.template)
+            .bind(serde_json::to_string(&target.podcasts)?)
+            .bind(target.active)
+            .bind(Utc::now())
+            .bind(id)
+            .map(Self::from_row)
+            .fetch_one(pool)
+            .await?)
+    }
+
+    pub async fn delete(pool: &SqlitePool, id: i64) -> Result<Target, sqlx::error::Error>{
+        let sql = "DELETE FROM telegram_targets WHERE id = $1 RETURNING *";
+        query(sql)
+            .bind(id)
+            .map(Self::from_row)
+            .fetch_one(pool)
+            .await
+    }
+
+    pub fn accepts(&self, episode: &Episode) -> bool{
+        self.active && (self.podcasts.is_empty()
+            || self.podcasts.iter().any(|podcast| podcast == &episode.podcast))
+    }
+
+    /// The message rendered with the template of the chat, if it has one.
+    fn caption(&self, message: &str, episode: &Episode) -> Result<String, Error>{
+        if self.template.is_empty() {
+            Ok(message.to_string())
+        } else {
+            render::render(&self.template, &episode.context())
+        }
+    }
+
+    /// The chat and topic of the message.
+    fn params(&self) -> Vec<(&'static str, String)>{
+        let mut params = vec![("chat_id", self.chat_id.clone())];
+        if !self.thread_id.is_empty() && self.thread_id != "0" {
+            params.push(("message_thread_id", self.thread_id.clone()));
+        }
+        params
+    }
+}
 
 impl Telegram{
-    pub fn new(active: bool, token: String, chat_id: String, thread_id: String, template: String) -> Self{
+    pub fn new(active: bool, url: String, token: String, template: String) -> Self{
         Self{
             active,
+            url,
             token,
-            chat_id,
-            thread_id,
             template,
+            listen_button: String::new(),
+            subscribe_button: String::new(),
+            admin_chats: Vec::new(),
+            image_url: String::new(),
+            feed_url: String::new(),
+            targets: Vec::new(),
+            overrides: HashSet::new(),
         }
     }
 
-    pub fn is_active(&self) -> bool{
-        self.active
-    }
-
     pub async fn get(pool: &SqlitePool) -> Result<Telegram, Error> {
         debug!("get_telegram");
         let active_str = Param::get(pool, "telegram_active")
             .await?;
         let active = active_str == "TRUE";
+        let url = Param::get(pool, "telegram_url").await?;
         let token = Param::get(pool, "telegram_token").await?;
-        let chat_id = Param::get(pool, "telegram_chat_id")
-            .await?;
-        let thread_id = Param::get(pool, "telegram_thread_id")
-            .await?;
         let template = Param::get(pool, "telegram_template").await?;
-        Ok(Telegram::new(active, token, chat_id, thread_id, template))
+        let public_url = Param::get(pool, "feed_public_url").await?;
+        Ok(Telegram{
+            listen_button: Param::get(pool, "telegram_listen_button").await?,
+            subscribe_button: Param::get(pool, "telegram_subscribe_button").await?,
+            admin_chats: util::lines(&Param::get(pool, "telegram_admin_chats").await?),
+            image_url: Param::get(pool, "feed_image_url").await?,
+            feed_url: if public_url.is_empty() {
+                public_url
+            } else {
+                format!("{}/rss/long.xml", public_url.trim_end_matches('/'))
+            },
+            targets: Target::get(pool).await?,
+            overrides: Podcast::get(pool).await?
+                .into_iter()
+                .filter(|podcast| podcast.template("telegram").is_some())
+                .map(|podcast| podcast.name)
+                .collect(),
+            ..Telegram::new(active, url, token, template)
+        })
     }
     pub async fn set(pool: &SqlitePool, telegram: &Telegram) -> Result<Telegram, Error> {
         debug!("save_telegram, {:?}", telegram);
+        // A broken template would only show up with the next episode
+        render::check(&telegram.template, &Episode::sample().context())?;
         Param::set(pool, "telegram_active", &telegram.active.to_string().to_uppercase()).await?;
+        let url = telegram.url.trim_end_matches('/');
+        Param::set(pool, "telegram_url", if url.is_empty() { DEFAULT_URL } else { url }).await?;
         Param::set(pool, "telegram_token", &telegram.token).await?;
-        Param::set(pool, "telegram_chat_id", &telegram.chat_id).await?;
-        Param::set(pool, "telegram_thread_id", &telegram.thread_id).await?;
         Param::set(pool, "telegram_template", &telegram.template).await?;
+        Param::set(pool, "telegram_listen_button", &telegram.listen_button).await?;
+        Param::set(pool, "telegram_subscribe_button", &telegram.subscribe_button).await?;
+        Param::set(pool, "telegram_admin_chats", &telegram.admin_chats.join("\n")).await?;
         Self::get(pool).await
     }
 
 
-    #[allow(dead_code)]
-    pub async fn send_message(&self, message: &str) -> Result<String, Error>{
-        let url = format!("{URL}/bot{}/sendMessage", self.token);
-        let params = vec![
-            ("chat_id", self.chat_id.as_str()),
-            ("message_thread_id", self.thread_id.as_str()),
+    fn endpoint(&self, method: &str) -> String{
+        format!("{}/bot{}/{method}", self.url.trim_end_matches('/'), self.token)
+    }
+
+    pub async fn get_me(&self) -> Result<String, Error>{
+        let url = self.endpoint("getMe");
+        Client::new()
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await.map_err(|e| e.into())
+    }
+
+    /// Waits up to `timeout` seconds for the messages sent to the bot after
+    /// `offset`.
+    pub async fn get_updates(&self, offset: i64, timeout: u64) -> Result<Vec<Value>, Error>{
+        let url = self.endpoint("getUpdates");
+        let response: Value = Client::new()
+            .get(url)
+            .query(&[
+                ("offset", offset.to_string()),
+                ("timeout", timeout.to_string()),
+                ("allowed_updates", r#"["message"]"#.to_string()),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        match response["result"].as_array() {
+            Some(updates) => Ok(updates.clone()),
+            None => Err(format!("Unexpected response from Telegram: {response}").into()),
+        }
+    }
+
+    /// Answers a command in the chat where it was sent.
+    pub async fn reply(&self, chat_id: &str, message: &str) -> Result<Value, Error>{
+        let url = self.endpoint("sendMessage");
+        let params = [
+            ("chat_id", chat_id),
             ("text", message),
             ("parse_mode", "HTML"),
+            ("disable_web_page_preview", "true"),
         ];
         Client::new()
             .post(url)
@@ -75,38 +320,420 @@ impl Telegram{
             .send()
             .await?
             .error_for_status()?
-            .text()
+            .json()
+            .await
+            .map_err(|e| e.into())
+    }
+
+    pub async fn send_message(&self, target: &Target, message: &str, keyboard: Option<&Value>) -> Result<Value, Error>{
+        let url = self.endpoint("sendMessage");
+        let mut params = target.params();
+        params.push(("text", message.to_string()));
+        params.push(("parse_mode", "HTML".to_string()));
+        if let Some(keyboard) = keyboard {
+            params.push(("reply_markup", keyboard.to_string()));
+        }
+        Client::new()
+            .post(url)
+            .form(&params)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
             .await.map_err(|e| e.into())
     }
 
-    pub async fn send_audio(&self, filename: &str, filepath: &str, caption: &str) -> Result<String, Error>{
+    /// Sends the audio with the details of the episode, so it shows its
+    /// title, podcast, length and cover in the player.
+    async fn send_audio(&self, target: &Target, caption: &str, episode: &Episode, audio: &Audio) -> Result<Value, Error>{
         debug!("send_audio");
-        let url = format!("{URL}/bot{}/sendAudio", self.token);
+        let url = self.endpoint("sendAudio");
         debug!("url: {url}");
-        let file = tokio::fs::read(filepath).await?;
-        let part = multipart::Part::bytes(file)
-            .file_name(filename.to_string());
-        let form = multipart::Form::new()
-            .text("chat_id", self.chat_id.to_string())
-            .text("message_thread_id", self.thread_id.to_string())
+        let mut form = multipart::Form::new();
+        for (name, value) in target.params() {
+            form = form.text(name, value);
+        }
+        form = form
             .text("caption", caption.to_string())
-            .text("parse_mode", "HTML".to_string())
-            .part("audio", part);
+            .text("parse_mode", "HTML".to_string());
+        match audio {
+            Audio::File{path, filename, thumbnail} => {
+                form = form.part("audio", util::file_part(path, filename).await?);
+                if let Some(thumbnail) = thumbnail {
+                    // Thumbnails can only be uploaded with the audio
+                    form = form
+                        .text("thumbnail", "attach://cover")
+                        .part("cover", multipart::Part::bytes(thumbnail.clone())
+                            .file_name("cover.jpg")
+                            .mime_str("image/jpeg")?);
+                }
+            },
+            Audio::Id(file_id) => form = form.text("audio", file_id.clone()),
+            Audio::Link => return Err("There is no audio to send".into()),
+        }
+        if !episode.title.is_empty() {
+            form = form.text("title", episode.title.clone());
+        }
+        if !episode.podcast.is_empty() {
+            form = form.text("performer", episode.podcast.clone());
+        }
+        if let Some(duration) = episode.duration {
+            form = form.text("duration", duration.to_string());
+        }
+        if let Some(keyboard) = self.keyboard(episode) {
+            form = form.text("reply_markup", keyboard.to_string());
+        }
         Client::new()
             .post(url)
             .multipart(form)
             .send()
             .await?
             .error_for_status()?
-            .text()
+            .json()
+            .await.map_err(|e| e.into())
+    }
+
+    /// Announces the episode with a link to the audio.
+    async fn send_link(&self, target: &Target, caption: &str, episode: &Episode) -> Result<Value, Error>{
+        self.send_message(target, &with_link(caption, episode), self.keyboard(episode).as_ref()).await
+    }
+
+    /// Replaces the caption, keeping the buttons if any.
+    pub async fn edit_caption(&self, chat_id: &str, message_id: &str, caption: &str, keyboard: Option<&Value>) -> Result<Value, Error>{
+        debug!("edit_caption");
+        let url = self.endpoint("editMessageCaption");
+        let keyboard = keyboard.map(Value::to_string);
+        let mut params = vec![
+            ("chat_id", chat_id),
+            ("message_id", message_id),
+            ("caption", caption),
+            ("parse_mode", "HTML"),
+        ];
+        if let Some(keyboard) = &keyboard {
+            params.push(("reply_markup", keyboard));
+        }
+        Client::new()
+            .post(url)
+            .form(&params)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await.map_err(|e| e.into())
+    }
+
+    /// Replaces the text of a message sent without the audio, keeping the
+    /// buttons if any.
+    pub async fn edit_text(&self, chat_id: &str, message_id: &str, text: &str, keyboard: Option<&Value>) -> Result<Value, Error>{
+        debug!("edit_text");
+        let url = self.endpoint("editMessageText");
+        let keyboard = keyboard.map(Value::to_string);
+        let mut params = vec![
+            ("chat_id", chat_id),
+            ("message_id", message_id),
+            ("text", text),
+            ("parse_mode", "HTML"),
+        ];
+        if let Some(keyboard) = &keyboard {
+            params.push(("reply_markup", keyboard));
+        }
+        Client::new()
+            .post(url)
+            .form(&params)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
             .await.map_err(|e| e.into())
     }
+
+    pub async fn delete_message(&self, chat_id: &str, message_id: &str) -> Result<Value, Error>{
+        debug!("delete_message");
+        let url = self.endpoint("deleteMessage");
+        let params = [
+            ("chat_id", chat_id),
+            ("message_id", message_id),
+        ];
+        Client::new()
+            .post(url)
+            .form(&params)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await.map_err(|e| e.into())
+    }
+
+    /// Episode or channel cover, when Telegram accepts it as a thumbnail.
+    async fn thumbnail(&self, episode: &Episode) -> Option<Vec<u8>>{
+        let url = episode.image.as_deref().unwrap_or(&self.image_url);
+        if url.is_empty() {
+            return None;
+        }
+        let result = async {
+            let mut response = Client::new()
+                .get(url)
+                .send()
+                .await?
+                .error_for_status()?;
+            let is_jpeg = response.headers()
+                .get(reqwest::header::CONTENT_TYPE)
+                .and_then(|content_type| content_type.to_str().ok())
+                .is_some_and(|content_type| content_type.contains("jpeg"));
+            // Most covers are too big, better not to download them at all
+            if !is_jpeg || response.content_length().is_some_and(|length| length > MAX_THUMBNAIL as u64) {
+                return Ok::<_, Error>(None);
+            }
+            let mut bytes = Vec::new();
+            while let Some(chunk) = response.chunk().await? {
+                bytes.extend_from_slice(&chunk);
+                if bytes.len() > MAX_THUMBNAIL {
+                    return Ok(None);
+                }
+            }
+            match validator::image_dimensions(&bytes) {
+                Some((width, height)) if width <= MAX_THUMBNAIL_SIDE && height <= MAX_THUMBNAIL_SIDE => Ok(Some(bytes)),
+                _ => Ok(None),
+            }
+        }.await;
+        result.unwrap_or_else(|e| {
+            debug!("Could not get the thumbnail {url}: {e}");
+            None
+        })
+    }
+
+    /// The message for the chat. The template of the podcast wins over the
+    /// one of the chat, and this one over the template of Telegram.
+    fn caption(&self, target: &Target, message: &str, episode: &Episode) -> Result<String, Error>{
+        if self.overrides.contains(&episode.podcast) {
+            Ok(message.to_string())
+        } else {
+            target.caption(message, episode)
+        }
+    }
+
+    /// Buttons below the message, as configured.
+    fn keyboard(&self, episode: &Episode) -> Option<Value>{
+        let mut buttons = Vec::new();
+        if !self.listen_button.is_empty() && !episode.link.is_empty() {
+            buttons.push(json!({"text": self.listen_button, "url": episode.link}));
+        }
+        if !self.subscribe_button.is_empty() && !self.feed_url.is_empty() {
+            buttons.push(json!({"text": self.subscribe_button, "url": self.feed_url}));
+        }
+        if buttons.is_empty() {
+            None
+        } else {
+            Some(json!({"inline_keyboard": [buttons]}))
+        }
+    }
+
+    /// Largest file the Bot API server accepts.
+    fn max_upload(&self) -> u64{
+        if self.url.trim_end_matches('/') == DEFAULT_URL {
+            MAX_UPLOAD
+        } else {
+            MAX_LOCAL_UPLOAD
+        }
+    }
+
+    /// Downloads the audio once for every chat, unless it is too big. The
+    /// file is removed when the guard is dropped.
+    async fn audio(&self, episode: &Episode) -> Result<(Option<util::TempFile>, Audio), Error>{
+        let url = episode.enclosure_url.as_deref().ok_or("Not enclosure")?;
+        let size = util::content_length(url).await
+            .or(episode.enclosure_length);
+        if size.is_some_and(|size| size > self.max_upload()) {
+            debug!("{url} is too big to upload: {size:?}");
+            return Ok((None, Audio::Link));
+        }
+        let name = util::normalize(&episode.title)?;
+        let ext = util::get_extension_from_filename(url).ok_or("Not extension")?;
+        let filename = format!("{name}.{ext}");
+        let file = util::TempFile::new(&filename);
+        util::fetch_url(url, file.path()).await?;
+        if tokio::fs::metadata(file.path()).await?.len() > self.max_upload() {
+            debug!("{url} is too big to upload");
+            return Ok((Some(file), Audio::Link));
+        }
+        let audio = Audio::File{
+            path: file.path().to_string(),
+            filename,
+            thumbnail: self.thumbnail(episode).await,
+        };
+        Ok((Some(file), audio))
+    }
+
+    /// Sends the episode to the chat and returns the id of the message and
+    /// its kind. Once uploaded, the audio is sent to the next chats by its id.
+    async fn announce(&self, target: &Target, message: &str, episode: &Episode, audio: &mut Audio) -> Result<(String, &'static str), Error>{
+        let caption = self.caption(target, message, episode)?;
+        let response = if matches!(audio, Audio::Link) {
+            self.send_link(target, &caption, episode).await?
+        } else {
+            match self.send_audio(target, &caption, episode, audio).await {
+                Err(e) if is_too_large(&e) => {
+                    *audio = Audio::Link;
+                    self.send_link(target, &caption, episode).await?
+                },
+                result => result?,
+            }
+        };
+        if let (Audio::File{..}, Some(file_id)) = (&audio, response.pointer("/result/audio/file_id").and_then(|id| id.as_str())) {
+            *audio = Audio::Id(file_id.to_string());
+        }
+        let message_id = response.pointer("/result/message_id")
+            .and_then(|id| id.as_i64())
+            .ok_or("Response without message_id")?
+            .to_string();
+        let kind = if matches!(audio, Audio::Link) { TEXT } else { AUDIO };
+        Ok((message_id, kind))
+    }
+}
+
+/// Public link of the message, only for public channels and supergroups.
+fn message_url(chat_id: &str, message_id: &str) -> Option<String>{
+    if let Some(username) = chat_id.strip_prefix('@') {
+        return Some(format!("https://t.me/{username}/{message_id}"));
+    }
+    chat_id.strip_prefix("-100")
+        .map(|chat| format!("https://t.me/c/{chat}/{message_id}"))
+}
+
+/// The caption followed by a link to the audio.
+fn with_link(caption: &str, episode: &Episode) -> String{
+    let url = episode.enclosure_url.as_deref().unwrap_or_default();
+    format!("{caption}\n\n<a href=\"{}\">🎧 {}</a>",
+        util::escape_html(url), util::escape_html(&episode.title))
+}
+
+/// Chat id, message id and kind of every message of the receipt, one per
+/// line. Older receipts have no kind, they were always audios.
+fn messages(receipt: &Receipt) -> impl Iterator<Item = (&str, &str, &str)>{
+    receipt.remote_id.lines().filter_map(|line| {
+        let mut fields = line.split(' ');
+        Some((fields.next()?, fields.next()?, fields.next().unwrap_or(AUDIO)))
+    })
+}
+
+/// The server refused the upload because of its size.
+fn is_too_large(error: &Error) -> bool{
+    error.downcast_ref::<reqwest::Error>()
+        .and_then(|e| e.status())
+        .is_some_and(|status| status == reqwest::StatusCode::PAYLOAD_TOO_LARGE)
+}
+
+#[async_trait]
+impl Publisher for Telegram{
+    fn name(&self) -> &'static str{
+        "telegram"
+    }
+
+    fn is_active(&self) -> bool{
+        self.active
+    }
+
+    fn template(&self) -> &str{
+        &self.template
+    }
+
+    /// Only when one of the chats takes the podcast.
+    fn accepts(&self, episode: &Episode) -> bool{
+        self.targets.iter().any(|target| target.accepts(episode))
+    }
+
+    fn limit(&self) -> Option<usize>{
+        Some(MAX_CAPTION)
+    }
+
+    /// Telegram counts the text without the html markup.
+    fn length(&self, message: &str) -> usize{
+        let re = regex::Regex::new(r"<[^>]*>").unwrap();
+        re.replace_all(message, "")
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&amp;", "&")
+            .chars()
+            .count()
+    }
+
+    fn validate(&self) -> Result<(), Error>{
+        if self.token.is_empty() {
+            return Err("Telegram token is empty".into());
+        }
+        if !self.targets.iter().any(|target| target.active) {
+            return Err("Telegram has no active chats".into());
+        }
+        Ok(())
+    }
+
+    /// Big episodes are announced with a link to the audio instead.
+    async fn publish(&self, message: &str, episode: &Episode) -> Result<Receipt, Error>{
+        let targets: Vec<&Target> = self.targets.iter()
+            .filter(|target| target.accepts(episode))
+            .collect();
+        if targets.is_empty() {
+            return Err(format!("No Telegram chat for {}", episode.podcast).into());
+        }
+        let (_file, mut audio) = self.audio(episode).await?;
+        let mut messages = Vec::new();
+        let mut remote_url = None;
+        for target in targets.iter() {
+            match self.announce(target, message, episode, &mut audio).await {
+                Ok((message_id, kind)) => {
+                    remote_url = remote_url.or(message_url(&target.chat_id, &message_id));
+                    messages.push(format!("{} {message_id} {kind}", target.chat_id));
+                },
+                Err(e) => error!("Could NOT send to Telegram chat {}: {e}", target.name),
+            }
+        }
+        // Retrying would send it again to the chats that worked
+        if messages.is_empty() {
+            return Err("All the Telegram chats failed".into());
+        }
+        Ok(Receipt{
+            remote_id: messages.join("\n"),
+            remote_url,
+        })
+    }
+
+    /// Every chat gets the edited message as it is, even those with their
+    /// own template, so they show what the publication stores.
+    async fn edit(&self, receipt: &Receipt, message: &str, episode: &Episode) -> Result<(), Error>{
+        let keyboard = self.keyboard(episode);
+        for (chat_id, message_id, kind) in messages(receipt) {
+            if kind == TEXT {
+                self.edit_text(chat_id, message_id, &with_link(message, episode), keyboard.as_ref()).await?;
+            } else {
+                self.edit_caption(chat_id, message_id, message, keyboard.as_ref()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, receipt: &Receipt) -> Result<(), Error>{
+        for (chat_id, message_id, _) in messages(receipt) {
+            self.delete_message(chat_id, message_id).await?;
+        }
+        Ok(())
+    }
+
+    async fn test_connection(&self) -> Result<(), Error>{
+        self.get_me().await.map(|_| ())
+    }
 }
 
 #[cfg(test)]
 mod test{
-    use super::Telegram;
+    use super::{Audio, Target, Telegram};
+    use crate::models::{Episode, Podcast, Publisher, mock};
+    use axum::{Form, Json, Router, routing, body::Bytes, extract::{Path, State}};
+    use chrono::Utc;
     use dotenv::dotenv;
+    use serde_json::json;
+    use std::collections::{HashMap, HashSet};
+    use std::sync::{Arc, Mutex};
     use std::{env, str::FromStr};
     use tracing_subscriber::{
         EnvFilter,
@@ -133,11 +760,163 @@ mod test{
             .expect("Cant convert thread_id");
         let filename = "example.mp3";
         let audio = "/data/rust/podmixer/5d279930-4426-d35f-7c3b-90314d30595d.mp3";
-        let telegram = Telegram::new(true, token, chat_id, thread_id, "".to_string());
-        assert!(telegram.send_message("Prueba").await.is_ok());
-        let response = telegram.send_audio(filename, audio, "Esto es una prueba").await;
+        let telegram = Telegram::new(true, super::default_url(), token, "".to_string());
+        let target = target(1, chat_id, thread_id, "", &[]);
+        assert!(telegram.send_message(&target, "Prueba", None).await.is_ok());
+        let audio = Audio::File{
+            path: audio.to_string(),
+            filename: filename.to_string(),
+            thumbnail: None,
+        };
+        let response = telegram.send_audio(&target, "Esto es una prueba", &Episode::default(), &audio).await;
         println!("{:?}", response);
         assert!(response.is_ok());
     }
-}
 
+    fn target(id: i64, chat_id: String, thread_id: String, template: &str, podcasts: &[&str]) -> Target {
+        Target{
+            id,
+            name: format!("Chat {id}"),
+            chat_id,
+            thread_id,
+            template: template.to_string(),
+            podcasts: podcasts.iter().map(|podcast| podcast.to_string()).collect(),
+            active: true,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        }
+    }
+
+    type Requests = Arc<Mutex<Vec<(String, String)>>>;
+
+    /// Just the headers of a jpeg with its size.
+    fn jpeg(width: u16, height: u16) -> Vec<u8> {
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x04, 0x00, 0x00, 0xFF, 0xC0, 0x00, 0x11, 0x08];
+        jpeg.extend_from_slice(&height.to_be_bytes());
+        jpeg.extend_from_slice(&width.to_be_bytes());
+        jpeg
+    }
+
+    #[tokio::test]
+    async fn telegram_mock(){
+        let requests: Requests = Arc::new(Mutex::new(Vec::new()));
+        let router = Router::new()
+            .route("/audio.mp3", routing::get(|| async { vec![0u8; 16] }))
+            .route("/cover.jpg", routing::get(|| async { ([("content-type", "image/jpeg")], jpeg(300, 300)) }))
+            .route("/big.jpg", routing::get(|| async { ([("content-type", "image/jpeg")], jpeg(1400, 1400)) }))
+            .route("/heavy.jpg", routing::get(|| async { ([("content-type", "image/jpeg")], vec![0u8; 300 * 1024]) }))
+            .route("/{bot}/sendMessage", routing::post(
+                |State(requests): State<Requests>, Path(bot): Path<String>, Form(params): Form<HashMap<String, String>>| async move {
+                    requests.lock().unwrap().push((bot, params["text"].clone()));
+                    Json(json!({"ok": true, "result": {"message_id": 6}}))
+                }))
+            .route("/{bot}/sendAudio", routing::post(
+                |State(requests): State<Requests>, Path(bot): Path<String>, body: Bytes| async move {
+                    requests.lock().unwrap().push((bot, String::from_utf8_lossy(&body).to_string()));
+                    Json(json!({"ok": true, "result": {"message_id": 7, "audio": {"file_id": "file-1"}}}))
+                }))
+            .route("/{bot}/editMessageCaption", routing::post(
+                |State(requests): State<Requests>, Form(params): Form<HashMap<String, String>>| async move {
+                    requests.lock().unwrap().push(("caption".to_string(), params["caption"].clone()));
+                    Json(json!({"ok": true, "result": true}))
+                }))
+            .route("/{bot}/editMessageText", routing::post(
+                |State(requests): State<Requests>, Form(params): Form<HashMap<String, String>>| async move {
+                    requests.lock().unwrap().push(("text".to_string(), params["text"].clone()));
+                    Json(json!({"ok": true, "result": true}))
+                }))
+            .with_state(requests.clone());
+        let url = mock::serve(router).await;
+        let telegram = Telegram{
+            listen_button: "Listen".to_string(),
+            image_url: format!("{url}/cover.jpg"),
+            targets: vec![
+                target(1, "@podmixer".to_string(), "0".to_string(), "", &[]),
+                target(2, "-100123".to_string(), "5".to_string(), "{{ title }} en el grupo", &["Podcast"]),
+                target(3, "-100456".to_string(), "".to_string(), "", &["Other"]),
+            ],
+            ..Telegram::new(true, format!("{url}/"), "token".to_string(), "".to_string())
+        };
+        // Only small covers are attached as thumbnails
+        for (cover, attached) in [("cover", true), ("big", false), ("heavy", false)] {
+            let episode = Episode{image: Some(format!("{url}/{cover}.jpg")), ..Default::default()};
+            assert_eq!(telegram.thumbnail(&episode).await.is_some(), attached, "{cover}");
+        }
+        let response = telegram.send_message(&telegram.targets[0], "Prueba", None).await.unwrap();
+        assert_eq!(response["result"]["message_id"], 6);
+        let episode = Episode{
+            podcast: "Podcast".to_string(),
+            title: "Episode 1".to_string(),
+            link: "https://example.com/1".to_string(),
+            enclosure_url: Some(format!("{url}/audio.mp3")),
+            duration: Some(3723),
+            ..Default::default()
+        };
+        let receipt = telegram.publish("Esto es una prueba", &episode).await.unwrap();
+        assert_eq!(receipt.remote_id, "@podmixer 7 audio\n-100123 7 audio");
+        assert_eq!(receipt.remote_url.as_deref(), Some("https://t.me/podmixer/7"));
+        // Too big even for a local Bot API server, only the link is sent
+        let episode = Episode{
+            title: "Episode 2".to_string(),
+            enclosure_url: Some(format!("{url}/big.mp3")),
+            enclosure_length: Some(3 * 1024 * 1024 * 1024),
+            ..Default::default()
+        };
+        let big_receipt = telegram.publish("Otra prueba", &episode).await.unwrap();
+        assert_eq!(big_receipt.remote_id, "@podmixer 6 text");
+        telegram.edit(&receipt, "Editado", &episode).await.unwrap();
+        telegram.edit(&big_receipt, "Editado", &episode).await.unwrap();
+        // No chat takes the podcast, nothing to announce
+        let others = Telegram{
+            targets: vec![telegram.targets[2].clone()],
+            ..telegram.clone()
+        };
+        assert!(!others.accepts(&episode));
+        assert!(others.publish("Otra prueba", &episode).await.is_err());
+        // The template of the podcast wins over the one of the group
+        let episode = Episode{
+            podcast: "Podcast".to_string(),
+            title: "Episode 1".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(telegram.caption(&telegram.targets[1], "Del podcast", &episode).unwrap(), "Episode 1 en el grupo");
+        let overridden = Telegram{
+            overrides: HashSet::from(["Podcast".to_string()]),
+            ..telegram.clone()
+        };
+        assert_eq!(overridden.caption(&telegram.targets[1], "Del podcast", &episode).unwrap(), "Del podcast");
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 7);
+        // Every chat gets the edited text, as text if it had no audio
+        assert_eq!(requests[4], ("caption".to_string(), "Editado".to_string()));
+        assert_eq!(requests[5], ("caption".to_string(), "Editado".to_string()));
+        assert_eq!(requests[6], ("text".to_string(), format!("Editado\n\n<a href=\"{url}/big.mp3\">🎧 Episode 2</a>")));
+        // Sent to the group by its id, with the template of the group
+        assert!(requests[2].1.contains("audio\"\r\n\r\nfile-1"));
+        assert!(requests[2].1.contains("message_thread_id\"\r\n\r\n5"));
+        assert!(requests[2].1.contains("Episode 1 en el grupo"));
+        assert!(!requests[1].1.contains("message_thread_id"));
+        assert_eq!(requests[3].1, format!("Otra prueba\n\n<a href=\"{url}/big.mp3\">🎧 Episode 2</a>"));
+        assert_eq!(requests[0], ("bottoken".to_string(), "Prueba".to_string()));
+        assert_eq!(requests[1].0, "bottoken");
+        assert!(requests[1].1.contains("Esto es una prueba"));
+        assert!(requests[1].1.contains("filename=\"Episode_1.mp3\""));
+        for field in ["title\"\r\n\r\nEpisode 1", "performer\"\r\n\r\nPodcast",
+                      "duration\"\r\n\r\n3723", "thumbnail\"\r\n\r\nattach://cover",
+                      "filename=\"cover.jpg\"",
+                      r#"{"inline_keyboard":[[{"text":"Listen","url":"https://example.com/1"}]]}"#] {
+            assert!(requests[1].1.contains(field), "{field}");
+        }
+    }
+
+    #[tokio::test]
+    async fn telegram_overrides(){
+        let pool = mock::pool().await;
+        let mut podcast = Podcast::create(&pool, "Podcast", "https://podcast.es/feed", true, &Utc::now()).await.unwrap();
+        Podcast::create(&pool, "Other", "https://other.es/feed", true, &Utc::now()).await.unwrap();
+        podcast.templates.insert("telegram".to_string(), "{{ title }}".to_string());
+        Podcast::update(&pool, &podcast).await.unwrap();
+        let telegram = Telegram::get(&pool).await.unwrap();
+        assert_eq!(telegram.overrides, HashSet::from(["Podcast".to_string()]));
+    }
+}
@@ -1,32 +1,87 @@
+use async_trait::async_trait;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{DateTime, Duration, Utc};
+use regex::Regex;
 use serde::{Serialize, Deserialize};
-use reqwest::Client;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use reqwest::{Client, multipart};
 use serde_json::{Value, json};
-use tracing::debug;
-use super::Error;
+use tracing::{debug, error};
+use super::{Error, Episode, Publisher, Receipt, render, util};
 use sqlx::sqlite::SqlitePool;
 use crate::models::Param;
 
-const X_URL: &str = "https://api.twitter.com";
+const DEFAULT_URL: &str = "https://api.twitter.com";
+/// Page where the user grants podmixer access to the account
+const AUTHORIZE_URL: &str = "https://x.com/i/oauth2/authorize";
+const SCOPES: &str = "tweet.read tweet.write users.read media.write offline.access";
+/// Where X sends the user back, under the public url
+const CALLBACK_PATH: &str = "/api/v1/oauth/twitter/callback";
+/// Refresh the access token this long before it expires
+const EXPIRATION_MARGIN: i64 = 5;
+/// Weighted characters in a tweet
+const MAX_LENGTH: usize = 280;
+/// Every link is shortened with t.co and counts the same
+const URL_LENGTH: usize = 23;
+/// Room left in every part of a thread for its number, as `\n12/12`
+const NUMBER_LENGTH: usize = 6;
+/// Biggest image accepted by the media upload
+const MAX_IMAGE: usize = 5 * 1024 * 1024;
+
+fn default_url() -> String{
+    DEFAULT_URL.to_string()
+}
+
+/// X rejected the tokens, it has to be authorized again.
+#[derive(Debug)]
+pub struct NeedsReauthorization;
+
+impl fmt::Display for NeedsReauthorization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "X authorization is no longer valid, authorize podmixer again in /api/v1/oauth/twitter/start")
+    }
+}
+
+impl std::error::Error for NeedsReauthorization {}
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Twitter{
     pub active: bool,
+    #[serde(default = "default_url")]
+    pub url: String,
     pub client_id: String,
     pub client_secret: String,
+    /// Only saved when changed by hand, as the authorization renews them
+    #[serde(default)]
     pub access_token: String,
+    #[serde(default)]
     pub refresh_token: String,
+    /// When the access token expires, unknown for tokens set by hand
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
     pub template: String,
+    /// Attach the episode artwork, or the channel one, to the first tweet
+    #[serde(default)]
+    pub attach_image: bool,
+    /// Channel artwork, used when the episode has none
+    #[serde(skip)]
+    pub image_url: String,
 }
 
 impl Twitter {
-    pub fn new(active: bool, client_id: String, client_secret: String, access_token: String, refresh_token: String, template: String) -> Self{
+    pub fn new(active: bool, url: String, client_id: String, client_secret: String, access_token: String, refresh_token: String, template: String) -> Self{
         Self{
             active,
+            url,
             client_id,
             client_secret,
             access_token,
             refresh_token,
+            expires_at: None,
             template,
+            attach_image: false,
+            image_url: String::new(),
         }
     }
     pub async fn get(pool: &SqlitePool) -> Result<Twitter, Error> {
@@ -34,94 +89,478 @@ impl Twitter {
         let active_str = Param::get(pool, "twitter_active")
             .await?;
         let active = active_str == "TRUE";
+        let url = Param::get(pool, "twitter_url").await?;
         let client_id = Param::get(pool, "twitter_client_id").await?;
         let client_secret = Param::get(pool, "twitter_client_secret").await?;
         let access_token = Param::get(pool, "twitter_access_token").await?;
         let refresh_token = Param::get(pool, "twitter_refresh_token").await?;
         let template = Param::get(pool, "twitter_template").await?;
-        Ok(Twitter::new(active, client_id, client_secret, access_token,
-            refresh_token, template))
+        Ok(Twitter{
+            attach_image: Param::get(pool, "twitter_attach_image").await? == "TRUE",
+            image_url: Param::get(pool, "feed_image_url").await?,
+            expires_at: DateTime::parse_from_rfc3339(&Param::get(pool, "twitter_expires_at").await?)
+                .ok()
+                .map(|expires_at| expires_at.to_utc()),
+            ..Twitter::new(active, url, client_id, client_secret, access_token,
+                refresh_token, template)
+        })
     }
 
     pub async fn set(pool: &SqlitePool, twitter: &Twitter) -> Result<Twitter, Error> {
         debug!("set_twitter, {:?}", twitter);
+        render::check(&twitter.template, &Episode::sample().context())?;
+        let current = Self::get(pool).await?;
         Param::set(pool, "twitter_active", &twitter.active.to_string().to_uppercase()).await?;
+        let url = twitter.url.trim_end_matches('/');
+        Param::set(pool, "twitter_url", if url.is_empty() { DEFAULT_URL } else { url }).await?;
         Param::set(pool, "twitter_client_id", &twitter.client_id).await?;
         Param::set(pool, "twitter_client_secret", &twitter.client_secret).await?;
-        Param::set(pool, "twitter_access_token", &twitter.access_token).await?;
-        Param::set(pool, "twitter_refresh_token", &twitter.refresh_token).await?;
+        if twitter.client_id != current.client_id {
+            // The tokens were given to another app
+            Twitter{access_token: String::new(), refresh_token: String::new(),
+                expires_at: None, ..twitter.clone()}.save_tokens(pool).await?;
+        } else if (!twitter.access_token.is_empty() && twitter.access_token != current.access_token)
+            || (!twitter.refresh_token.is_empty() && twitter.refresh_token != current.refresh_token) {
+            // Set by hand, when they expire is unknown
+            Twitter{expires_at: None, ..twitter.clone()}.save_tokens(pool).await?;
+        }
         Param::set(pool, "twitter_template", &twitter.template).await?;
+        Param::set(pool, "twitter_attach_image", &twitter.attach_image.to_string().to_uppercase()).await?;
         Self::get(pool).await
     }
 
 
-    pub fn is_active(&self) -> bool{
-        self.active
-    }
-
-    pub fn get_access_token(&self) -> &str{
-        &self.access_token
+    fn endpoint(&self, path: &str) -> String{
+        format!("{}{path}", self.url.trim_end_matches('/'))
     }
 
-    pub fn get_refresh_token(&self) -> &str{
-        &self.refresh_token
+    /// Whether the access token has to be refreshed before using it.
+    pub fn needs_refresh(&self) -> bool{
+        match self.expires_at {
+            _ if self.access_token.is_empty() => true,
+            Some(expires_at) => expires_at - Duration::minutes(EXPIRATION_MARGIN) <= Utc::now(),
+            None => true,
+        }
     }
 
     pub async fn update_access_token(&mut self) -> Result<(), Error>{
         debug!("Update access token");
-        let url = format!("{X_URL}/2/oauth2/token");
-        debug!("Url: {url}");
         let params = [
-            ("refresh_token", &self.refresh_token),
-            ("grant_type", &"refresh_token".to_string()),
-            ("client_id", &self.client_id)
+            ("refresh_token", self.refresh_token.as_str()),
+            ("grant_type", "refresh_token"),
+            ("client_id", self.client_id.as_str()),
         ];
-        // .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
-        debug!("Params: {:?}", params);
-        debug!("Before access_token: {}", &self.access_token);
-        debug!("Before refresh_token: {}", &self.refresh_token);
-        let data: Value = Client::new()
+        let data = self.request_token(&params).await?;
+        self.set_tokens(&data)
+    }
+
+    /// Asks X for tokens. A rejected grant means that the user has to
+    /// authorize podmixer again.
+    async fn request_token(&self, params: &[(&str, &str)]) -> Result<Value, Error>{
+        let url = self.endpoint("/2/oauth2/token");
+        debug!("Url: {url}");
+        let response = Client::new()
             .post(url)
             .basic_auth(&self.client_id, Some(&self.client_secret))
-            .form(&params)
+            .form(params)
             .send()
-            .await?
-            .error_for_status()?
-            .json()
             .await?;
+        let status = response.status();
+        if status == reqwest::StatusCode::BAD_REQUEST || status == reqwest::StatusCode::UNAUTHORIZED {
+            let body = response.text().await.unwrap_or_default();
+            error!("X rejected the token request: {body}");
+            return Err(NeedsReauthorization.into());
+        }
+        Ok(response.error_for_status()?.json().await?)
+    }
+
+    /// Takes the tokens of the response, only when all of them are there.
+    fn set_tokens(&mut self, data: &Value) -> Result<(), Error>{
         debug!("Data: {:?}", data);
-        self.access_token = data.get("access_token").unwrap().as_str().unwrap().to_string();
-        self.refresh_token = data.get("refresh_token").unwrap().as_str().unwrap().to_string();
-        debug!("New access_token: {}", &self.access_token);
-        debug!("New refresh_token: {}", &self.refresh_token);
+        let access_token = data["access_token"].as_str()
+            .ok_or("X token response without access_token")?;
+        // Without offline.access there is no way to keep posting
+        let refresh_token = data["refresh_token"].as_str()
+            .ok_or("X token response without refresh_token")?;
+        self.expires_at = data["expires_in"].as_i64()
+            .map(|seconds| Utc::now() + Duration::seconds(seconds));
+        self.access_token = access_token.to_string();
+        self.refresh_token = refresh_token.to_string();
         Ok(())
     }
 
-    pub async fn post(&self, message: &str) -> Result<String, Error>{
+    /// Saves both tokens and their expiration together, so a refresh token
+    /// already spent is never kept.
+    pub async fn save_tokens(&self, pool: &SqlitePool) -> Result<(), Error>{
+        let mut tx = pool.begin().await?;
+        Param::set(&mut *tx, "twitter_access_token", &self.access_token).await?;
+        Param::set(&mut *tx, "twitter_refresh_token", &self.refresh_token).await?;
+        Param::set(&mut *tx, "twitter_expires_at", &self.expires_at
+            .map(|expires_at| expires_at.to_rfc3339())
+            .unwrap_or_default()).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Starts the authorization, returns where to send the user.
+    pub async fn authorize(pool: &SqlitePool) -> Result<String, Error>{
+        let twitter = Self::get(pool).await?;
+        if twitter.client_id.is_empty() || twitter.client_secret.is_empty() {
+            return Err("Twitter client credentials are empty".into());
+        }
+        let redirect_uri = redirect_uri(pool).await?;
+        let state = util::random_string(32);
+        let verifier = util::random_string(64);
+        let mut tx = pool.begin().await?;
+        Param::set(&mut *tx, "twitter_oauth_state", &state).await?;
+        Param::set(&mut *tx, "twitter_oauth_verifier", &verifier).await?;
+        tx.commit().await?;
+        let mut url = reqwest::Url::parse(AUTHORIZE_URL)?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &twitter.client_id)
+            .append_pair("redirect_uri", &redirect_uri)
+            .append_pair("scope", SCOPES)
+            .append_pair("state", &state)
+            .append_pair("code_challenge", &challenge(&verifier))
+            .append_pair("code_challenge_method", "S256");
+        Ok(url.to_string())
+    }
+
+    /// Ends the authorization, trading the code for the tokens.
+    pub async fn callback(pool: &SqlitePool, state: &str, code: &str) -> Result<Twitter, Error>{
+        let expected = Param::get(pool, "twitter_oauth_state").await?;
+        if expected.is_empty() || expected != state {
+            return Err("Unknown authorization state, start again".into());
+        }
+        let verifier = Param::get(pool, "twitter_oauth_verifier").await?;
+        // Every authorization can be used once
+        Param::set(pool, "twitter_oauth_state", "").await?;
+        Param::set(pool, "twitter_oauth_verifier", "").await?;
+        let redirect_uri = redirect_uri(pool).await?;
+        let mut twitter = Self::get(pool).await?;
+        let params = [
+            ("code", code),
+            ("grant_type", "authorization_code"),
+            ("client_id", twitter.client_id.as_str()),
+            ("redirect_uri", redirect_uri.as_str()),
+            ("code_verifier", verifier.as_str()),
+        ];
+        let data = twitter.request_token(&params).await?;
+        twitter.set_tokens(&data)?;
+        twitter.save_tokens(pool).await?;
+        Ok(twitter)
+    }
+
+    pub async fn get_me(&self) -> Result<String, Error>{
+        let url = self.endpoint("/2/users/me");
+        Ok(Client::new()
+            .get(url)
+            .header("Authorization", format!("Bearer {}", self.access_token))
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?)
+    }
+
+    /// Posts a tweet, as a reply to `reply_to` when it is part of a thread.
+    pub async fn post(&self, message: &str, reply_to: Option<&str>, media_ids: &[String]) -> Result<Value, Error>{
         debug!("post");
-        let url = format!("{X_URL}/2/tweets");
+        let url = self.endpoint("/2/tweets");
         debug!("url: {url}. message: {message}");
-        let message = json!({
+        let mut tweet = json!({
             "text": message
         });
+        if let Some(reply_to) = reply_to {
+            tweet["reply"] = json!({"in_reply_to_tweet_id": reply_to});
+        }
+        if !media_ids.is_empty() {
+            tweet["media"] = json!({"media_ids": media_ids});
+        }
         debug!("access_token: {}", self.access_token);
         Ok(Client::new()
             .post(url)
             .header("Content-Type", "application/json")
             .header("Authorization", format!("Bearer {}", self.access_token))
-            .json(&message)
+            .json(&tweet)
             .send()
             .await?
             .error_for_status()?
-            .text()
+            .json()
+            .await?)
+    }
+
+    /// Uploads the artwork of the episode, or the channel one, and returns
+    /// its media id.
+    pub async fn upload_image(&self, episode: &Episode) -> Result<Option<String>, Error>{
+        let image_url = episode.image.as_deref().unwrap_or(&self.image_url);
+        if image_url.is_empty() {
+            return Ok(None);
+        }
+        let response = Client::new()
+            .get(image_url)
+            .send()
+            .await?
+            .error_for_status()?;
+        let mime = response.headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .unwrap_or("image/jpeg")
+            .to_string();
+        let bytes = response.bytes().await?;
+        if !mime.starts_with("image/") || bytes.len() > MAX_IMAGE {
+            debug!("Image {image_url} not suitable for X: {mime}, {} bytes", bytes.len());
+            return Ok(None);
+        }
+        let part = multipart::Part::bytes(bytes.to_vec())
+            .file_name("image")
+            .mime_str(&mime)?;
+        let form = multipart::Form::new()
+            .text("media_category", "tweet_image")
+            .part("media", part);
+        let response: Value = Client::new()
+            .post(self.endpoint("/2/media/upload"))
+            .header("Authorization", format!("Bearer {}", self.access_token))
+            .multipart(form)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        response.pointer("/data/id")
+            .and_then(|id| id.as_str())
+            .map(|id| Some(id.to_string()))
+            .ok_or_else(|| format!("Media upload without id: {response}").into())
+    }
+
+    pub async fn delete_tweet(&self, id: &str) -> Result<Value, Error>{
+        debug!("delete_tweet");
+        let url = self.endpoint(&format!("/2/tweets/{id}"));
+        Ok(Client::new()
+            .delete(url)
+            .header("Authorization", format!("Bearer {}", self.access_token))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
             .await?)
     }
 }
 
+#[async_trait]
+impl Publisher for Twitter{
+    fn name(&self) -> &'static str{
+        "twitter"
+    }
+
+    fn is_active(&self) -> bool{
+        self.active
+    }
+
+    fn template(&self) -> &str{
+        &self.template
+    }
+
+    /// Longer announcements are posted as a thread.
+    fn limit(&self) -> Option<usize>{
+        Some(MAX_LENGTH)
+    }
+
+    fn length(&self, message: &str) -> usize{
+        tweet_length(message)
+    }
+
+    fn validate(&self) -> Result<(), Error>{
+        if self.client_id.is_empty() || self.client_secret.is_empty() {
+            return Err("Twitter client credentials are empty".into());
+        }
+        if self.refresh_token.is_empty() {
+            return Err(NeedsReauthorization.into());
+        }
+        Ok(())
+    }
+
+    async fn prepare(&mut self, pool: &SqlitePool) -> Result<(), Error>{
+        if !self.needs_refresh() {
+            return Ok(());
+        }
+        debug!("Update twitter");
+        if let Err(e) = self.update_access_token().await {
+            if e.downcast_ref::<NeedsReauthorization>().is_some() {
+                // Spent tokens, nothing to do until it is authorized again
+                self.access_token.clear();
+                self.refresh_token.clear();
+                self.expires_at = None;
+                self.save_tokens(pool).await?;
+            }
+            return Err(e);
+        }
+        self.save_tokens(pool).await
+    }
+
+    async fn publish(&self, message: &str, episode: &Episode) -> Result<Receipt, Error>{
+        let mut media_ids = Vec::new();
+        if self.attach_image {
+            // The announcement is still worth it without the image
+            match self.upload_image(episode).await {
+                Ok(Some(media_id)) => media_ids.push(media_id),
+                Ok(None) => {},
+                Err(e) => error!("Error uploading the image to X: {e}"),
+            }
+        }
+        let mut ids: Vec<String> = Vec::new();
+        for part in split(message) {
+            let response = match self.post(&part, ids.last().map(|id| id.as_str()), &media_ids).await {
+                Ok(response) => response,
+                // Once the first one is out, retrying would post it twice
+                Err(e) if !ids.is_empty() => {
+                    error!("Error posting the thread in X: {e}");
+                    break;
+                },
+                Err(e) => return Err(e),
+            };
+            let id = response.pointer("/data/id")
+                .and_then(|id| id.as_str())
+                .ok_or("Response without id")?;
+            ids.push(id.to_string());
+            media_ids.clear();
+        }
+        Ok(Receipt{
+            remote_url: ids.first().map(|id| format!("https://x.com/i/web/status/{id}")),
+            remote_id: ids.join("\n"),
+        })
+    }
+
+    async fn delete(&self, receipt: &Receipt) -> Result<(), Error>{
+        // The replies first, so the thread never has a hole at the top
+        for id in receipt.remote_id.lines().rev() {
+            self.delete_tweet(id).await?;
+        }
+        Ok(())
+    }
+
+    async fn test_connection(&self) -> Result<(), Error>{
+        self.get_me().await.map(|_| ())
+    }
+}
+
+async fn redirect_uri(pool: &SqlitePool) -> Result<String, Error>{
+    let public_url = Param::get(pool, "feed_public_url").await?;
+    if public_url.is_empty() {
+        return Err("The public url of the feed is needed to authorize X".into());
+    }
+    Ok(format!("{}{CALLBACK_PATH}", public_url.trim_end_matches('/')))
+}
+
+/// PKCE S256 challenge of the verifier.
+fn challenge(verifier: &str) -> String{
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+/// Weight of a character for X, 1 for the latin, greek, cyrillic... ranges
+/// and some punctuation, 2 for the rest, like CJK or emoji.
+fn char_weight(c: char) -> usize{
+    match c as u32 {
+        0..=4351 | 8192..=8205 | 8208..=8223 | 8242..=8247 => 1,
+        _ => 2,
+    }
+}
+
+/// Length of a tweet as X counts it, every url as `URL_LENGTH`. Emoji
+/// sequences count 2 per character, more than X does, so they always fit.
+pub fn tweet_length(text: &str) -> usize{
+    let re = Regex::new(r"https?://\S+").unwrap();
+    let mut length = 0;
+    let mut last = 0;
+    for url in re.find_iter(text) {
+        length += text[last..url.start()].chars().map(char_weight).sum::<usize>() + URL_LENGTH;
+        last = url.end();
+    }
+    length + text[last..].chars().map(char_weight).sum::<usize>()
+}
+
+/// Cuts a word longer than a tweet in chunks of up to `limit`, but never
+/// inside a url.
+fn cut_word(word: &str, limit: usize) -> Vec<String>{
+    let re = Regex::new(r"https?://\S+").unwrap();
+    let mut segments = Vec::new();
+    let mut last = 0;
+    for url in re.find_iter(word) {
+        segments.push((&word[last..url.start()], false));
+        segments.push((url.as_str(), true));
+        last = url.end();
+    }
+    segments.push((&word[last..], false));
+    let mut chunks = Vec::new();
+    let mut chunk = String::new();
+    let mut length = 0;
+    for (segment, is_url) in segments {
+        if is_url {
+            if length + URL_LENGTH > limit {
+                chunks.push(std::mem::take(&mut chunk));
+                length = 0;
+            }
+            chunk.push_str(segment);
+            length += URL_LENGTH;
+            continue;
+        }
+        for c in segment.chars() {
+            if length + char_weight(c) > limit {
+                chunks.push(std::mem::take(&mut chunk));
+                length = 0;
+            }
+            chunk.push(c);
+            length += char_weight(c);
+        }
+    }
+    chunks.push(chunk);
+    chunks
+}
+
+/// Splits the text in numbered tweets, on word boundaries, when it does not
+/// fit in one.
+pub fn split(text: &str) -> Vec<String>{
+    let text = text.trim();
+    if tweet_length(text) <= MAX_LENGTH {
+        return vec![text.to_string()];
+    }
+    let limit = MAX_LENGTH - NUMBER_LENGTH;
+    let mut parts: Vec<String> = Vec::new();
+    let mut current = String::new();
+    for piece in text.split_inclusive(char::is_whitespace) {
+        if tweet_length(format!("{current}{piece}").trim_end()) <= limit {
+            current.push_str(piece);
+            continue;
+        }
+        if !current.trim().is_empty() {
+            parts.push(current.trim().to_string());
+        }
+        // A word longer than a tweet is cut wherever it fits
+        let word = piece.trim_end();
+        let mut chunks = cut_word(word, limit);
+        current = chunks.pop().unwrap_or_default() + &piece[word.len()..];
+        parts.extend(chunks);
+    }
+    if !current.trim().is_empty() {
+        parts.push(current.trim().to_string());
+    }
+    let total = parts.len();
+    parts.iter()
+        .enumerate()
+        .map(|(index, part)| format!("{part}\n{}/{total}", index + 1))
+        .collect()
+}
+
 #[cfg(test)]
 mod test{
-    use super::Twitter;
+    use super::{MAX_LENGTH, NeedsReauthorization, Twitter, split, tweet_length};
+    use crate::models::{Episode, Param, Publisher, mock};
+    use axum::{Form, Json, Router, routing, http::{HeaderMap, StatusCode}, response::IntoResponse};
     use dotenv::dotenv;
+    use serde_json::{Value, json};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
     use std::{env, str::FromStr};
     use tracing_subscriber::{
         EnvFilter,
@@ -141,9 +580,9 @@ mod test{
         let client_secret = env::var("X_CLIENT_SECRET").expect("X_CLIENT_SECRET");
         let access_token = env::var("X_ACCESS_TOKEN").expect("X_ACCESS_TOKEN");
         let refresh_token = env::var("X_REFRESH_TOKEN").expect("X_REFRESH_TOKEN");
-        let mut twitter = Twitter::new(active, client_id, client_secret, access_token, refresh_token, "".to_string());
+        let mut twitter = Twitter::new(active, super::default_url(), client_id, client_secret, access_token, refresh_token, "".to_string());
         assert!(twitter.update_access_token().await.is_ok());
-        let response = twitter.post("Prueba").await;
+        let response = twitter.post("Prueba", None, &[]).await;
         match response{
             Ok(_) => println!("Populated in Twitter"),
             Err(ref error) => {
@@ -158,5 +597,177 @@ mod test{
         }
         println!("{:?}", response);
     }
-}
 
+    #[test]
+    fn test_split(){
+        assert_eq!(split("Hola"), vec!["Hola"]);
+        let words = "palabra ".repeat(70);
+        let link = "https://atareao.es/".to_string() + &"a".repeat(300);
+        let parts = split(&format!("{words}{link}"));
+        assert_eq!(parts.len(), 3);
+        assert!(parts[0].ends_with("palabra\n1/3"));
+        assert_eq!(parts[2], format!("palabra palabra {link}\n3/3"));
+        assert!(parts.iter().all(|part| tweet_length(part) <= MAX_LENGTH));
+        let long = split(&"a".repeat(600));
+        assert_eq!(long.len(), 3);
+        assert!(long.iter().all(|part| tweet_length(part) <= MAX_LENGTH));
+        // A url glued to the text stays whole
+        let glued = format!("{}(https://atareao.es/podcast/1)", "a".repeat(260));
+        let parts = split(&glued);
+        assert_eq!(parts.len(), 2);
+        assert!(parts[0].ends_with("a(\n1/2"));
+        assert!(parts[1].starts_with("https://atareao.es/podcast/1)"));
+        // CJK weighs twice
+        assert_eq!(tweet_length("日本 https://atareao.es"), 28);
+        let cjk = split(&"日本語".repeat(100));
+        assert_eq!(cjk.len(), 3);
+        assert!(cjk.iter().all(|part| tweet_length(part) <= MAX_LENGTH));
+    }
+
+    #[tokio::test]
+    async fn twitter_mock(){
+        let router = Router::new()
+            .route("/2/oauth2/token", routing::post(
+                |headers: HeaderMap, Form(params): Form<HashMap<String, String>>| async move {
+                    // client_id:client_secret
+                    assert_eq!(headers["authorization"], "Basic aWQ6c2VjcmV0");
+                    assert_eq!(params["grant_type"], "refresh_token");
+                    assert_eq!(params["refresh_token"], "refresh");
+                    Json(json!({"access_token": "new-access", "refresh_token": "new-refresh"}))
+                }))
+            .route("/2/tweets", routing::post(
+                |headers: HeaderMap, Json(tweet): Json<Value>| async move {
+                    assert_eq!(headers["authorization"], "Bearer new-access");
+                    assert_eq!(tweet["text"], "Prueba");
+                    Json(json!({"data": {"id": "42", "text": "Prueba"}}))
+                }));
+        let url = mock::serve(router).await;
+        let mut twitter = Twitter::new(true, url, "id".to_string(), "secret".to_string(),
+            "access".to_string(), "refresh".to_string(), "".to_string());
+        twitter.update_access_token().await.unwrap();
+        assert_eq!(twitter.access_token, "new-access");
+        assert_eq!(twitter.refresh_token, "new-refresh");
+        let response = twitter.post("Prueba", None, &[]).await.unwrap();
+        assert_eq!(response["data"]["id"], "42");
+        let receipt = twitter.publish("Prueba", &Episode::default()).await.unwrap();
+        assert_eq!(receipt.remote_url.as_deref(), Some("https://x.com/i/web/status/42"));
+    }
+
+    #[tokio::test]
+    async fn twitter_thread(){
+        let tweets: Arc<Mutex<Vec<Value>>> = Arc::default();
+        let posted = tweets.clone();
+        let router = Router::new()
+            .route("/cover.png", routing::get(|| async {
+                ([("content-type", "image/png")], vec![0u8; 64])
+            }))
+            .route("/2/media/upload", routing::post(|headers: HeaderMap| async move {
+                assert_eq!(headers["authorization"], "Bearer access");
+                Json(json!({"data": {"id": "7", "media_key": "3_7"}}))
+            }))
+            .route("/2/tweets", routing::post(move |Json(tweet): Json<Value>| async move {
+                let mut tweets = posted.lock().unwrap();
+                tweets.push(tweet);
+                Json(json!({"data": {"id": format!("{}", tweets.len())}}))
+            }));
+        let url = mock::serve(router).await;
+        let mut twitter = Twitter::new(true, url.clone(), "id".to_string(), "secret".to_string(),
+            "access".to_string(), "refresh".to_string(), "".to_string());
+        twitter.attach_image = true;
+        let episode = Episode{
+            image: Some(format!("{url}/cover.png")),
+            ..Default::default()
+        };
+        let message = "palabra ".repeat(60);
+        let receipt = twitter.publish(&message, &episode).await.unwrap();
+        assert_eq!(receipt.remote_id, "1\n2");
+        assert_eq!(receipt.remote_url.as_deref(), Some("https://x.com/i/web/status/1"));
+        let tweets = tweets.lock().unwrap();
+        assert_eq!(tweets[0]["media"]["media_ids"], json!(["7"]));
+        assert!(tweets[0].get("reply").is_none());
+        assert!(tweets[1].get("media").is_none());
+        assert_eq!(tweets[1]["reply"]["in_reply_to_tweet_id"], "1");
+        assert!(tweets[1]["text"].as_str().unwrap().ends_with("2/2"));
+    }
+
+    #[tokio::test]
+    async fn twitter_set(){
+        let pool = mock::pool().await;
+        Param::set(&pool, "twitter_client_id", "id").await.unwrap();
+        let mut twitter = Twitter::get(&pool).await.unwrap();
+        twitter.access_token = "access".to_string();
+        twitter.refresh_token = "refresh".to_string();
+        twitter.expires_at = Some(chrono::Utc::now() + chrono::Duration::hours(2));
+        twitter.save_tokens(&pool).await.unwrap();
+        // Saving the form again, with or without the tokens, keeps them
+        let form = Twitter{active: true, ..twitter.clone()};
+        let saved = Twitter::set(&pool, &form).await.unwrap();
+        assert!(saved.active);
+        assert!(!saved.needs_refresh());
+        let form = Twitter{access_token: "".to_string(), refresh_token: "".to_string(), ..twitter.clone()};
+        let saved = Twitter::set(&pool, &form).await.unwrap();
+        assert_eq!(saved.access_token, "access");
+        assert_eq!(saved.refresh_token, "refresh");
+        assert!(saved.expires_at.is_some());
+        // Changed by hand
+        let form = Twitter{access_token: "manual".to_string(), ..twitter.clone()};
+        let saved = Twitter::set(&pool, &form).await.unwrap();
+        assert_eq!(saved.access_token, "manual");
+        assert_eq!(saved.refresh_token, "refresh");
+        assert!(saved.expires_at.is_none());
+        // Another app
+        let form = Twitter{client_id: "other".to_string(), ..saved};
+        let saved = Twitter::set(&pool, &form).await.unwrap();
+        assert!(saved.access_token.is_empty());
+        assert!(saved.refresh_token.is_empty());
+    }
+
+    #[tokio::test]
+    async fn twitter_oauth(){
+        let router = Router::new()
+            .route("/2/oauth2/token", routing::post(
+                |Form(params): Form<HashMap<String, String>>| async move {
+                    match params["grant_type"].as_str() {
+                        "authorization_code" => {
+                            assert_eq!(params["code"], "code");
+                            assert_eq!(params["redirect_uri"], "https://podmixer.es/api/v1/oauth/twitter/callback");
+                            assert_eq!(super::challenge(&params["code_verifier"]).len(), 43);
+                            Json(json!({"access_token": "access", "refresh_token": "refresh", "expires_in": 7200})).into_response()
+                        },
+                        _ if params["refresh_token"] == "spent" => StatusCode::BAD_REQUEST.into_response(),
+                        _ => Json(json!({"access_token": "only-access"})).into_response(),
+                    }
+                }));
+        let url = mock::serve(router).await;
+        let pool = mock::pool().await;
+        Param::set(&pool, "feed_public_url", "https://podmixer.es/").await.unwrap();
+        Param::set(&pool, "twitter_url", &url).await.unwrap();
+        Param::set(&pool, "twitter_client_id", "id").await.unwrap();
+        Param::set(&pool, "twitter_client_secret", "secret").await.unwrap();
+        let authorize = reqwest::Url::parse(&Twitter::authorize(&pool).await.unwrap()).unwrap();
+        let params: HashMap<_, _> = authorize.query_pairs().into_owned().collect();
+        assert_eq!(params["code_challenge_method"], "S256");
+        assert!(Twitter::callback(&pool, "forged", "code").await.is_err());
+        let twitter = Twitter::callback(&pool, &params["state"], "code").await.unwrap();
+        assert!(!twitter.needs_refresh());
+        // Every authorization is used once
+        assert!(Twitter::callback(&pool, &params["state"], "code").await.is_err());
+        let mut twitter = Twitter::get(&pool).await.unwrap();
+        assert_eq!(twitter.refresh_token, "refresh");
+        assert!(!twitter.needs_refresh());
+        // Nothing to refresh yet
+        twitter.prepare(&pool).await.unwrap();
+        assert_eq!(twitter.access_token, "access");
+        // Incomplete responses keep the old tokens
+        twitter.expires_at = None;
+        assert!(twitter.prepare(&pool).await.is_err());
+        assert_eq!(Twitter::get(&pool).await.unwrap().refresh_token, "refresh");
+        // A rejected refresh token asks for a new authorization
+        twitter.refresh_token = "spent".to_string();
+        let error = twitter.prepare(&pool).await.unwrap_err();
+        assert!(error.downcast_ref::<NeedsReauthorization>().is_some());
+        let twitter = Twitter::get(&pool).await.unwrap();
+        assert!(twitter.refresh_token.is_empty());
+        assert!(twitter.validate().is_err());
+    }
+}
@@ -1,21 +1,89 @@
-use std::error::Error;
+use super::Error;
 use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+use reqwest::multipart;
 use regex::Regex;
+use rand::{Rng, distr::Alphanumeric};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use tracing::error;
 use std::{
     path::Path,
     ffi::OsStr
 };
 
-pub async fn fetch_url(url: &str, filename: &str) -> Result<(), Box<dyn Error>> {
+/// A downloaded file, removed when it goes out of scope, even on errors.
+pub struct TempFile{
+    path: String,
+}
+
+impl TempFile{
+    pub fn new(filename: &str) -> Self{
+        let path = std::env::temp_dir().join(filename);
+        Self{
+            path: path.to_string_lossy().to_string(),
+        }
+    }
+
+    pub fn path(&self) -> &str{
+        &self.path
+    }
+}
+
+impl Drop for TempFile{
+    fn drop(&mut self){
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                error!("Could NOT remove {}: {e}", self.path);
+            }
+        }
+    }
+}
+
+/// Size announced by the server, without downloading the file.
+pub async fn content_length(url: &str) -> Option<u64> {
+    let response = reqwest::Client::new()
+        .head(url)
+        .send()
+        .await
+        .ok()?
+        .error_for_status()
+        .ok()?;
+    response.headers()
+        .get(reqwest::header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// Multipart part that streams the file instead of loading it in memory.
+pub async fn file_part(filepath: &str, filename: &str) -> Result<multipart::Part, Error> {
+    let file = tokio::fs::File::open(filepath).await?;
+    let length = file.metadata().await?.len();
+    let body = reqwest::Body::wrap_stream(ReaderStream::new(file));
+    Ok(multipart::Part::stream_with_length(body, length)
+        .file_name(filename.to_string()))
+}
+
+pub async fn fetch_url(url: &str, filename: &str) -> Result<(), Error> {
     let mut file = tokio::fs::File::create(filename).await?;
-    let mut response = reqwest::get(url).await?;
+    let mut response = reqwest::get(url).await?.error_for_status()?;
     while let Some(chunck) = response.chunk().await?{
         file.write_all(&chunck).await?;
     }
+    file.flush().await?;
     Ok(())
 }
 
-pub fn normalize(title: &str) -> Result<String, Box<dyn Error>>{
+/// Escapes the text to be used inside html, or Telegram html messages.
+pub fn escape_html(text: &str) -> String{
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+pub fn normalize(title: &str) -> Result<String, Error>{
     let re = Regex::new(r"[^a-zA-Z0-9\._-]");
     Ok(re?.replace_all(title, "_").to_string())
 }
@@ -26,18 +94,154 @@ pub fn get_extension_from_filename(filename: &str) -> Option<&str> {
         .and_then(OsStr::to_str)
 }
 
+pub fn parse_date(date: &str) -> Option<DateTime<Utc>> {
+    if let Ok(date) = DateTime::parse_from_rfc2822(date) {
+        Some(date.to_utc())
+    } else {
+        NaiveDateTime::parse_from_str(date, "%a, %d %b %Y %H:%M:%S")
+            .ok()
+            .map(|date| date.and_utc())
+    }
+}
+
+/// Seconds of an `itunes:duration`, either `HH:MM:SS`, `MM:SS` or seconds.
+pub fn parse_duration(duration: &str) -> Option<u64> {
+    let mut seconds = 0;
+    let parts: Vec<&str> = duration.trim().split(':').collect();
+    if parts.len() > 3 {
+        return None;
+    }
+    for part in parts {
+        seconds = seconds * 60 + part.trim().parse::<f64>().ok()? as u64;
+    }
+    Some(seconds)
+}
+
+/// Logs the error and the whole chain of its causes.
+pub fn log_error(message: &str, error: &Error) {
+    error!("{message}: {error}");
+    let mut next_error = error.source();
+    while let Some(cause) = next_error {
+        error!("caused by: {:#}", cause);
+        next_error = cause.source();
+    }
+}
+
+/// Length of a status where every url counts as `url_length` characters
+/// and any other character as one, the way Mastodon counts them. X weighs
+/// CJK characters and emoji twice, see `twitter::tweet_length`.
+pub fn weighted_length(text: &str, url_length: usize) -> usize {
+    let re = Regex::new(r"https?://\S+").unwrap();
+    let urls = re.find_iter(text).count();
+    let url_chars: usize = re.find_iter(text).map(|url| url.as_str().chars().count()).sum();
+    text.chars().count() - url_chars + urls * url_length
+}
+
+/// Truncates `text` to `max` weighted characters, ending with an ellipsis,
+/// without splitting urls or words.
+pub fn truncate_weighted(text: &str, max: usize, url_length: usize) -> String {
+    if weighted_length(text, url_length) <= max {
+        return text.to_string();
+    }
+    let re = Regex::new(r"https?://\S+").unwrap();
+    let budget = max.saturating_sub(1);
+    let mut length = 0;
+    let mut output = String::new();
+    let mut last = 0;
+    let mut segments = Vec::new();
+    for url in re.find_iter(text) {
+        segments.push((&text[last..url.start()], false));
+        segments.push((url.as_str(), true));
+        last = url.end();
+    }
+    segments.push((&text[last..], false));
+    'segments: for (segment, is_url) in segments {
+        if is_url {
+            if length + url_length > budget {
+                break;
+            }
+            output.push_str(segment);
+            length += url_length;
+            continue;
+        }
+        for (index, c) in segment.char_indices() {
+            if length + 1 > budget {
+                // Do not leave half a word behind
+                let next = segment[index..].chars().next();
+                if next.is_some_and(|next| !next.is_whitespace()) {
+                    if let Some(space) = output.rfind(char::is_whitespace) {
+                        output.truncate(space);
+                    }
+                }
+                break 'segments;
+            }
+            output.push(c);
+            length += 1;
+        }
+    }
+    format!("{}…", output.trim_end())
+}
+
+/// Cuts the text to `max` characters at a word boundary.
+pub fn truncate_words(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text.to_string();
+    }
+    let mut output: String = text.chars().take(max.saturating_sub(1)).collect();
+    if let Some(space) = output.rfind(char::is_whitespace) {
+        output.truncate(space);
+    }
+    format!("{}…", output.trim_end())
+}
+
+/// Splits a multiline config value in its non empty lines.
+pub fn lines(text: &str) -> Vec<String> {
+    text.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| line.to_string())
+        .collect()
+}
+
+pub fn random_string(length: usize) -> String {
+    rand::rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
+
 
 
 #[cfg(test)]
 mod test{
     use super::fetch_url;
     use super::normalize;
+    use super::{truncate_weighted, weighted_length};
     use std::str::FromStr;
     use tracing_subscriber::{
         EnvFilter,
         layer::SubscriberExt,
         util::SubscriberInitExt
     };
+    #[test]
+    fn test_truncate_weighted(){
+        let text = "Nuevo episodio https://example.com/a/very/long/url/to/the/episode";
+        assert_eq!(weighted_length(text, 23), 38);
+        assert_eq!(truncate_weighted(text, 38, 23), text);
+        assert_eq!(truncate_weighted(text, 30, 23), "Nuevo episodio…");
+        assert_eq!(truncate_weighted("uno dos tres", 9, 23), "uno dos…");
+    }
+
+    #[test]
+    fn test_parse_duration(){
+        assert_eq!(super::parse_duration("1:02:03"), Some(3723));
+        assert_eq!(super::parse_duration("62:03"), Some(3723));
+        assert_eq!(super::parse_duration("3723"), Some(3723));
+        assert_eq!(super::parse_duration("3723.5"), Some(3723));
+        assert_eq!(super::parse_duration("unknown"), None);
+    }
+
     #[test]
     fn test_normalize(){
         let response = normalize("Esto es una prueba de audio.mp3");
@@ -0,0 +1,365 @@
+use serde::Serialize;
+use rss::{Channel, Item};
+use reqwest::{Client, Url, header};
+use regex::Regex;
+use chrono::Utc;
+use std::collections::HashSet;
+use tracing::debug;
+use super::{Error, util};
+
+const MIN_ARTWORK_SIZE: u32 = 1400;
+const MAX_ARTWORK_SIZE: u32 = 3000;
+const MAX_ARTWORK_BYTES: u64 = 512 * 1024;
+const ARTWORK_HEADER_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, Serialize, Clone)]
+pub struct Issue{
+    pub target: String,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct Report{
+    pub valid: bool,
+    pub errors: Vec<Issue>,
+    pub warnings: Vec<Issue>,
+}
+
+impl Report {
+    fn error(&mut self, target: &str, message: &str){
+        self.errors.push(Issue{
+            target: target.to_string(),
+            message: message.to_string(),
+        });
+    }
+
+    fn warning(&mut self, target: &str, message: &str){
+        self.warnings.push(Issue{
+            target: target.to_string(),
+            message: message.to_string(),
+        });
+    }
+}
+
+/// Checks a generated channel against the Apple Podcasts, Spotify and
+/// Podcast Index requirements, including the artwork behind `itunes:image`.
+pub async fn validate(channel: &Channel) -> Report{
+    let mut report = Report::default();
+    check_channel(channel, &mut report);
+    check_items(channel.items(), &mut report);
+    let artwork = channel.itunes_ext()
+        .and_then(|itunes| itunes.image())
+        .or(channel.image().map(|image| image.url()))
+        .unwrap_or("");
+    if !artwork.is_empty() {
+        if let Err(e) = check_artwork(artwork, &mut report).await {
+            report.error("artwork", &format!("Can not read {artwork}: {e}"));
+        }
+    }
+    report.valid = report.errors.is_empty();
+    report
+}
+
+fn check_channel(channel: &Channel, report: &mut Report){
+    let target = "channel";
+    if channel.title().trim().is_empty() {
+        report.error(target, "Missing title");
+    }
+    if channel.description().trim().is_empty() {
+        report.error(target, "Missing description");
+    }
+    if Url::parse(channel.link()).is_err() {
+        report.error(target, &format!("Invalid link: '{}'", channel.link()));
+    }
+    match channel.language() {
+        Some(language) if !language.is_empty() => {},
+        _ => report.error(target, "Missing language"),
+    }
+    let Some(itunes) = channel.itunes_ext() else {
+        report.error(target, "Missing itunes extension");
+        return;
+    };
+    match itunes.image() {
+        Some(image) if Url::parse(image).is_ok() => {},
+        Some(image) => report.error(target, &format!("Invalid itunes:image: '{image}'")),
+        None => report.error(target, "Missing itunes:image"),
+    }
+    if itunes.categories().iter().all(|category| category.text().is_empty()) {
+        report.error(target, "Missing itunes:category");
+    }
+    match itunes.explicit() {
+        Some("true") | Some("false") => {},
+        // Older feeds, Apple only takes true or false now
+        Some(explicit @ ("yes" | "no")) => report.warning(target,
+            &format!("itunes:explicit '{explicit}' is deprecated, use 'true' or 'false'")),
+        _ => report.error(target, "itunes:explicit must be 'true' or 'false'"),
+    }
+    if itunes.author().unwrap_or("").is_empty() {
+        report.warning(target, "Missing itunes:author");
+    }
+    let email = itunes.owner().and_then(|owner| owner.email()).unwrap_or("");
+    if email.is_empty() {
+        report.warning(target, "Missing itunes:owner email, required to claim the feed");
+    } else if !is_valid_email(email) {
+        report.error(target, &format!("Invalid itunes:owner email: '{email}'"));
+    }
+}
+
+fn check_items(items: &[Item], report: &mut Report){
+    if items.is_empty() {
+        report.warning("channel", "The channel has no episodes");
+    }
+    let mut guids = HashSet::new();
+    let now = Utc::now();
+    for item in items {
+        let title = item.title().unwrap_or("");
+        let target = format!("item '{title}'");
+        if title.trim().is_empty() {
+            report.error(&target, "Missing title");
+        }
+        match item.guid() {
+            Some(guid) => {
+                if !guids.insert(guid.value().to_string()) {
+                    report.error(&target, &format!("Duplicate guid: '{}'", guid.value()));
+                }
+            },
+            None => report.warning(&target, "Missing guid"),
+        }
+        match item.pub_date() {
+            Some(pub_date) => match util::parse_date(pub_date) {
+                Some(date) if date > now => report.warning(&target, &format!("Publication date in the future: '{pub_date}'")),
+                Some(_) => {},
+                None => report.error(&target, &format!("Invalid publication date: '{pub_date}'")),
+            },
+            None => report.warning(&target, "Missing publication date"),
+        }
+        let Some(enclosure) = item.enclosure() else {
+            report.error(&target, "Missing enclosure");
+            continue;
+        };
+        match Url::parse(enclosure.url()) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {},
+            _ => report.error(&target, &format!("Invalid enclosure url: '{}'", enclosure.url())),
+        }
+        let mime_type = enclosure.mime_type();
+        if !mime_type.starts_with("audio/") && !mime_type.starts_with("video/") {
+            report.error(&target, &format!("Invalid enclosure type: '{mime_type}'"));
+        }
+        match enclosure.length().parse::<u64>() {
+            Ok(length) if length > 0 => {},
+            _ => report.warning(&target, &format!("Invalid enclosure length: '{}'", enclosure.length())),
+        }
+    }
+}
+
+async fn check_artwork(artwork: &str, report: &mut Report) -> Result<(), Error>{
+    debug!("check_artwork: {artwork}");
+    let target = "artwork";
+    let (content_type, length, bytes) = match artwork.strip_prefix("file://") {
+        Some(path) => read_local_artwork(path).await?,
+        None if artwork.starts_with('/') => read_local_artwork(artwork).await?,
+        None => read_remote_artwork(artwork).await?,
+    };
+    if let Some(content_type) = content_type {
+        if content_type != "image/jpeg" && content_type != "image/png" {
+            report.error(target, &format!("Artwork must be JPEG or PNG, not {content_type}"));
+        }
+    }
+    if let Some(length) = length {
+        if length > MAX_ARTWORK_BYTES {
+            report.warning(target, &format!("Artwork is {length} bytes, recommended maximum is {MAX_ARTWORK_BYTES}"));
+        }
+    }
+    match image_dimensions(&bytes) {
+        Some((width, height)) => {
+            if width != height {
+                report.error(target, &format!("Artwork must be square, it is {width}x{height}"));
+            }
+            if !(MIN_ARTWORK_SIZE..=MAX_ARTWORK_SIZE).contains(&width) ||
+                    !(MIN_ARTWORK_SIZE..=MAX_ARTWORK_SIZE).contains(&height) {
+                report.error(target, &format!("Artwork must be between {MIN_ARTWORK_SIZE} and {MAX_ARTWORK_SIZE} pixels, it is {width}x{height}"));
+            }
+        },
+        None => report.error(target, "Artwork must be a JPEG or PNG image"),
+    }
+    Ok(())
+}
+
+async fn read_local_artwork(path: &str) -> Result<(Option<String>, Option<u64>, Vec<u8>), Error>{
+    let bytes = tokio::fs::read(path).await?;
+    Ok((None, Some(bytes.len() as u64), bytes))
+}
+
+async fn read_remote_artwork(url: &str) -> Result<(Option<String>, Option<u64>, Vec<u8>), Error>{
+    let client = Client::new();
+    // Many CDNs do not answer HEAD, the GET below tells the same
+    let head = match client.head(url).send().await.and_then(|response| response.error_for_status()) {
+        Ok(response) => Some(response),
+        Err(e) => {
+            debug!("HEAD {url} failed, using GET: {e}");
+            None
+        },
+    };
+    let mut response = client.get(url)
+        .header(header::RANGE, format!("bytes=0-{}", ARTWORK_HEADER_BYTES - 1))
+        .send()
+        .await?
+        .error_for_status()?;
+    let source = head.as_ref().unwrap_or(&response);
+    let content_type = source.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.split(';').next().unwrap_or("").trim().to_string());
+    let length = match head {
+        Some(ref head) => head.content_length(),
+        None => total_length(&response),
+    }.filter(|length| *length > 0);
+    let mut bytes = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        bytes.extend_from_slice(&chunk);
+        if bytes.len() >= ARTWORK_HEADER_BYTES {
+            break;
+        }
+    }
+    Ok((content_type, length, bytes))
+}
+
+/// Size of the whole file from a GET, even when it only sent a range.
+fn total_length(response: &reqwest::Response) -> Option<u64>{
+    if response.status() == reqwest::StatusCode::PARTIAL_CONTENT {
+        // bytes 0-65535/123456
+        response.headers()
+            .get(header::CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit('/').next())
+            .and_then(|total| total.parse().ok())
+    } else {
+        response.content_length()
+    }
+}
+
+/// Reads width and height from the header of a PNG or JPEG image.
+pub fn image_dimensions(bytes: &[u8]) -> Option<(u32, u32)>{
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") && bytes.len() >= 24 && &bytes[12..16] == b"IHDR" {
+        let width = u32::from_be_bytes(bytes[16..20].try_into().ok()?);
+        let height = u32::from_be_bytes(bytes[20..24].try_into().ok()?);
+        return Some((width, height));
+    }
+    if !bytes.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut position = 2;
+    while position + 4 <= bytes.len() {
+        if bytes[position] != 0xFF {
+            return None;
+        }
+        let marker = bytes[position + 1];
+        if marker == 0xFF {
+            position += 1;
+            continue;
+        }
+        let length = u16::from_be_bytes([bytes[position + 2], bytes[position + 3]]) as usize;
+        // SOF0..SOF15 except DHT (C4), JPG (C8) and DAC (CC)
+        if (0xC0..=0xCF).contains(&marker) && ![0xC4, 0xC8, 0xCC].contains(&marker) {
+            if position + 9 > bytes.len() {
+                return None;
+            }
+            let height = u16::from_be_bytes([bytes[position + 5], bytes[position + 6]]) as u32;
+            let width = u16::from_be_bytes([bytes[position + 7], bytes[position + 8]]) as u32;
+            return Some((width, height));
+        }
+        position += 2 + length;
+    }
+    None
+}
+
+fn is_valid_email(email: &str) -> bool{
+    Regex::new(r"^[^@\s]+@[^@\s]+\.[^@\s]+$")
+        .map(|re| re.is_match(email))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test{
+    use super::{image_dimensions, check_channel, check_items, read_remote_artwork, Report};
+    use crate::models::mock;
+    use axum::{Router, routing, http::StatusCode};
+    use rss::{ChannelBuilder, ItemBuilder, EnclosureBuilder, GuidBuilder};
+    use rss::extension::itunes::ITunesChannelExtensionBuilder;
+
+    #[test]
+    fn test_explicit(){
+        let report = |explicit: &str| {
+            let itunes = ITunesChannelExtensionBuilder::default()
+                .explicit(Some(explicit.to_string()))
+                .build();
+            let channel = ChannelBuilder::default()
+                .itunes_ext(Some(itunes))
+                .build();
+            let mut report = Report::default();
+            check_channel(&channel, &mut report);
+            report
+        };
+        let explicit = |report: &Report| report.errors.iter()
+            .chain(report.warnings.iter())
+            .filter(|issue| issue.message.contains("itunes:explicit"))
+            .count();
+        assert_eq!(explicit(&report("true")), 0);
+        let deprecated = report("yes");
+        assert_eq!(explicit(&deprecated), 1);
+        assert!(deprecated.warnings.iter().any(|issue| issue.message.contains("deprecated")));
+        let invalid = report("maybe");
+        assert!(invalid.errors.iter().any(|issue| issue.message.contains("itunes:explicit")));
+    }
+
+    #[test]
+    fn test_png_dimensions(){
+        let mut png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR".to_vec();
+        png.extend_from_slice(&1400u32.to_be_bytes());
+        png.extend_from_slice(&1400u32.to_be_bytes());
+        assert_eq!(image_dimensions(&png), Some((1400, 1400)));
+    }
+
+    #[tokio::test]
+    async fn test_artwork_without_head(){
+        let router = Router::new().route("/cover.png", routing::get(|| async {
+            (StatusCode::PARTIAL_CONTENT,
+             [("content-type", "image/png"), ("content-range", "bytes 0-3/900000")],
+             b"\x89PNG".to_vec())
+        }).head(|| async { StatusCode::METHOD_NOT_ALLOWED }));
+        let url = mock::serve(router).await;
+        let (content_type, length, bytes) = read_remote_artwork(&format!("{url}/cover.png")).await.unwrap();
+        assert_eq!(content_type.as_deref(), Some("image/png"));
+        assert_eq!(length, Some(900000));
+        assert_eq!(bytes, b"\x89PNG");
+    }
+
+    #[test]
+    fn test_jpeg_dimensions(){
+        let jpeg = [0xFF, 0xD8,
+            0xFF, 0xE0, 0x00, 0x04, 0x00, 0x00,
+            0xFF, 0xC0, 0x00, 0x11, 0x08, 0x0B, 0xB8, 0x05, 0x78];
+        assert_eq!(image_dimensions(&jpeg), Some((1400, 3000)));
+        assert_eq!(image_dimensions(b"GIF89a"), None);
+    }
+
+    #[test]
+    fn test_items(){
+        let enclosure = EnclosureBuilder::default()
+            .url("https://example.com/1.mp3")
+            .mime_type("audio/mpeg")
+            .length("0")
+            .build();
+        let guid = GuidBuilder::default().value("1").build();
+        let item = ItemBuilder::default()
+            .title(Some("Episode".to_string()))
+            .guid(Some(guid))
+            .pub_date(Some("Fri, 28 Feb 2025 16:08:58 +0100".to_string()))
+            .enclosure(Some(enclosure))
+            .build();
+        let mut report = Report::default();
+        check_items(&[item.clone(), item], &mut report);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.warnings.len(), 2);
+    }
+}
@@ -0,0 +1,419 @@
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sqlx::{sqlite::{SqlitePool, SqliteRow}, query, Row};
+use chrono::{DateTime, Utc, Duration};
+use reqwest::{Client, header};
+use tracing::{debug, error, info};
+use super::{Error, Episode, Feed, hub};
+
+pub const EPISODE_NEW: &str = "episode.new";
+pub const FEED_REGENERATED: &str = "feed.regenerated";
+pub const PING: &str = "ping";
+pub const EVENTS: [&str; 2] = [EPISODE_NEW, FEED_REGENERATED];
+
+const MAX_ATTEMPTS: i64 = 6;
+const TIMEOUT_SECONDS: u64 = 10;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NewWebhook{
+    pub name: String,
+    pub url: String,
+    #[serde(default)]
+    pub secret: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Events sent to the webhook, all of them when empty
+    #[serde(default)]
+    pub events: Vec<String>,
+    pub active: bool,
+}
+
+/// An arbitrary url that receives a signed JSON payload on every event.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Webhook{
+    pub id: i64,
+    pub name: String,
+    pub url: String,
+    #[serde(skip_serializing)]
+    pub secret: String,
+    pub headers: HashMap<String, String>,
+    pub events: Vec<String>,
+    pub active: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Delivery{
+    pub id: i64,
+    pub webhook_id: i64,
+    pub event: String,
+    pub payload: String,
+    /// `pending`, `delivered` or `failed`
+    pub status: String,
+    pub attempts: i64,
+    pub response_code: Option<i64>,
+    pub error: Option<String>,
+    pub next_attempt_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl NewWebhook{
+    pub fn validate(&self) -> Result<(), Error>{
+        reqwest::Url::parse(&self.url)?;
+        if let Some(event) = self.events.iter().find(|event| !EVENTS.contains(&event.as_str())) {
+            return Err(format!("Unknown event: {event}").into());
+        }
+        for (name, value) in self.headers.iter() {
+            header::HeaderName::from_bytes(name.as_bytes())?;
+            header::HeaderValue::from_str(value)?;
+        }
+        Ok(())
+    }
+}
+
+impl Webhook{
+    fn from_row(row: SqliteRow) -> Self{
+        Self{
+            id: row.get("id"),
+            name: row.get("name"),
+            url: row.get("url"),
+            secret: row.get("secret"),
+            headers: serde_json::from_str(row.get("headers")).unwrap_or_default(),
+            events: serde_json::from_str(row.get("events")).unwrap_or_default(),
+            active: row.get("active"),
+            created_at: row.get("created_at"),
+            updated_at: row.get("updated_at"),
+        }
+    }
+
+    pub async fn get(pool: &SqlitePool) -> Result<Vec<Webhook>, sqlx::error::Error>{
+        let sql = "SELECT * FROM webhooks ORDER BY id";
+        query(sql)
+            .map(Self::from_row)
+            .fetch_all(pool)
+            .await
+    }
+
+    pub async fn get_by_id(pool: &SqlitePool, id: i64) -> Result<Option<Webhook>, sqlx::error::Error>{
+        let sql = "SELECT * FROM webhooks WHERE id = $1";
+        query(sql)
+            .bind(id)
+            .map(Self::from_row)
+            .fetch_optional(pool)
+            .await
+    }
+
+    pub async fn create(pool: &SqlitePool, webhook: &NewWebhook) -> Result<Webhook, Error>{
+        webhook.validate()?;
+        let sql = "INSERT INTO webhooks (name, url, secret, headers, events, active)
+                   VALUES ($1, $2, $3, $4, $5, $6) RETURNING *";
+        Ok(query(sql)
+            .bind(&webhook.name)
+            .bind(&webhook.url)
+            .bind(&webhook.secret)
+            .bind(serde_json::to_string(&webhook.headers)?)
+            .bind(serde_json::to_string(&webhook.events)?)
+            .bind(webhook.active)
+            .map(Self::from_row)
+            .fetch_one(pool)
+            .await?)
+    }
+
+    /// Updates the webhook, keeping the current secret when the new one is empty.
+    pub async fn update(pool: &SqlitePool, id: i64, webhook: &NewWebhook) -> Result<Webhook, Error>{
+        webhook.validate()?;
+        let sql = "UPDATE webhooks SET name = $1, url = $2,
+                   secret = CASE WHEN $3 = '' THEN secret ELSE $3 END,
+                   headers = $4, events = $5, active = $6, updated_at = $7
+                   WHERE id = $8 RETURNING *";
+        Ok(query(sql)
+            .bind(&webhook.name)
+            .bind(&webhook.url)
+            .bind(&webhook.secret)
+            .bind(serde_json::to_string(&webhook.headers)?)
+            .bind(serde_json::to_string(&webhook.events)?)
+            .bind(webhook.active)
+            .bind(Utc::now())
+            .bind(id)
+            .map(Self::from_row)
+            .fetch_one(pool)
+            .await?)
+    }
+
+    pub async fn delete(pool: &SqlitePool, id: i64) -> Result<Webhook, sqlx::error::Error>{
+        let sql = "DELETE FROM webhooks WHERE id = $1 RETURNING *";
+        query(sql)
+            .bind(id)
+            .map(Self::from_row)
+            .fetch_one(pool)
+            .await
+    }
+
+    pub fn accepts(&self, event: &str) -> bool{
+        self.events.is_empty() || self.events.iter().any(|accepted| accepted == event)
+    }
+
+    /// Posts the payload and returns the status code of the response.
+    pub async fn send(&self, delivery: &Delivery) -> Result<u16, Error>{
+        debug!("Send {} to {}", delivery.event, self.url);
+        let mut request = Client::new()
+            .post(&self.url)
+            .timeout(std::time::Duration::from_secs(TIMEOUT_SECONDS))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("X-Podmixer-Event", &delivery.event)
+            .header("X-Podmixer-Delivery", delivery.id.to_string());
+        for (name, value) in self.headers.iter() {
+            request = request.header(name, value);
+        }
+        if !self.secret.is_empty() {
+            let signature = hub::sign(&self.secret, delivery.payload.as_bytes())?;
+            request = request.header("X-Podmixer-Signature", format!("sha256={signature}"));
+        }
+        let response = request.body(delivery.payload.clone())
+            .send()
+            .await?;
+        Ok(response.status().as_u16())
+    }
+}
+
+impl Delivery{
+    fn from_row(row: SqliteRow) -> Self{
+        Self{
+            id: row.get("id"),
+            webhook_id: row.get("webhook_id"),
+            event: row.get("event"),
+            payload: row.get("payload"),
+            status: row.get("status"),
+            attempts: row.get("attempts"),
+            response_code: row.get("response_code"),
+            error: row.get("error"),
+            next_attempt_at: row.get("next_attempt_at"),
+            created_at: row.get("created_at"),
+            updated_at: row.get("updated_at"),
+        }
+    }
+
+    pub async fn get_by_webhook(pool: &SqlitePool, webhook_id: i64) -> Result<Vec<Delivery>, sqlx::error::Error>{
+        let sql = "SELECT * FROM webhook_deliveries WHERE webhook_id = $1
+                   ORDER BY id DESC LIMIT 100";
+        query(sql)
+            .bind(webhook_id)
+            .map(Self::from_row)
+            .fetch_all(pool)
+            .await
+    }
+
+    async fn create(pool: &SqlitePool, webhook_id: i64, event: &str, payload: &str) -> Result<Delivery, sqlx::error::Error>{
+        let sql = "INSERT INTO webhook_deliveries (webhook_id, event, payload, next_attempt_at)
+                   VALUES ($1, $2, $3, $4) RETURNING *";
+        query(sql)
+            .bind(webhook_id)
+            .bind(event)
+            .bind(payload)
+            .bind(Utc::now())
+            .map(Self::from_row)
+            .fetch_one(pool)
+            .await
+    }
+
+    async fn get_due(pool: &SqlitePool) -> Result<Vec<Delivery>, sqlx::error::Error>{
+        let sql = "SELECT * FROM webhook_deliveries WHERE status = 'pending'
+                   AND next_attempt_at <= $1 ORDER BY id";
+        query(sql)
+            .bind(Utc::now())
+            .map(Self::from_row)
+            .fetch_all(pool)
+            .await
+    }
+
+    async fn save_attempt(&self, pool: &SqlitePool, response_code: Option<u16>, error: Option<String>) -> Result<Delivery, sqlx::error::Error>{
+        let attempts = self.attempts + 1;
+        let delivered = error.is_none();
+        let status = if delivered {
+            "delivered"
+        } else if attempts >= MAX_ATTEMPTS {
+            "failed"
+        } else {
+            "pending"
+        };
+        // 1, 2, 4, 8... minutes between attempts
+        let next_attempt_at = Utc::now() + Duration::minutes(1 << (attempts - 1));
+        let sql = "UPDATE webhook_deliveries SET status = $1, attempts = $2,
+                   response_code = $3, error = $4, next_attempt_at = $5,
+                   updated_at = $6 WHERE id = $7 RETURNING *";
+        query(sql)
+            .bind(status)
+            .bind(attempts)
+            .bind(response_code.map(i64::from))
+            .bind(error)
+            .bind(next_attempt_at)
+            .bind(Utc::now())
+            .bind(self.id)
+            .map(Self::from_row)
+            .fetch_one(pool)
+            .await
+    }
+
+    /// Queues the payload for the webhook and tries to deliver it right away.
+    pub async fn create_attempt(pool: &SqlitePool, webhook: &Webhook, event: &str, payload: &str) -> Result<Delivery, Error>{
+        let delivery = Self::create(pool, webhook.id, event, payload).await?;
+        delivery.attempt(pool, webhook).await
+    }
+
+    /// Tries to deliver it once and records the result.
+    pub async fn attempt(&self, pool: &SqlitePool, webhook: &Webhook) -> Result<Delivery, Error>{
+        let (response_code, error) = match webhook.send(self).await {
+            Ok(code) if (200..300).contains(&code) => (Some(code), None),
+            Ok(code) => (Some(code), Some(format!("Unexpected status {code}"))),
+            Err(e) => (None, Some(e.to_string())),
+        };
+        if let Some(error) = &error {
+            error!("Can not deliver {} to {}: {error}", self.event, webhook.url);
+        }
+        Ok(self.save_attempt(pool, response_code, error).await?)
+    }
+}
+
+/// Queues the event for every active webhook listening to it and tries to
+/// deliver it right away. Failed deliveries are retried by [`retry`].
+pub async fn notify(pool: &SqlitePool, event: &str, data: Value) -> Result<(), Error>{
+    let webhooks: Vec<Webhook> = Webhook::get(pool).await?
+        .into_iter()
+        .filter(|webhook| webhook.active && webhook.accepts(event))
+        .collect();
+    if webhooks.is_empty() {
+        return Ok(());
+    }
+    let mut payload = json!({
+        "event": event,
+        "created_at": Utc::now(),
+    });
+    if let (Value::Object(payload), Value::Object(data)) = (&mut payload, data) {
+        payload.extend(data);
+    }
+    let payload = serde_json::to_string(&payload)?;
+    info!("Notify {event} to {} webhooks", webhooks.len());
+    for webhook in webhooks {
+        Delivery::create_attempt(pool, &webhook, event, &payload).await?;
+    }
+    Ok(())
+}
+
+/// Retries the pending deliveries whose backoff has expired.
+pub async fn retry(pool: &SqlitePool) -> Result<(), Error>{
+    for delivery in Delivery::get_due(pool).await? {
+        match Webhook::get_by_id(pool, delivery.webhook_id).await? {
+            Some(webhook) if webhook.active => {
+                delivery.attempt(pool, &webhook).await?;
+            },
+            _ => debug!("Skip delivery {} of an inactive webhook", delivery.id),
+        }
+    }
+    Ok(())
+}
+
+/// The mix podcast generated by podmixer, as sent in the payloads.
+pub fn mix(feed: &Feed) -> Value{
+    json!({
+        "title": feed.title,
+        "link": feed.link,
+        "short": feed.self_url("short.xml"),
+        "long": feed.self_url("long.xml"),
+    })
+}
+
+pub fn episode_new(feed: &Feed, episode: &Episode) -> Value{
+    json!({
+        "mix": mix(feed),
+        "podcast": {"name": episode.podcast},
+        "episode": episode,
+    })
+}
+
+#[cfg(test)]
+mod test{
+    use super::{NewWebhook, Webhook, Delivery, notify, retry, EPISODE_NEW, FEED_REGENERATED};
+    use crate::models::{hub::sign, mock};
+    use axum::{Router, routing, extract::State, http::{HeaderMap, StatusCode}};
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    type Requests = Arc<Mutex<Vec<(HeaderMap, String)>>>;
+
+    #[tokio::test]
+    async fn webhook_notify(){
+        let pool = mock::pool().await;
+        let requests: Requests = Arc::new(Mutex::new(Vec::new()));
+        let router = Router::new()
+            .route("/ok", routing::post(
+                |State(requests): State<Requests>, headers: HeaderMap, body: String| async move {
+                    requests.lock().unwrap().push((headers, body));
+                }))
+            .route("/ko", routing::post(|| async { StatusCode::INTERNAL_SERVER_ERROR }))
+            .with_state(requests.clone());
+        let url = mock::serve(router).await;
+        let ok = Webhook::create(&pool, &NewWebhook{
+            name: "ok".to_string(),
+            url: format!("{url}/ok"),
+            secret: "secret".to_string(),
+            headers: HashMap::from([("X-Token".to_string(), "token".to_string())]),
+            events: vec![EPISODE_NEW.to_string()],
+            active: true,
+        }).await.unwrap();
+        let ko = Webhook::create(&pool, &NewWebhook{
+            name: "ko".to_string(),
+            url: format!("{url}/ko"),
+            secret: "".to_string(),
+            headers: HashMap::new(),
+            events: vec![],
+            active: true,
+        }).await.unwrap();
+        notify(&pool, EPISODE_NEW, json!({"episode": {"title": "Episode 1"}})).await.unwrap();
+        notify(&pool, FEED_REGENERATED, json!({})).await.unwrap();
+        {
+            let requests = requests.lock().unwrap();
+            assert_eq!(requests.len(), 1);
+            let (headers, body) = &requests[0];
+            assert_eq!(headers["X-Podmixer-Event"], EPISODE_NEW);
+            assert_eq!(headers["X-Token"], "token");
+            let signature = format!("sha256={}", sign("secret", body.as_bytes()).unwrap());
+            assert_eq!(headers["X-Podmixer-Signature"].to_str().unwrap(), signature);
+            let payload: serde_json::Value = serde_json::from_str(body).unwrap();
+            assert_eq!(payload["event"], EPISODE_NEW);
+            assert_eq!(payload["episode"]["title"], "Episode 1");
+        }
+        let deliveries = Delivery::get_by_webhook(&pool, ok.id).await.unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].status, "delivered");
+        let deliveries = Delivery::get_by_webhook(&pool, ko.id).await.unwrap();
+        assert_eq!(deliveries.len(), 2);
+        assert!(deliveries.iter().all(|delivery| delivery.status == "pending"
+            && delivery.attempts == 1 && delivery.response_code == Some(500)));
+        // Not due yet
+        retry(&pool).await.unwrap();
+        let deliveries = Delivery::get_by_webhook(&pool, ko.id).await.unwrap();
+        assert!(deliveries.iter().all(|delivery| delivery.attempts == 1));
+    }
+
+    #[test]
+    fn test_validate(){
+        let mut webhook = NewWebhook{
+            name: "name".to_string(),
+            url: "https://example.com/hook".to_string(),
+            secret: "".to_string(),
+            headers: HashMap::new(),
+            events: vec![EPISODE_NEW.to_string()],
+            active: true,
+        };
+        assert!(webhook.validate().is_ok());
+        webhook.events.push("episode.deleted".to_string());
+        assert!(webhook.validate().is_err());
+        webhook.events.pop();
+        webhook.headers.insert("Bad Header".to_string(), "value".to_string());
+        assert!(webhook.validate().is_err());
+    }
+}
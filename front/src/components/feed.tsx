@@ -4,6 +4,7 @@ import Checkbox from '@mui/material/Checkbox';
 import FormControlLabel from '@mui/material/FormControlLabel';
 import Grid from '@mui/material/Grid';
 import Button from '@mui/material/Button';
+import MenuItem from '@mui/material/MenuItem';
 import { BASE_URL } from '../constants';
 
 interface FeedState {
@@ -13,6 +14,7 @@ interface FeedState {
     link: string;
     imageUrl: string;
     category: string;
+    subcategory: string;
     rating: string;
     description: string;
     author: string;
@@ -20,7 +22,14 @@ interface FeedState {
     keywords: string;
     owner_name: string;
     owner_email: string;
-
+    language: string;
+    itunes_type: string;
+    copyright: string;
+    block: boolean;
+    complete: boolean;
+    public_url: string;
+    hub_url: string;
+    page_size: number;
 }
 
 export default class Feed extends React.Component<{}, FeedState> {
@@ -33,6 +42,7 @@ export default class Feed extends React.Component<{}, FeedState> {
             link: "",
             imageUrl: "",
             category: "",
+            subcategory: "",
             rating: "",
             description: "",
             author: "",
@@ -40,6 +50,14 @@ export default class Feed extends React.Component<{}, FeedState> {
             keywords: "",
             owner_name: "",
             owner_email: "",
+            language: "es",
+            itunes_type: "episodic",
+            copyright: "",
+            block: false,
+            complete: false,
+            public_url: "",
+            hub_url: "",
+            page_size: 100,
         }
     }
 
@@ -53,13 +71,22 @@ export default class Feed extends React.Component<{}, FeedState> {
                 "link": ${this.state.link ? JSON.stringify(this.state.link) : "\"\""},
                 "image_url": ${this.state.imageUrl ? JSON.stringify(this.state.imageUrl) : "\"\""},
                 "category": ${this.state.category ? JSON.stringify(this.state.category) : "\"\""},
+                "subcategory": ${this.state.subcategory ? JSON.stringify(this.state.subcategory) : "\"\""},
                 "rating": ${this.state.rating ? JSON.stringify(this.state.rating) : "\"\""},
                 "description": ${this.state.description ? JSON.stringify(this.state.description) : "\"\""},
                 "author": ${this.state.author ? JSON.stringify(this.state.author) : "\"\""},
                 "explicit": ${JSON.stringify(this.state.explicit)},
                 "keywords": ${this.state.keywords ? JSON.stringify(this.state.keywords) : "\"\""},
                 "owner_name": ${this.state.owner_name ? JSON.stringify(this.state.owner_name) : "\"\""},
-                "owner_email": ${this.state.owner_email ? JSON.stringify(this.state.owner_email) : "\"\""}
+                "owner_email": ${this.state.owner_email ? JSON.stringify(this.state.owner_email) : "\"\""},
+                "language": ${this.state.language ? JSON.stringify(this.state.language) : "\"es\""},
+                "itunes_type": ${this.state.itunes_type ? JSON.stringify(this.state.itunes_type) : "\"episodic\""},
+                "copyright": ${this.state.copyright ? JSON.stringify(this.state.copyright) : "\"\""},
+                "block": ${JSON.stringify(this.state.block)},
+                "complete": ${JSON.stringify(this.state.complete)},
+                "public_url": ${this.state.public_url ? JSON.stringify(this.state.public_url) : "\"\""},
+                "hub_url": ${this.state.hub_url ? JSON.stringify(this.state.hub_url) : "\"\""},
+                "page_size": ${JSON.stringify(Number(this.state.page_size) || 0)}
                 }`;
             console.log(`Submitting feed: ${body}`);
             const response = await fetch(`${BASE_URL}/api/v1/config/feed`, {
@@ -80,6 +107,7 @@ export default class Feed extends React.Component<{}, FeedState> {
                     link: feed.link,
                     imageUrl: feed.image_url,
                     category: feed.category,
+                    subcategory: feed.subcategory,
                     rating: feed.rating,
                     description: feed.description,
                     author: feed.author,
@@ -87,7 +115,14 @@ export default class Feed extends React.Component<{}, FeedState> {
                     keywords: feed.keywords,
                     owner_name: feed.owner_name,
                     owner_email: feed.owner_email,
-
+                    language: feed.language,
+                    itunes_type: feed.itunes_type,
+                    copyright: feed.copyright,
+                    block: feed.block,
+                    complete: feed.complete,
+                    public_url: feed.public_url,
+                    hub_url: feed.hub_url,
+                    page_size: feed.page_size,
                 });
            }
         } catch (error) {
@@ -115,6 +150,7 @@ export default class Feed extends React.Component<{}, FeedState> {
                     link: feed.link,
                     imageUrl: feed.image_url,
                     category: feed.category,
+                    subcategory: feed.subcategory,
                     rating: feed.rating,
                     description: feed.description,
                     author: feed.author,
@@ -122,6 +158,14 @@ export default class Feed extends React.Component<{}, FeedState> {
                     keywords: feed.keywords,
                     owner_name: feed.owner_name,
                     owner_email: feed.owner_email,
+                    language: feed.language,
+                    itunes_type: feed.itunes_type,
+                    copyright: feed.copyright,
+                    block: feed.block,
+                    complete: feed.complete,
+                    public_url: feed.public_url,
+                    hub_url: feed.hub_url,
+                    page_size: feed.page_size,
                 });
             }
         } catch (error) {
@@ -162,6 +206,9 @@ export default class Feed extends React.Component<{}, FeedState> {
                 <Grid size={{xs: 12, sm: 6, md: 4}}>
                     <TextField fullWidth label="Category" variant="outlined" value={this.state.category} onChange={(e) => this.setState({ category: e.target.value })} />
                 </Grid>
+                <Grid size={{xs: 12, sm: 6, md: 4}}>
+                    <TextField fullWidth label="Subcategory" variant="outlined" value={this.state.subcategory} onChange={(e) => this.setState({ subcategory: e.target.value })} />
+                </Grid>
                 <Grid size={{xs: 12, sm: 6, md: 4}}>
                     <TextField fullWidth label="Rating" variant="outlined" value={this.state.rating} onChange={(e) => this.setState({ rating: e.target.value })} />
                 </Grid>
@@ -183,6 +230,33 @@ export default class Feed extends React.Component<{}, FeedState> {
                 <Grid size={{xs: 12, sm: 6, md: 4}}>
                     <TextField fullWidth label="Keywords" variant="outlined" value={this.state.keywords} onChange={(e) => this.setState({ keywords: e.target.value })} />
                 </Grid>
+                <Grid size={{xs: 12, sm: 6, md: 4}}>
+                    <TextField fullWidth label="Idioma" variant="outlined" value={this.state.language} onChange={(e) => this.setState({ language: e.target.value })} />
+                </Grid>
+                <Grid size={{xs: 12, sm: 6, md: 4}}>
+                    <TextField select fullWidth label="Tipo" variant="outlined" value={this.state.itunes_type} onChange={(e) => this.setState({ itunes_type: e.target.value })}>
+                        <MenuItem value="episodic">episodic</MenuItem>
+                        <MenuItem value="serial">serial</MenuItem>
+                    </TextField>
+                </Grid>
+                <Grid size={{xs: 12, sm: 6, md: 4}}>
+                    <TextField fullWidth label="Copyright" variant="outlined" value={this.state.copyright} onChange={(e) => this.setState({ copyright: e.target.value })} />
+                </Grid>
+                <Grid size={{xs: 12, sm: 6, md: 4}}>
+                    <FormControlLabel control={<Checkbox checked={this.state.block} onChange={(e) => this.setState({ block: e.target.checked })} />} label="Block" />
+                </Grid>
+                <Grid size={{xs: 12, sm: 6, md: 4}}>
+                    <FormControlLabel control={<Checkbox checked={this.state.complete} onChange={(e) => this.setState({ complete: e.target.checked })} />} label="Complete" />
+                </Grid>
+                <Grid size={{xs: 12, sm: 6, md: 4}}>
+                    <TextField fullWidth label="Url pública" variant="outlined" value={this.state.public_url} onChange={(e) => this.setState({ public_url: e.target.value })} />
+                </Grid>
+                <Grid size={{xs: 12, sm: 6, md: 4}}>
+                    <TextField fullWidth label="WebSub hub" variant="outlined" value={this.state.hub_url} onChange={(e) => this.setState({ hub_url: e.target.value })} />
+                </Grid>
+                <Grid size={{xs: 12, sm: 6, md: 4}}>
+                    <TextField fullWidth type="number" label="Episodios por página" variant="outlined" value={this.state.page_size} onChange={(e) => this.setState({ page_size: Number(e.target.value) })} />
+                </Grid>
                 <Grid size={12}>
                     <Button variant="contained" onClick={this.onClick}>Guardar</Button>
                 </Grid>